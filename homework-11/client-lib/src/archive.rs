@@ -0,0 +1,149 @@
+//! Packaging of directories and files matching a glob pattern into a single tar archive.
+//!
+//! The paths in the archive are relative to the uploaded directory (or to the directory
+//! the glob pattern starts in), the server unpacks them into a directory of its own.
+
+use common::error::{ChatError, ChatResult, ErrorCode};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tar::Builder;
+
+/// Name used for the archive if it cannot be derived from the uploaded directory.
+const DEFAULT_NAME: &str = "files";
+
+/// Packs the content of the directory, returning the name of the directory and the archive.
+pub(crate) fn pack_directory(path: &Path) -> ChatResult<(String, Vec<u8>)> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(path)
+        .and_then(|entries| {
+            entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect()
+        })
+        .map_err(|e| read_error(path, e))?;
+    entries.sort();
+    let archive = pack(path, &entries)?;
+    Ok((directory_name(path), archive))
+}
+
+/// Packs the files and directories matching the pattern (e.g. `logs/**/*.txt`).
+pub(crate) fn pack_glob(pattern: &str) -> ChatResult<(String, Vec<u8>)> {
+    let invalid = |e: glob::PatternError| {
+        ChatError::new(
+            ErrorCode::InvalidArgument,
+            format!("Invalid pattern {}: {}", pattern, e),
+        )
+    };
+    let entries = glob::glob(pattern)
+        .map_err(invalid)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            let path = e.path().to_path_buf();
+            read_error(&path, e.into())
+        })?;
+    if entries.is_empty() {
+        return Err(ChatError::new(
+            ErrorCode::NotFound,
+            format!("No files match {}", pattern),
+        ));
+    }
+    let base = glob_base(pattern);
+    let archive = pack(&base, &outermost(entries))?;
+    Ok((directory_name(&base), archive))
+}
+
+fn pack(base: &Path, entries: &[PathBuf]) -> ChatResult<Vec<u8>> {
+    let mut builder = Builder::new(Vec::new());
+    for path in entries {
+        let name = path.strip_prefix(base).unwrap_or(path);
+        let added = if path.is_dir() {
+            builder.append_dir_all(name, path)
+        } else {
+            builder.append_path_with_name(path, name)
+        };
+        added.map_err(|e| read_error(path, e))?;
+    }
+    builder
+        .into_inner()
+        .map_err(|e| ChatError::new(ErrorCode::Io, e.to_string()))
+}
+
+/// The paths not within any of the other (directory) paths, the directories are packed with all their content.
+fn outermost(mut paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    // a directory precedes its content once sorted
+    paths.sort();
+    let mut directories: Vec<PathBuf> = Vec::new();
+    paths.retain(|path| {
+        if directories
+            .iter()
+            .any(|directory| path.starts_with(directory))
+        {
+            return false;
+        }
+        if path.is_dir() {
+            directories.push(path.clone());
+        }
+        true
+    });
+    paths
+}
+
+/// The leading part of the pattern without any wildcards.
+fn glob_base(pattern: &str) -> PathBuf {
+    Path::new(pattern)
+        .components()
+        .take_while(|component| {
+            !component
+                .as_os_str()
+                .to_string_lossy()
+                .contains(['*', '?', '['])
+        })
+        .collect()
+}
+
+fn directory_name(path: &Path) -> String {
+    // the name of e.g. `.` is known only after resolving it
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    path.file_name().map_or(DEFAULT_NAME.to_string(), |name| {
+        name.to_string_lossy().to_string()
+    })
+}
+
+fn read_error(path: &Path, e: io::Error) -> ChatError {
+    ChatError::new(
+        ErrorCode::Io,
+        format!("Cannot read {}: {}", path.display(), e),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_within_matched_directories_are_packed_once() {
+        let base = std::env::temp_dir().join(format!("archive-glob-{}", std::process::id()));
+        fs::create_dir_all(base.join("logs/old")).unwrap();
+        for file in ["logs/a.log", "logs/old/b.log", "c.log"] {
+            fs::write(base.join(file), file).unwrap();
+        }
+
+        let pattern = format!("{}/**/*", base.display());
+        let (_, archive) = pack_glob(&pattern).unwrap();
+        let mut names = tar::Archive::new(&archive[..])
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path().unwrap().to_string_lossy().to_string();
+                path.trim_end_matches('/').to_string()
+            })
+            .collect::<Vec<_>>();
+        fs::remove_dir_all(&base).unwrap();
+
+        names.sort();
+        assert_eq!(
+            names,
+            ["c.log", "logs", "logs/a.log", "logs/old", "logs/old/b.log"]
+        );
+    }
+}
@@ -0,0 +1,827 @@
+//! Connection to the server and the requests sent over it.
+//!
+//! All the frames sent by the server are read by a dedicated receiver thread: the events are published
+//! to the subscribers right away, the responses are handed over to the waiting requests in the order
+//! the requests were sent (the server answers the requests of a single connection in order).
+//! Hence the client can be shared by multiple threads, each of them getting the responses to its own requests.
+//! A response may be followed by content (a download), which is read by the receiver thread as well.
+//!
+//! If reconnecting is enabled, the receiver thread also re-establishes a lost connection: it restores
+//! the session by repeating the last successful `.nick`, `.admin` and `.join` commands and then sends
+//! the messages queued while offline. The requests made meanwhile fail with `ErrorCode::Offline`.
+
+use crate::archive::{pack_directory, pack_glob};
+use crate::event::{Event, Progress};
+use crate::reconnect::Reconnect;
+use common::command::{next_token, quote};
+use common::compression::{is_compressed, Compression};
+use common::content_type::SNIFF_LENGTH;
+use common::download::Download;
+use common::error::{ChatError, ChatResult, ErrorCode};
+use common::event::ServerEvent;
+use common::frame::read_frame;
+use common::util::format_size;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, Read, Seek, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Commands changing the session state on the server, repeated after reconnecting (in this order).
+const RESTORED_COMMANDS: [&str; 4] = [".nick", ".admin", ".join", ".compress"];
+/// Uploads are written in chunks of this size, reporting the progress in between.
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Minimal time between two progress events of an upload.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+/// Limit of a download (kept in memory until saved), larger ones are skipped.
+const MAX_DOWNLOAD_SIZE: usize = 1024 * 1024 * 1024;
+
+/// Online user as listed by the server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct User {
+    /// Nick of the user, or the address if not set.
+    pub name: String,
+    pub room: String,
+}
+
+pub struct ChatClient {
+    shared: Arc<Shared>,
+}
+
+/// State shared with the receiver thread.
+struct Shared {
+    /// The server address, for reconnecting.
+    address: Vec<SocketAddr>,
+    /// Replaced by a new connection after reconnecting.
+    writer: Mutex<TcpStream>,
+    pending: Mutex<Pending>,
+    subscribers: Mutex<Vec<Sender<Event>>>,
+    reconnect: Mutex<Option<Reconnect>>,
+    /// The last successful command of each of the `RESTORED_COMMANDS`.
+    session: Mutex<Vec<(String, String)>>,
+    /// Compression of the uploads negotiated with the server.
+    compression: Mutex<Option<Compression>>,
+}
+
+/// Response to a request, with the content following it (if any).
+struct Reply {
+    text: String,
+    content: Option<Vec<u8>>,
+}
+
+#[derive(Default)]
+struct Pending {
+    link: Link,
+    requests: VecDeque<Sender<ChatResult<Reply>>>,
+    /// Messages typed while offline.
+    outbox: VecDeque<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Link {
+    #[default]
+    Online,
+    Reconnecting,
+    Closed,
+}
+
+impl Shared {
+    fn publish(&self, event: Event) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Sends the request and waits for the response to it, the content is reported to `progress` as written.
+    fn request(
+        &self,
+        line: &[u8],
+        content: &mut dyn Read,
+        progress: &mut dyn FnMut(usize),
+    ) -> ChatResult<String> {
+        self.exchange(line, content, progress)
+            .map(|reply| reply.text)
+    }
+
+    /// Same as `request`, also returning the content following the response.
+    fn exchange(
+        &self,
+        line: &[u8],
+        content: &mut dyn Read,
+        progress: &mut dyn FnMut(usize),
+    ) -> ChatResult<Reply> {
+        let (sender, receiver) = channel();
+        {
+            // the request is queued and written under the same lock to keep the order of the responses
+            let mut writer = self.writer.lock().unwrap();
+            {
+                let mut pending = self.pending.lock().unwrap();
+                match pending.link {
+                    Link::Online => pending.requests.push_back(sender),
+                    Link::Reconnecting => return Err(offline()),
+                    Link::Closed => return Err(closed()),
+                }
+            }
+            if let Err(e) = write_request(&mut writer, line, content, progress) {
+                // the receiver thread finishes (or reconnects) and fails all the pending requests,
+                // the server would take the rest of the request (if any) for the next one otherwise
+                let _ = writer.shutdown(Shutdown::Both);
+                if e.code == ErrorCode::Connection && self.reconnect.lock().unwrap().is_some() {
+                    return Err(offline());
+                }
+                return Err(e);
+            }
+        }
+        receiver.recv().unwrap_or_else(|_| match self.link() {
+            Link::Reconnecting => Err(offline()),
+            _ => Err(closed()),
+        })
+    }
+
+    /// Records the compression chosen by the server from its response to `.compress`.
+    fn negotiated(&self, response: &str) -> Option<Compression> {
+        let compression = response
+            .strip_prefix("Compression: ")
+            .and_then(|name| name.parse().ok());
+        *self.compression.lock().unwrap() = compression;
+        compression
+    }
+
+    fn link(&self) -> Link {
+        self.pending.lock().unwrap().link
+    }
+
+    /// Remembers the successful command if it is to be repeated after reconnecting.
+    fn remember(&self, line: &str) {
+        let mut words = line.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        // e.g. `.join` without a room just tells the current one
+        if RESTORED_COMMANDS.contains(&name) && words.next().is_some() {
+            let mut session = self.session.lock().unwrap();
+            session.retain(|(command, _)| command != name);
+            session.push((name.to_string(), line.to_string()));
+        }
+    }
+}
+
+impl ChatClient {
+    pub fn connect(address: impl ToSocketAddrs) -> ChatResult<Self> {
+        let stream = TcpStream::connect(address).map_err(ChatError::connection)?;
+        Self::from_stream(stream)
+    }
+
+    /// Wraps an already established connection.
+    pub fn from_stream(stream: TcpStream) -> ChatResult<Self> {
+        let reader = BufReader::new(stream.try_clone().map_err(ChatError::connection)?);
+        let shared = Arc::new(Shared {
+            address: stream.peer_addr().into_iter().collect(),
+            writer: Mutex::new(stream),
+            pending: Mutex::default(),
+            subscribers: Mutex::default(),
+            reconnect: Mutex::default(),
+            session: Mutex::default(),
+            compression: Mutex::default(),
+        });
+        let receiver_shared = shared.clone();
+        thread::Builder::new()
+            .name("receiver".to_string())
+            .spawn(move || receive(reader, receiver_shared))
+            .map_err(|e| ChatError::new(ErrorCode::Internal, e.to_string()))?;
+        Ok(ChatClient { shared })
+    }
+
+    /// Enables reconnecting after the connection is lost (by default the client just disconnects).
+    pub fn with_reconnect(self, reconnect: Reconnect) -> Self {
+        *self.shared.reconnect.lock().unwrap() = Some(reconnect);
+        self
+    }
+
+    /// Registers a new subscriber of the client events.
+    ///
+    /// The events are delivered as long as the receiver is alive, dropping it unsubscribes.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = channel();
+        self.shared.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Sends a plain (non-command) message.
+    pub fn send_message(&self, text: &str) -> ChatResult<String> {
+        if text.starts_with('.') || text.contains('\n') {
+            return Err(ChatError::new(
+                ErrorCode::InvalidArgument,
+                "A message must be a single line not starting with '.'",
+            ));
+        }
+        self.send_line(text)
+    }
+
+    /// Sends a command with the given arguments (quoted as needed).
+    pub fn command(&self, name: &str, args: &[&str]) -> ChatResult<String> {
+        let line = args.iter().fold(name.to_string(), |line, arg| {
+            format!("{} {}", line, quote(arg))
+        });
+        self.send_line(&line)
+    }
+
+    pub fn set_nick(&self, nick: &str) -> ChatResult<String> {
+        self.command(".nick", &[nick])
+    }
+
+    pub fn motd(&self) -> ChatResult<String> {
+        self.command(".motd", &[])
+    }
+
+    pub fn help(&self) -> ChatResult<String> {
+        self.command(".help", &[])
+    }
+
+    /// Moves to the given room.
+    pub fn join(&self, room: &str) -> ChatResult<String> {
+        self.command(".join", &[room])
+    }
+
+    /// Lists the online users with their rooms.
+    pub fn users(&self) -> ChatResult<Vec<User>> {
+        let response = self.command(".users", &[])?;
+        // a header followed by `<name> #<room>` lines
+        let users = response
+            .lines()
+            .skip(1)
+            .filter_map(|line| match next_token(line.trim()) {
+                Ok(Some((name, room))) => Some(User {
+                    name,
+                    room: room.trim().trim_start_matches('#').to_string(),
+                }),
+                _ => None,
+            })
+            .collect();
+        Ok(users)
+    }
+
+    /// Offers the compression algorithms (in the order of preference) to the server for the uploads,
+    /// returning the one chosen by the server (if any).
+    pub fn negotiate_compression(
+        &self,
+        offered: &[Compression],
+    ) -> ChatResult<Option<Compression>> {
+        let names = offered.iter().map(Compression::as_str).collect::<Vec<_>>();
+        let response = self.command(".compress", &names)?;
+        Ok(self.shared.negotiated(&response))
+    }
+
+    /// Uploads the file for storing into the server files directory.
+    pub fn upload_file(&self, path: impl AsRef<Path>) -> ChatResult<String> {
+        self.upload(".file", path.as_ref())
+    }
+
+    /// Uploads the image for conversion and storing into the server images directory.
+    pub fn upload_image(&self, path: impl AsRef<Path>) -> ChatResult<String> {
+        self.upload(".image", path.as_ref())
+    }
+
+    /// Uploads the content of the directory as a single archive, unpacked by the server
+    /// into a directory of its own (under the files directory).
+    pub fn upload_directory(&self, path: impl AsRef<Path>) -> ChatResult<String> {
+        let (name, archive) = pack_directory(path.as_ref())?;
+        let size = archive.len() as u64;
+        self.send_upload(".archive", &name, Cursor::new(archive), size)
+    }
+
+    /// Uploads the files matching the glob pattern (e.g. `out/**/*.log`) as a single archive,
+    /// keeping their paths relative to the directory the pattern starts in.
+    pub fn upload_glob(&self, pattern: &str) -> ChatResult<String> {
+        let (name, archive) = pack_glob(pattern)?;
+        let size = archive.len() as u64;
+        self.send_upload(".archive", &name, Cursor::new(archive), size)
+    }
+
+    /// Downloads the upload with the given ID (e.g. `#a1b2`), returning its name and content.
+    pub fn fetch(&self, id: &str) -> ChatResult<(String, Vec<u8>)> {
+        let result = self.get(id);
+        if let Err(e) = &result {
+            self.shared.publish(Event::Error(e.clone()));
+        }
+        result
+    }
+
+    /// Downloads the upload with the given ID (e.g. `#a1b2`) into the directory, under its original name
+    /// (with a numeric suffix if such a file exists already).
+    pub fn download(&self, id: &str, directory: impl AsRef<Path>) -> ChatResult<String> {
+        let result = self.get(id).and_then(|(name, content)| {
+            let path = save(directory.as_ref(), &name, &content)?;
+            Ok(format!(
+                "Downloaded {} ({}) into {}",
+                name,
+                format_size(content.len() as u64),
+                path.display()
+            ))
+        });
+        self.finish(result)
+    }
+
+    /// Sends a raw line (a message or a command as typed by the user) and waits for the response.
+    ///
+    /// While reconnecting, plain messages are queued to be sent later.
+    pub fn send_line(&self, line: &str) -> ChatResult<String> {
+        if !line.starts_with('.') {
+            if let Some(result) = self.enqueue(line) {
+                return self.finish(result);
+            }
+        }
+        let result = self.shared.request(
+            format!("{}\n", line).as_bytes(),
+            &mut io::empty(),
+            &mut |_| {},
+        );
+        if result.is_ok() {
+            self.shared.remember(line);
+        }
+        self.finish(result)
+    }
+
+    /// Closes the connection (same as dropping the client).
+    pub fn disconnect(self) {}
+
+    fn upload(&self, command: &str, path: &Path) -> ChatResult<String> {
+        // opened before sending the command, so that a local failure does not break the protocol
+        let unreadable = |e: io::Error| {
+            ChatError::new(
+                ErrorCode::Io,
+                format!("Cannot read {}: {}", path.display(), e),
+            )
+        };
+        let file = File::open(path).map_err(unreadable)?;
+        let size = file.metadata().map_err(unreadable)?.len();
+        // only the name is sent, the local directories are of no interest to the server
+        let name = path
+            .file_name()
+            .map_or(path.to_string_lossy(), |name| name.to_string_lossy());
+        self.send_upload(command, &name, file, size)
+    }
+
+    /// Sends the content of the given size, compressed if negotiated and worth it, streamed as it is otherwise.
+    fn send_upload(
+        &self,
+        command: &str,
+        name: &str,
+        mut content: impl Read + Seek,
+        size: u64,
+    ) -> ChatResult<String> {
+        let compressed = self
+            .compress(&mut content, size)
+            .map_err(|e| ChatError::new(ErrorCode::Io, format!("Cannot read {}: {}", name, e)))?;
+        let (line, mut content, total): (_, Box<dyn Read>, _) = match compressed {
+            Some((compression, compressed)) => {
+                let line = format!(
+                    "{} {} {} {} {}\n",
+                    command,
+                    compressed.len(),
+                    quote(name),
+                    compression,
+                    size
+                );
+                let total = compressed.len() as u64;
+                (line, Box::new(Cursor::new(compressed)), total)
+            }
+            None => {
+                let line = format!("{} {} {}\n", command, size, quote(name));
+                let content = Announced {
+                    inner: content,
+                    remaining: size,
+                };
+                (line, Box::new(content), size)
+            }
+        };
+        let mut progress = Progress {
+            name: name.to_string(),
+            sent: 0,
+            total,
+            elapsed: Duration::ZERO,
+        };
+        let started_at = Instant::now();
+        let mut published_at = started_at;
+        let result = self
+            .shared
+            .request(line.as_bytes(), &mut content, &mut |sent| {
+                progress.sent += sent as u64;
+                progress.elapsed = started_at.elapsed();
+                if progress.is_done() || published_at.elapsed() >= PROGRESS_INTERVAL {
+                    published_at = Instant::now();
+                    self.shared.publish(Event::Progress(progress.clone()));
+                }
+            });
+        self.finish(result)
+    }
+
+    fn get(&self, id: &str) -> ChatResult<(String, Vec<u8>)> {
+        let line = format!(".get {}\n", quote(id));
+        let reply = self
+            .shared
+            .exchange(line.as_bytes(), &mut io::empty(), &mut |_| {})?;
+        let download = Download::from_wire(&reply.text);
+        match (download, reply.content) {
+            (Some(download), Some(content)) => Ok((download.name, content)),
+            _ => Err(ChatError::new(
+                ErrorCode::Internal,
+                format!("Unexpected response to .get: {}", reply.text),
+            )),
+        }
+    }
+
+    /// Compresses the content of the given size with the negotiated compression, `None` if not worth it
+    /// (the content is rewound to its start either way).
+    fn compress(
+        &self,
+        content: &mut (impl Read + Seek),
+        size: u64,
+    ) -> io::Result<Option<(Compression, Vec<u8>)>> {
+        let Some(compression) = *self.shared.compression.lock().unwrap() else {
+            return Ok(None);
+        };
+        let mut head = Vec::new();
+        (&mut *content)
+            .take(SNIFF_LENGTH as u64)
+            .read_to_end(&mut head)?;
+        content.rewind()?;
+        if is_compressed(&head) {
+            return Ok(None);
+        }
+        let compressed = compression.compress_from(&mut (&mut *content).take(size))?;
+        content.rewind()?;
+        Ok(((compressed.len() as u64) < size).then_some((compression, compressed)))
+    }
+
+    /// Queues the message if reconnecting, `None` if it is to be sent right away.
+    fn enqueue(&self, message: &str) -> Option<ChatResult<String>> {
+        let mut pending = self.shared.pending.lock().unwrap();
+        if pending.link != Link::Reconnecting {
+            return None;
+        }
+        let limit = self
+            .shared
+            .reconnect
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, |reconnect| reconnect.queue_size);
+        if pending.outbox.len() >= limit {
+            return Some(Err(ChatError::new(
+                ErrorCode::Offline,
+                format!("Not connected, too many ({}) messages queued", limit),
+            )));
+        }
+        pending.outbox.push_back(message.to_string());
+        Some(Ok(
+            "Not connected, the message is sent once reconnected".to_string()
+        ))
+    }
+
+    /// Publishes the outcome of a request to the subscribers.
+    fn finish(&self, result: ChatResult<String>) -> ChatResult<String> {
+        match &result {
+            Ok(response) => self.shared.publish(Event::Response(response.clone())),
+            Err(e) => self.shared.publish(Event::Error(e.clone())),
+        }
+        result
+    }
+}
+
+impl Drop for ChatClient {
+    fn drop(&mut self) {
+        // also makes the receiver thread finish instead of reconnecting
+        if let Ok(mut pending) = self.shared.pending.lock() {
+            pending.link = Link::Closed;
+        }
+        if let Ok(writer) = self.shared.writer.lock() {
+            let _ = writer.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Saves the downloaded content into a new file in the directory.
+fn save(directory: &Path, name: &str, content: &[u8]) -> ChatResult<PathBuf> {
+    // the name comes from the server, it must not point anywhere else
+    let name = Path::new(name)
+        .file_name()
+        .map_or("download".into(), |name| name.to_string_lossy().to_string());
+    let stem = Path::new(&name).with_extension("");
+    let extension = Path::new(&name)
+        .extension()
+        .map_or(String::new(), |extension| {
+            format!(".{}", extension.to_string_lossy())
+        });
+    let mut path = directory.join(&name);
+    let mut suffix = 1;
+    let mut file = loop {
+        match File::options().write(true).create_new(true).open(&path) {
+            Ok(file) => break file,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                suffix += 1;
+                path = directory.join(format!("{}-{}{}", stem.display(), suffix, extension));
+            }
+            Err(e) => return Err(write_error(&path, e)),
+        }
+    };
+    file.write_all(content).map_err(|e| write_error(&path, e))?;
+    Ok(path)
+}
+
+fn write_error(path: &Path, e: io::Error) -> ChatError {
+    ChatError::new(
+        ErrorCode::Io,
+        format!("Cannot write {}: {}", path.display(), e),
+    )
+}
+
+fn closed() -> ChatError {
+    ChatError::connection("Connection closed by the server")
+}
+
+fn offline() -> ChatError {
+    ChatError::new(ErrorCode::Offline, "Not connected, reconnecting")
+}
+
+fn receive(mut reader: BufReader<TcpStream>, shared: Arc<Shared>) {
+    loop {
+        while let Ok(Some(frame)) = read_frame(&mut reader) {
+            if let Some(event) = ServerEvent::from_wire(&frame) {
+                shared.publish(event.into());
+                continue;
+            }
+            let result = match (ChatError::from_wire(&frame), Download::from_wire(&frame)) {
+                (Some(e), _) => Err(e),
+                (None, Some(download)) => match read_content(&mut reader, &download) {
+                    Ok(content) => content.map(|content| Reply {
+                        text: frame,
+                        content: Some(content),
+                    }),
+                    // the connection is lost
+                    Err(_) => break,
+                },
+                (None, None) => Ok(Reply {
+                    text: frame,
+                    content: None,
+                }),
+            };
+            // a response nobody waits for (e.g. the requesting thread gave up) is dropped
+            if let Some(request) = shared.pending.lock().unwrap().requests.pop_front() {
+                let _ = request.send(result);
+            }
+        }
+
+        let reconnect = {
+            let reconnect = shared.reconnect.lock().unwrap().clone();
+            let mut pending = shared.pending.lock().unwrap();
+            // dropping the senders fails the requests still waiting for a response
+            pending.requests.clear();
+            match (pending.link, reconnect) {
+                (Link::Online, Some(reconnect)) => {
+                    pending.link = Link::Reconnecting;
+                    Some(reconnect)
+                }
+                _ => {
+                    pending.link = Link::Closed;
+                    None
+                }
+            }
+        };
+        match reconnect.and_then(|reconnect| reestablish(&shared, &reconnect)) {
+            Some(stream) => reader = stream,
+            None => break,
+        }
+    }
+    shared.publish(Event::Disconnected);
+}
+
+/// Connects again with growing delays, `None` once given up (or the client was dropped meanwhile).
+fn reestablish(shared: &Arc<Shared>, reconnect: &Reconnect) -> Option<BufReader<TcpStream>> {
+    let mut attempt = 1;
+    while !reconnect.gives_up(attempt) {
+        let delay = reconnect.delay(attempt);
+        shared.publish(Event::Reconnecting { attempt, delay });
+        thread::sleep(delay);
+        if shared.link() == Link::Closed {
+            return None;
+        }
+        let connection = TcpStream::connect(&shared.address[..])
+            .and_then(|stream| Ok((BufReader::new(stream.try_clone()?), stream)));
+        if let Ok((reader, stream)) = connection {
+            *shared.writer.lock().unwrap() = stream;
+            // the responses to the restoring requests are read by this (receiver) thread
+            let restoring = shared.clone();
+            if thread::Builder::new()
+                .name("restore".to_string())
+                .spawn(move || restore(&restoring))
+                .is_ok()
+            {
+                return Some(reader);
+            }
+        }
+        attempt += 1;
+    }
+    let mut pending = shared.pending.lock().unwrap();
+    pending.link = Link::Closed;
+    pending.outbox.clear();
+    None
+}
+
+/// Repeats the session commands and sends the messages queued while offline.
+fn restore(shared: &Shared) {
+    let mut lines = shared
+        .session
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(_, line)| line.clone())
+        .collect::<Vec<_>>();
+    lines.sort_by_key(|line| {
+        RESTORED_COMMANDS
+            .iter()
+            .position(|command| line.split_whitespace().next() == Some(command))
+    });
+    let mut responses = Vec::new();
+    {
+        // nobody else may write before the session is restored
+        let mut writer = shared.writer.lock().unwrap();
+        {
+            let mut pending = shared.pending.lock().unwrap();
+            if pending.link != Link::Reconnecting {
+                return;
+            }
+            pending.link = Link::Online;
+            lines.extend(pending.outbox.drain(..));
+            for line in &lines {
+                let (sender, receiver) = channel();
+                pending.requests.push_back(sender);
+                // the arguments (e.g. the admin token) are not to be shown in the errors
+                let name = line.split_whitespace().next().unwrap_or_default();
+                responses.push((name.to_string(), receiver));
+            }
+        }
+        for line in lines {
+            if writer.write_all(format!("{}\n", line).as_bytes()).is_err() {
+                // the receiver thread notices the failure as well
+                return;
+            }
+        }
+    }
+    for (name, receiver) in responses {
+        match receiver.recv() {
+            Ok(Ok(reply)) => {
+                // the server might accept another compression than before
+                if name == ".compress" {
+                    shared.negotiated(&reply.text);
+                }
+            }
+            Ok(Err(error)) => shared.publish(Event::RestoreFailed {
+                command: name.to_string(),
+                error,
+            }),
+            // lost again, the receiver thread takes care
+            Err(_) => return,
+        }
+    }
+    shared.publish(Event::Reconnected);
+}
+
+/// Writes the request line followed by the content, the failures to read the content are reported as `IO_ERROR`.
+fn write_request(
+    writer: &mut TcpStream,
+    line: &[u8],
+    content: &mut dyn Read,
+    progress: &mut dyn FnMut(usize),
+) -> ChatResult<()> {
+    writer.write_all(line).map_err(ChatError::connection)?;
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let read = content
+            .read(&mut buffer)
+            .map_err(|e| ChatError::new(ErrorCode::Io, format!("Cannot read the upload: {}", e)))?;
+        if read == 0 {
+            return Ok(());
+        }
+        writer
+            .write_all(&buffer[..read])
+            .map_err(ChatError::connection)?;
+        progress(read);
+    }
+}
+
+/// Content of an upload, failing if it ends before the size announced by the command (e.g. a truncated file).
+struct Announced<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: Read> Read for Announced<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+        let limit = self.remaining.min(buffer.len() as u64) as usize;
+        let read = self.inner.read(&mut buffer[..limit])?;
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} bytes fewer than announced", self.remaining),
+            ));
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+/// Reads the content following the response (decompressing it if needed), an I/O error means a lost connection.
+fn read_content(reader: &mut impl Read, download: &Download) -> io::Result<ChatResult<Vec<u8>>> {
+    // the sizes come from the server, nothing is allocated for them before checking
+    if download.wire_size.max(download.size) > MAX_DOWNLOAD_SIZE {
+        let size = download.wire_size as u64;
+        if io::copy(&mut reader.take(size), &mut io::sink())? < size {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        return Ok(Err(ChatError::new(
+            ErrorCode::TooLarge,
+            format!(
+                "{} has {}, downloads are limited to {}",
+                download.name,
+                format_size(download.size as u64),
+                format_size(MAX_DOWNLOAD_SIZE as u64)
+            ),
+        )));
+    }
+    let mut content = vec![0; download.wire_size];
+    reader.read_exact(&mut content)?;
+    Ok(match download.compression {
+        Some(compression) => compression
+            .decompress(&content, download.size)
+            .map_err(|e| {
+                ChatError::new(
+                    ErrorCode::DecodeFailed,
+                    format!("Failed to decompress {}: {}", download.name, e),
+                )
+            }),
+        None => Ok(content),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn download(wire_size: usize, compression: Option<Compression>, size: usize) -> Download {
+        Download {
+            name: "a.bin".to_string(),
+            wire_size,
+            compression,
+            size,
+        }
+    }
+
+    #[test]
+    fn read_content_reads_announced_bytes() {
+        let mut input = &b"abcdef"[..];
+        let content = read_content(&mut input, &download(4, None, 4)).unwrap();
+        assert_eq!(content.unwrap(), b"abcd");
+        assert_eq!(input, b"ef");
+    }
+
+    #[test]
+    fn read_content_skips_too_large_downloads() {
+        // e.g. a compressed content claiming to expand beyond the limit
+        let huge = download(4, Some(Compression::Deflate), MAX_DOWNLOAD_SIZE + 1);
+        let mut input = &b"abcdef"[..];
+        let e = read_content(&mut input, &huge).unwrap().unwrap_err();
+        assert_eq!(e.code, ErrorCode::TooLarge);
+        assert_eq!(input, b"ef");
+
+        // nothing is allocated for a size the server does not send
+        let mut input = &b"abcdef"[..];
+        let huge = download(MAX_DOWNLOAD_SIZE + 1, None, MAX_DOWNLOAD_SIZE + 1);
+        assert!(read_content(&mut input, &huge).is_err());
+    }
+
+    #[test]
+    fn announced_content_is_checked_against_its_size() {
+        let mut content = Announced {
+            inner: &b"abcdef"[..],
+            remaining: 4,
+        };
+        let mut read = Vec::new();
+        content.read_to_end(&mut read).unwrap();
+        assert_eq!(read, b"abcd");
+
+        // e.g. a file truncated after its size was taken
+        let mut content = Announced {
+            inner: &b"ab"[..],
+            remaining: 4,
+        };
+        let e = content.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
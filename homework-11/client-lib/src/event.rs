@@ -0,0 +1,92 @@
+//! Events published to the client subscribers.
+
+use common::error::ChatError;
+use common::event::ServerEvent;
+use std::time::Duration;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Event {
+    /// A (successful) response of the server to a request of this client.
+    Response(String),
+    /// An error reported by the server or a failure of the connection.
+    Error(ChatError),
+    /// A message sent by another user.
+    Message { from: String, text: String },
+    /// A notification sent by the server (e.g. the reason of being kicked).
+    Notice(String),
+    /// Another user (identified by the nick or the address) entered the room of this client.
+    Joined { user: String, room: String },
+    /// Another user left the room of this client.
+    Left { user: String, room: String },
+    /// Another user in the room of this client changed the nick.
+    NickChanged { from: String, to: String },
+    /// The room or the nick of this client changed (reported on connecting as well).
+    SessionChanged { room: String, nick: Option<String> },
+    /// Part of an upload was sent, published periodically until the upload is sent completely.
+    Progress(Progress),
+    /// The connection was lost, the next attempt to reconnect follows after the delay.
+    Reconnecting { attempt: u32, delay: Duration },
+    /// A command restoring the session after reconnecting was refused (published before `Reconnected`).
+    RestoreFailed { command: String, error: ChatError },
+    /// The connection was re-established and the session (nick, rooms, ...) restored.
+    Reconnected,
+    /// The connection to the server was lost (for good), no further events follow.
+    Disconnected,
+}
+
+/// State of an upload in progress.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Progress {
+    /// Name of the uploaded file (without the local directories).
+    pub name: String,
+    pub sent: u64,
+    pub total: u64,
+    /// Time since the upload started.
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    pub fn percent(&self) -> u8 {
+        match self.total {
+            0 => 100,
+            total => (self.sent * 100 / total) as u8,
+        }
+    }
+
+    /// Bytes sent per second so far.
+    pub fn throughput(&self) -> u64 {
+        match self.elapsed.as_millis() {
+            0 => 0,
+            millis => (self.sent as u128 * 1000 / millis) as u64,
+        }
+    }
+
+    /// Estimated time to send the rest, `None` until the throughput is known.
+    pub fn eta(&self) -> Option<Duration> {
+        match self.throughput() {
+            0 => None,
+            throughput => Some(Duration::from_secs(
+                (self.total - self.sent).div_ceil(throughput),
+            )),
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.sent >= self.total
+    }
+}
+
+impl From<ServerEvent> for Event {
+    fn from(event: ServerEvent) -> Self {
+        match event {
+            ServerEvent::Message { from, text } => Event::Message { from, text },
+            ServerEvent::Notice(text) => Event::Notice(text),
+            ServerEvent::Joined { user, room } => Event::Joined { user, room },
+            ServerEvent::Left { user, room } => Event::Left { user, room },
+            ServerEvent::NickChanged { from, to } => Event::NickChanged { from, to },
+            ServerEvent::Session { room, nick } => Event::SessionChanged { room, nick },
+            other => Event::Notice(other.to_string()),
+        }
+    }
+}
@@ -0,0 +1,33 @@
+//! Client library for the Chatee server.
+//!
+//! The library implements the client side of the protocol without any console interaction,
+//! so that bots and tools can talk to the server directly:
+//!
+//! ```no_run
+//! use client_lib::ChatClient;
+//!
+//! let client = ChatClient::connect("localhost:11111")?;
+//! client.set_nick("bot")?;
+//! client.send_message("Hello from a bot")?;
+//! let stored = client.upload_file("report.pdf")?;
+//! println!("{}", stored);
+//! # Ok::<(), client_lib::ChatError>(())
+//! ```
+//!
+//! Every request returns the response of the server, failures reported by the server come
+//! as a `ChatError` with the code sent by the server. Apart from that, all the traffic is published
+//! as `Event`s to the subscribers (see `ChatClient::subscribe`).
+//!
+//! A lost connection is re-established if enabled by `ChatClient::with_reconnect`,
+//! otherwise all the requests fail from then on.
+
+mod archive;
+mod client;
+mod event;
+mod reconnect;
+
+pub use client::{ChatClient, User};
+pub use common::compression::Compression;
+pub use common::error::{ChatError, ChatResult, ErrorCode};
+pub use event::{Event, Progress};
+pub use reconnect::Reconnect;
@@ -0,0 +1,105 @@
+//! Policy of re-establishing a lost connection.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// How the client reconnects after losing the connection (see `ChatClient::with_reconnect`).
+///
+/// The delays grow exponentially from `initial_delay` up to `max_delay`, each of them randomized
+/// between its half and its full length, so that the clients dropped by a restarting server
+/// do not all come back at the same moment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reconnect {
+    /// Delay before the first attempt.
+    pub initial_delay: Duration,
+    /// Upper bound of the delay between two attempts.
+    pub max_delay: Duration,
+    /// Number of attempts before giving up, `None` for trying forever.
+    pub max_attempts: Option<u32>,
+    /// Number of messages kept while offline to be sent once reconnected.
+    pub queue_size: usize,
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Reconnect {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            max_attempts: None,
+            queue_size: 100,
+        }
+    }
+}
+
+impl Reconnect {
+    /// The delay before the given attempt (counted from 1).
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let base = self
+            .initial_delay
+            .saturating_mul(factor)
+            .min(self.max_delay);
+        let half = base / 2;
+        let jitter = random() % (half.as_millis() as u64 + 1);
+        half + Duration::from_millis(jitter)
+    }
+
+    pub(crate) fn gives_up(&self, attempt: u32) -> bool {
+        self.max_attempts.is_some_and(|max| attempt > max)
+    }
+}
+
+/// A random number good enough for the jitter, without pulling in a dependency.
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_is_between_half_and_full_growing_base() {
+        let reconnect = Reconnect {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            ..Reconnect::default()
+        };
+        let bases = [100, 200, 400, 800, 1000, 1000];
+        for (attempt, base) in (1..).zip(bases) {
+            let base = Duration::from_millis(base);
+            for _ in 0..100 {
+                let delay = reconnect.delay(attempt);
+                assert!(
+                    base / 2 <= delay && delay <= base,
+                    "attempt {}: {:?}",
+                    attempt,
+                    delay
+                );
+            }
+        }
+        // far beyond the overflow of the exponential growth
+        assert!(reconnect.delay(u32::MAX) <= reconnect.max_delay);
+    }
+
+    #[test]
+    fn delay_is_randomized() {
+        let reconnect = Reconnect::default();
+        let delays = (0..20)
+            .map(|_| reconnect.delay(5))
+            .collect::<std::collections::HashSet<_>>();
+        assert!(delays.len() > 1);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let reconnect = Reconnect {
+            max_attempts: Some(3),
+            ..Reconnect::default()
+        };
+        assert!(!reconnect.gives_up(3));
+        assert!(reconnect.gives_up(4));
+        assert!(!Reconnect::default().gives_up(u32::MAX));
+    }
+}
@@ -0,0 +1,91 @@
+//! Non-interactive (batch) mode of the client.
+//!
+//! The commands given by `--exec` and read from the `--script` file are run one by one (exactly as if typed
+//! in the interactive mode) and their results printed to stdout, either as plain text or as JSON lines.
+//! The run stops on the first failure unless `--keep-going` is set, the exit code tells whether all
+//! the commands succeeded, so that the client can be used e.g. in CI pipelines.
+
+use crate::command::{handle_command, QUIT};
+use client_lib::ChatClient;
+use common::cli::{OutputFormat, Settings};
+use common::error::ChatResult;
+use common::util::flush;
+use serde_json::json;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read};
+
+/// Collects the commands to run, `None` if the client is meant to run interactively.
+pub(crate) fn commands(settings: &Settings) -> Result<Option<Vec<String>>, Box<dyn Error>> {
+    let mut commands = settings.exec.clone();
+    if let Some(script) = &settings.script {
+        let content = if script == "-" {
+            let mut content = String::new();
+            io::stdin().read_to_string(&mut content)?;
+            content
+        } else {
+            fs::read_to_string(script)
+                .map_err(|e| format!("Failed to read script {}: {}", script, e))?
+        };
+        // empty lines and comments are skipped
+        commands.extend(
+            content
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(String::from),
+        );
+    } else if commands.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(commands))
+}
+
+/// Runs the commands, returning whether all of them succeeded.
+pub(crate) fn run(client: &mut ChatClient, commands: &[String], settings: &Settings) -> bool {
+    let mut succeeded = true;
+    for command in commands {
+        if command == QUIT {
+            break;
+        }
+        let result = handle_command(client, command);
+        print_result(command, &result, settings.output);
+        match result {
+            Ok(_) => {}
+            // nothing more can be done without the connection
+            Err(e) if e.is_fatal() => return false,
+            Err(_) => {
+                succeeded = false;
+                if !settings.keep_going {
+                    break;
+                }
+            }
+        }
+    }
+    succeeded
+}
+
+fn print_result(command: &str, result: &ChatResult<String>, output: OutputFormat) {
+    match (output, result) {
+        (OutputFormat::Text, Ok(response)) => {
+            if !response.is_empty() {
+                println!("{}", response);
+            }
+        }
+        (OutputFormat::Text, Err(e)) => eprintln!("{}: {}", command, e),
+        (OutputFormat::Json, Ok(response)) => {
+            let record = json!({"command": command, "ok": true, "response": response});
+            println!("{}", record);
+        }
+        (OutputFormat::Json, Err(e)) => {
+            let record = json!({
+                "command": command,
+                "ok": false,
+                "code": e.code.as_str(),
+                "error": e.message,
+            });
+            println!("{}", record);
+        }
+    }
+    flush();
+}
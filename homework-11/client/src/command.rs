@@ -1,147 +1,98 @@
-//! Command handling for the server.
+//! Command handling for the client.
 //!
-//! The module handles all commands (including a declarative help for all of them).
-
-use common::util::flush;
-use common::log;
+//! The module defines the commands handled by the client itself (the uploads and downloads in particular),
+//! any other command is forwarded to the server as is. The protocol itself is implemented
+//! by the `client-lib` crate.
+
+use client_lib::ChatClient;
+use common::command::{Arg, ArgKind, Args, CommandFn, CommandSpec, FnCommand, Registry};
+use common::error::ChatResult;
+use common::info;
 use lazy_static::lazy_static;
-use std::collections::HashMap;
-use std::error::Error;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Read, Write};
-use std::net::TcpStream;
+use std::path::Path;
 
-pub struct Command {
-    pub func: Option<fn(&mut TcpStream, &str) -> Result<String, Box<dyn Error>>>,
-    pub description: String,
-}
+/// Terminates the client, handled by the input loops themselves (hence not in the registry).
+pub(crate) const QUIT: &str = ".quit";
 
 lazy_static! {
-    static ref CLIENT_COMMANDS: HashMap<&'static str, Command> = {
+    pub(crate) static ref CLIENT_COMMANDS: Registry<ChatClient> = {
+        use ArgKind::*;
         #[rustfmt::skip]
-        let functions = [
-            (".file", Command { func: Some(file), description: "Sends a file to the server for storing into files/".to_string() }),
-            (".image", Command { func: Some(image), description: "Sends an image to the server for storing into images/".to_string() }),
-            (".info", Command { func: Some(info), description: "Sends an info text to the server (to be logged there)".to_string() }),
-            (".help", Command { func: Some(help), description: "Requests help from server".to_string() }),
-            (".quit", Command { func: None, description: "Terminates the client".to_string() }),
+        let commands: [(CommandSpec, CommandFn<ChatClient>); 6] = [
+            (CommandSpec::new(".file", "Sends a file, a directory or the files matching a pattern (e.g. out/*.log) to the server for storing into files/").arg(Arg::required("path", Path)), file),
+            (CommandSpec::new(".image", "Sends an image to the server for storing into images/").arg(Arg::required("path", Path)), image),
+            (CommandSpec::new(".get", "Downloads the upload with the given ID (e.g. #a1b2) into the directory (the current one by default)").arg(Arg::required("id", Word)).arg(Arg::optional("directory", Path)), get),
+            (CommandSpec::new(".info", "Sends an info text to the server (to be logged there)").arg(Arg::required("text", Text)), info),
+            (CommandSpec::new(".help", "Requests help from server"), help),
+            (CommandSpec::new(".motd", "Requests the message of the day from server"), motd),
         ];
-        functions.into_iter().collect()
+        let mut registry = Registry::new();
+        for (spec, func) in commands {
+            registry.register(FnCommand::new(spec, func));
+        }
+        registry
     };
 }
 
-fn help(stream: &mut TcpStream, input: &str) -> Result<String, Box<dyn Error>> {
-    if !input.is_empty() {
-        return Err("Command '.help' has no arguments".into());
-    }
-    // send '.file input' to the server, wait for response
-    if let Err(e) = stream.write_all(".help\n".as_bytes()) {
-        return Err(e.into());
-    }
-    receive_server_response(stream)
+fn help(client: &mut ChatClient, _: &Args) -> ChatResult<String> {
+    client.help()
 }
 
-fn file(stream: &mut TcpStream, input: &str) -> Result<String, Box<dyn Error>> {
-    log!("Starting to send file {}", input);
-    send_command_with_content(stream, input, ".file", true)
+fn motd(client: &mut ChatClient, _: &Args) -> ChatResult<String> {
+    client.motd()
 }
 
-fn image(stream: &mut TcpStream, input: &str) -> Result<String, Box<dyn Error>> {
-    log!("Starting to send image {}", input);
-    send_command_with_content(stream, input, ".image", true)
-}
-
-fn info(stream: &mut TcpStream, input: &str) -> Result<String, Box<dyn Error>> {
-    send_command_with_content(stream, input, ".info", false)
-}
-
-fn send_command_with_content(
-    stream: &mut TcpStream,
-    input: &str,
-    command: &str,
-    is_file: bool,
-) -> Result<String, Box<dyn Error>> {
-    if input.is_empty() {
-        return Err(format!(
-            "Command '{}' requires a {}parameter",
-            command,
-            if is_file { "<filename> " } else { "" }
-        )
-            .into());
-    }
-
-    if is_file {
-        send_file(stream, command, input)?;
+fn file(client: &mut ChatClient, args: &Args) -> ChatResult<String> {
+    let path = args.value("path");
+    if Path::new(path).is_dir() {
+        info!("Starting to send directory {}", path);
+        client.upload_directory(path)
+    } else if path.contains(['*', '?', '[']) && !Path::new(path).exists() {
+        info!("Starting to send files matching {}", path);
+        client.upload_glob(path)
     } else {
-        stream.write_all(format!("{} {}\n", command, input).as_bytes())?;
+        info!("Starting to send file {}", path);
+        client.upload_file(path)
     }
-
-    receive_server_response(stream)
 }
 
-fn send_file(stream: &mut TcpStream, command: &str, file_name: &str) -> Result<(), Box<dyn Error>> {
-    let file_size = std::fs::metadata(file_name)?.len();
-    stream.write_all(format!("{} {} {}\n", command, file_size, file_name).as_bytes())?;
-    let mut file = File::open(file_name)?;
-    let mut content = Vec::new();
-    file.read_to_end(&mut content)?;
-    stream.write_all(&content)?;
-    log!("File {} sent", file_name);
-    Ok(())
+fn image(client: &mut ChatClient, args: &Args) -> ChatResult<String> {
+    let path = args.value("path");
+    info!("Starting to send image {}", path);
+    client.upload_image(path)
 }
 
-fn receive_server_response(stream: &mut TcpStream) -> Result<String, Box<dyn Error>> {
-    let mut reader = BufReader::new(stream);
-    let mut buffer = String::new();
-    let mut response = String::new();
-
-    while reader.read_line(&mut buffer)? > 0 {
-        if buffer.trim().is_empty() {
-            break;
-        }
-        response.push_str(&buffer);
-        buffer.clear();
-    }
-
-    let trimmed_response = response.trim();
-    if trimmed_response.starts_with("ERROR:") {
-        Err(trimmed_response.into())
-    } else {
-        Ok(trimmed_response.into())
-    }
+fn get(client: &mut ChatClient, args: &Args) -> ChatResult<String> {
+    let id = args.value("id");
+    info!("Starting to download {}", id);
+    client.download(id, args.get("directory").unwrap_or("."))
 }
 
-pub(crate) fn handle_command(stream: &mut TcpStream, input: &str) -> Result<String, Box<dyn Error>> {
-    if !input.starts_with('.') {
-        stream.write_all(format!("{}\n", input).as_bytes())?;
-        return receive_server_response(stream);
-    }
+fn info(client: &mut ChatClient, args: &Args) -> ChatResult<String> {
+    client.send_line(&format!(".info {}", args.value("text")))
+}
 
-    let mut parts = input.splitn(2, ' ');
-    let command = parts.next().unwrap();
-    let input = parts.next().unwrap_or("");
-    if let Some(command_spec) = CLIENT_COMMANDS.get(command) {
-        match command_spec.func {
-            Some(func) => func(stream, input),
-            None => Err("Command '.quit' is not handled".into()),
+pub(crate) fn handle_command(client: &mut ChatClient, input: &str) -> ChatResult<String> {
+    let (name, args) = input.split_once(' ').unwrap_or((input, ""));
+    match CLIENT_COMMANDS.get(name) {
+        Some(command) => {
+            let args = command.spec().parse(args)?;
+            command.execute(client, &args)
         }
-    } else {
-        let commands = CLIENT_COMMANDS.keys().collect::<Vec<_>>();
-        Err(format!("Invalid command {}, valid are: {:?}", command, commands).into())
+        // plain messages and commands handled by the server only (e.g. '.nick')
+        None => client.send_line(input),
     }
 }
 
 pub(crate) fn print_commands() {
-    log!("Available commands:");
-    let max_command_len = CLIENT_COMMANDS.keys().map(|k| k.len()).max().unwrap();
-    let mut commands: Vec<_> = CLIENT_COMMANDS.iter().collect();
-    commands.sort_by_key(|&(command, _)| command);
-    for (command, spec) in commands {
-        log!(
-            "  {:<max_command_len$} .. {}",
-            command,
-            spec.description,
-            max_command_len = max_command_len
-        );
+    info!("Available commands (see '.help' for the server ones):");
+    for line in CLIENT_COMMANDS.help(|_| true) {
+        info!("  {}", line);
     }
+    let width = CLIENT_COMMANDS
+        .specs()
+        .map(|spec| spec.usage().len())
+        .max()
+        .unwrap_or(0);
+    info!("  {:<width$}  Terminates the client", QUIT, width = width);
 }
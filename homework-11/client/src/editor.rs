@@ -0,0 +1,130 @@
+//! Input line editing for the line mode of the client.
+//!
+//! The line editor provides the Emacs or Vi key bindings, the input history persisted across sessions
+//! and the tab completion of the command names, the local paths (for the arguments of the `Path` kind,
+//! e.g. of `.file`) and the nicks of the online users (anywhere else).
+
+use crate::command::{CLIENT_COMMANDS, QUIT};
+use common::cli::{EditMode, Settings};
+use common::command::{quote, ArgKind};
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::FileHistory;
+use rustyline::validate::Validator;
+use rustyline::{Config, Context, Editor, Helper};
+use std::env;
+use std::path::PathBuf;
+
+const HISTORY_FILE_NAME: &str = ".chatee_history";
+const HISTORY_SIZE: usize = 1000;
+
+pub(crate) type LineEditor = Editor<ChatHelper, FileHistory>;
+
+#[derive(Default)]
+pub(crate) struct ChatHelper {
+    /// Names of the commands, both the client and the server ones.
+    pub(crate) commands: Vec<String>,
+    /// Nicks of the online users.
+    pub(crate) nicks: Vec<String>,
+    files: FilenameCompleter,
+}
+
+/// Creates the line editor and loads the history (if any).
+pub(crate) fn create_editor(settings: &Settings) -> rustyline::Result<LineEditor> {
+    let edit_mode = match settings.edit_mode {
+        EditMode::Emacs => rustyline::EditMode::Emacs,
+        EditMode::Vi => rustyline::EditMode::Vi,
+    };
+    let config = Config::builder()
+        .edit_mode(edit_mode)
+        .max_history_size(HISTORY_SIZE)?
+        .history_ignore_dups(true)?
+        .auto_add_history(true)
+        .build();
+    let mut editor = Editor::with_config(config)?;
+    let helper = ChatHelper {
+        commands: CLIENT_COMMANDS
+            .complete("")
+            .into_iter()
+            .chain([QUIT])
+            .map(String::from)
+            .collect(),
+        ..ChatHelper::default()
+    };
+    editor.set_helper(Some(helper));
+    if let Some(path) = history_file(settings) {
+        // the history file does not exist on the first run
+        let _ = editor.load_history(&path);
+    }
+    Ok(editor)
+}
+
+/// The file the history is persisted in, `None` if it cannot be determined.
+pub(crate) fn history_file(settings: &Settings) -> Option<PathBuf> {
+    match &settings.history_file {
+        Some(file) => Some(PathBuf::from(file)),
+        None => env::var_os("HOME")
+            .or_else(|| env::var_os("USERPROFILE"))
+            .map(|home| PathBuf::from(home).join(HISTORY_FILE_NAME)),
+    }
+}
+
+impl Completer for ChatHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let typed = &line[..pos];
+        if typed.starts_with('.') && !typed.contains(char::is_whitespace) {
+            let mut names = self
+                .commands
+                .iter()
+                .filter(|name| name.starts_with(typed))
+                .collect::<Vec<_>>();
+            names.sort();
+            names.dedup();
+            let candidates = names
+                .into_iter()
+                .map(|name| Pair {
+                    display: name.clone(),
+                    replacement: format!("{} ", name),
+                })
+                .collect();
+            return Ok((0, candidates));
+        }
+        if let Some(arg) = CLIENT_COMMANDS.arg_at(typed) {
+            if arg.kind == ArgKind::Path {
+                return self.files.complete(line, pos, ctx);
+            }
+        }
+        let start = typed
+            .rfind(char::is_whitespace)
+            .map_or(0, |index| index + 1);
+        let word = &typed[start..];
+        let candidates = self
+            .nicks
+            .iter()
+            .filter(|nick| nick.starts_with(word))
+            .map(|nick| Pair {
+                display: nick.clone(),
+                replacement: quote(nick),
+            })
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ChatHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ChatHelper {}
+
+impl Validator for ChatHelper {}
+
+impl Helper for ChatHelper {}
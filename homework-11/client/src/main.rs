@@ -1,35 +1,77 @@
+mod batch;
 mod command;
+mod editor;
+mod progress;
 mod stream_handler;
+mod tui;
 
-use common::cli::{parse_args, CliArg};
-use common::util::flush;
-use common::{elog, log};
-use std::net::TcpStream;
+use client_lib::{ChatClient, ChatResult, Reconnect};
+use common::cli::{parse_args, CliArg, Settings, UiMode};
+use common::logging;
+use common::{error, info, warn};
 use stream_handler::handle_stream;
 
 fn main() {
-    let args = [CliArg::Host, CliArg::Port];
-    let (host, port) = match parse_args("client", &args) {
-        Ok(params) => {
-            let [host, port]: [String; 2] =
-                params.try_into().expect("Exactly 2 parameters expected");
-            (host, port)
-        }
+    #[rustfmt::skip]
+    let args = [CliArg::Config, CliArg::Host, CliArg::Port, CliArg::LogLevel, CliArg::LogFormat, CliArg::LogFile, CliArg::LogMaxSize, CliArg::Ui, CliArg::HistoryFile, CliArg::EditMode, CliArg::Script, CliArg::Exec, CliArg::KeepGoing, CliArg::Output, CliArg::Compression];
+    let settings = match parse_args("client", &args).and_then(|settings| {
+        logging::init(&settings.log)?;
+        Ok(settings)
+    }) {
+        Ok(settings) => settings,
         Err(e) => {
-            elog!("Error parsing arguments: {}", e);
+            error!("Error parsing arguments: {}", e);
             std::process::exit(1);
         }
     };
 
-    let address = format!("{}:{}", host, port);
-    log!("Connecting to {}", address);
-    match TcpStream::connect(&address) {
-        Ok(mut stream) => {
-            handle_stream(&mut stream);
+    let batch = match batch::commands(&settings) {
+        Ok(batch) => batch,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
         }
+    };
+    if batch.is_some() {
+        // stdout is reserved for the command results
+        let _ = logging::redirect_console(|_, message| eprintln!("{}", message));
+    }
+
+    let address = format!("{}:{}", settings.host, settings.port);
+    info!("Connecting to {}", address);
+    match connect(&address, &settings) {
+        Ok(mut client) => match (batch, settings.ui) {
+            (Some(commands), _) => {
+                if !batch::run(&mut client, &commands, &settings) {
+                    std::process::exit(1);
+                }
+            }
+            (None, UiMode::Line) => {
+                let mut client = client.with_reconnect(Reconnect::default());
+                handle_stream(&mut client, &settings)
+            }
+            (None, UiMode::Tui) => {
+                let client = client.with_reconnect(Reconnect::default());
+                if let Err(e) = tui::run(client, address) {
+                    error!("Terminal user interface failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
         Err(e) => {
-            elog!("Failed to connect to server: {}", e);
+            error!("Failed to connect to server: {}", e);
             std::process::exit(1);
         }
     }
 }
+
+fn connect(address: &str, settings: &Settings) -> ChatResult<ChatClient> {
+    let client = ChatClient::connect(address)?;
+    if let Some(compression) = settings.compression {
+        // an older server without the compression support just gets the uploads as they are
+        if let Err(e) = client.negotiate_compression(&[compression]) {
+            warn!("Failed to negotiate the compression: {}", e);
+        }
+    }
+    Ok(client)
+}
@@ -0,0 +1,34 @@
+//! Rendering of the upload progress reported by the `client-lib` events.
+
+use client_lib::Progress;
+use common::util::{format_duration, format_size};
+
+const BAR_WIDTH: usize = 30;
+
+/// The progress bar followed by the summary, e.g. `[#######-------] 45% ...`.
+pub(crate) fn bar(progress: &Progress) -> String {
+    let filled = BAR_WIDTH * progress.percent() as usize / 100;
+    format!(
+        "[{}{}] {}",
+        "#".repeat(filled),
+        "-".repeat(BAR_WIDTH - filled),
+        summary(progress)
+    )
+}
+
+/// The percentage, bytes, throughput and ETA, e.g. `45% 1.2 MB/2.6 MB, 3.4 MB/s, ETA 0m 02s`.
+pub(crate) fn summary(progress: &Progress) -> String {
+    let eta = match progress.eta() {
+        _ if progress.is_done() => "done".to_string(),
+        Some(eta) => format!("ETA {}", format_duration(eta)),
+        None => "ETA unknown".to_string(),
+    };
+    format!(
+        "{}% {}/{}, {}/s, {}",
+        progress.percent(),
+        format_size(progress.sent),
+        format_size(progress.total),
+        format_size(progress.throughput()),
+        eta
+    )
+}
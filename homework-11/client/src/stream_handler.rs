@@ -1,49 +1,189 @@
 //! Stream handler module for client.
 //!
-//! The module handles communication stream with the server for the client.
+//! The module handles communication stream with the server for the client: the user input is read
+//! on the main thread, while the messages of the other users are printed as they arrive
+//! (above the input line, so that the line being edited is not garbled).
 
-use crate::command::{handle_command, print_commands};
+use crate::command::{handle_command, print_commands, QUIT};
+use crate::editor::{create_editor, history_file, LineEditor};
+use crate::progress;
+use client_lib::{ChatClient, Event};
+use common::cli::Settings;
+use common::logging;
 use common::util::flush;
-use common::{elog, log};
-use std::io::stdin;
-use std::net::TcpStream;
+use common::{error, info, warn};
+use rustyline::error::ReadlineError;
+use rustyline::ExternalPrinter;
+use std::io::{stdout, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread;
 
-pub(crate) fn handle_stream(stream: &mut TcpStream) {
-    log!("Connected to server, please input '.<cmd> <param>' (Ctrl+D or 'exit' to finish):");
+const PROMPT: &str = "> ";
+
+pub(crate) fn handle_stream(client: &mut ChatClient, settings: &Settings) {
+    let mut editor = match create_editor(settings) {
+        Ok(editor) => editor,
+        Err(e) => {
+            error!("Failed to set up the input line: {}", e);
+            return;
+        }
+    };
+    // the records (e.g. the incoming messages) are printed above the input line instead of over it,
+    // unless not on a terminal
+    if let Ok(printer) = editor.create_external_printer() {
+        let printer = Mutex::new(printer);
+        let redirected = logging::redirect_console(move |level, message| {
+            let line = format!("{}\n", logging::format_line(level, message));
+            let _ = printer.lock().unwrap().print(line);
+        });
+        if let Err(e) = redirected {
+            warn!("Failed to print above the input line: {}", e);
+        }
+    }
+    // the nicks offered by the completion are refreshed whenever somebody comes, goes or changes the nick
+    let nicks_stale = Arc::new(AtomicBool::new(true));
+    let events = client.subscribe();
+    let stale = nicks_stale.clone();
+    if let Err(e) = thread::Builder::new()
+        .name("events".to_string())
+        .spawn(move || print_events(events, &stale))
+    {
+        error!("Failed to start printing incoming messages: {}", e);
+    }
+
+    info!("Connected to server, please input '.<cmd> <param>' (Ctrl+D or '.quit' to finish, Tab to complete):");
     print_commands();
+    match handle_command(client, ".motd") {
+        Ok(motd) if !motd.is_empty() => {
+            info!("Message of the day: {}", motd);
+        }
+        Ok(_) => {}
+        Err(e) => {
+            error!("Failed to get the message of the day: {}", e);
+        }
+    }
+    if let (Ok(help), Some(helper)) = (client.help(), editor.helper_mut()) {
+        let commands = help
+            .lines()
+            .filter_map(|line| line.split_whitespace().next());
+        helper.commands.extend(
+            commands
+                .filter(|name| name.starts_with('.'))
+                .map(String::from),
+        );
+    }
     flush();
 
     loop {
-        let mut buffer = String::new();
-        match stdin().read_line(&mut buffer) {
-            Ok(0) => break,
-            Ok(_) => {
+        if nicks_stale.swap(false, Ordering::SeqCst) {
+            refresh_nicks(client, &mut editor);
+        }
+        match editor.readline(PROMPT) {
+            Ok(buffer) => {
                 let input = buffer.trim_end().to_string();
                 match input.as_str().trim() {
                     "" => {
                         continue;
                     }
-                    ".quit" => {
+                    QUIT => {
                         break;
                     }
                     _ => {
-                        let result = handle_command(stream, &input);
+                        let result = handle_command(client, &input);
                         match result {
+                            // plain messages are acknowledged by an empty response
+                            Ok(response) if response.is_empty() => {}
                             Ok(response) => {
-                                log!("Server: {}", response);
+                                info!("Server: {}", response);
                             }
-                            Err(e) => {
-                                elog!("Error handling command: {}", e);
+                            Err(e) if e.is_fatal() => {
+                                error!("Connection to the server lost: {}", e);
                                 break;
                             }
+                            Err(e) => {
+                                error!("Error handling command: {}", e);
+                            }
                         }
                     }
                 }
             }
+            Err(ReadlineError::Eof | ReadlineError::Interrupted) => break,
             Err(e) => {
-                elog!("Error reading input: {}", e);
+                error!("Error reading input: {}", e);
                 break;
             }
         }
     }
+
+    if let Some(path) = history_file(settings) {
+        if let Err(e) = editor.save_history(&path) {
+            warn!(
+                "Failed to save the input history to {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+fn refresh_nicks(client: &ChatClient, editor: &mut LineEditor) {
+    match (client.users(), editor.helper_mut()) {
+        (Ok(users), Some(helper)) => {
+            helper.nicks = users.into_iter().map(|user| user.name).collect();
+        }
+        (Err(e), _) if !e.is_fatal() => warn!("Failed to list the online users: {}", e),
+        _ => {}
+    }
+}
+
+/// Prints the messages and notices pushed by the server until the connection is closed.
+fn print_events(events: Receiver<Event>, nicks_stale: &AtomicBool) {
+    // the progress bar is redrawn in place, which makes no sense unless on a terminal
+    let progress_bar = stdout().is_terminal();
+    for event in events {
+        match event {
+            Event::Message { from, text } => info!("<{}> {}", from, text),
+            Event::Notice(text) => info!("*** {}", text),
+            Event::Joined { user, room } => {
+                nicks_stale.store(true, Ordering::SeqCst);
+                info!("*** {} joined #{}", user, room);
+            }
+            Event::Left { user, room } => {
+                nicks_stale.store(true, Ordering::SeqCst);
+                info!("*** {} left #{}", user, room);
+            }
+            Event::NickChanged { from, to } => {
+                nicks_stale.store(true, Ordering::SeqCst);
+                info!("*** {} is now known as {}", from, to);
+            }
+            // the own nick is among the listed users as well
+            Event::SessionChanged { .. } => {
+                nicks_stale.store(true, Ordering::SeqCst);
+                continue;
+            }
+            Event::Progress(progress) if progress_bar => {
+                print!("\r{}", progress::bar(&progress));
+                if progress.is_done() {
+                    println!();
+                }
+            }
+            Event::Reconnecting { attempt, delay } => warn!(
+                "Connection to the server lost, reconnecting in {:.1}s (attempt {})",
+                delay.as_secs_f64(),
+                attempt
+            ),
+            Event::Reconnected => {
+                nicks_stale.store(true, Ordering::SeqCst);
+                info!("Reconnected to the server");
+            }
+            Event::RestoreFailed { command, error } => {
+                error!("Failed to restore {}: {}", command, error)
+            }
+            Event::Disconnected => break,
+            _ => continue,
+        }
+        flush();
+    }
 }
@@ -0,0 +1,502 @@
+//! Full-screen terminal user interface of the client (`--ui tui`).
+//!
+//! The screen consists of the message pane (scrolled by PageUp/PageDown), a sidebar with the rooms
+//! and the users of the current room, a status bar and the input line. The input is handled
+//! by the same `handle_command` as in the line mode, but executed by a worker thread, so that the screen
+//! stays responsive during long uploads. The sidebar is refreshed using `.users` on start,
+//! whenever the server announces a change and periodically.
+
+use crate::command::{handle_command, QUIT};
+use crate::progress;
+use client_lib::{ChatClient, ChatResult, Event, Progress, User};
+use common::command::next_token;
+use common::logging::{self, Level};
+use ratatui::crossterm::event::{self as terminal, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Position, Rect};
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, List, ListItem, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::io;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait for a key press before checking the other event sources.
+const TICK: Duration = Duration::from_millis(100);
+const USERS_REFRESH_INTERVAL: Duration = Duration::from_secs(15);
+const SIDEBAR_WIDTH: u16 = 28;
+/// Number of the messages kept in the message pane.
+const MAX_ENTRIES: usize = 5000;
+
+/// Request for the worker thread.
+enum Job {
+    /// A line typed by the user.
+    Input(String),
+    /// Request for the list of the online users.
+    Users,
+}
+
+/// Outcome of a `Job`.
+enum Outcome {
+    Input(ChatResult<String>),
+    Users(ChatResult<Vec<User>>),
+}
+
+enum Entry {
+    Own(String),
+    Message { from: String, text: String },
+    Notice(String),
+    Response(String),
+    Error(String),
+    Log(Level, String),
+}
+
+/// Line being edited (the cursor being a char index).
+#[derive(Default)]
+struct Input {
+    text: String,
+    cursor: usize,
+}
+
+impl Input {
+    fn byte_index(&self) -> usize {
+        self.text
+            .char_indices()
+            .nth(self.cursor)
+            .map_or(self.text.len(), |(index, _)| index)
+    }
+
+    fn len(&self) -> usize {
+        self.text.chars().count()
+    }
+
+    fn insert(&mut self, c: char) {
+        let index = self.byte_index();
+        self.text.insert(index, c);
+        self.cursor += 1;
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.delete();
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.cursor < self.len() {
+            let index = self.byte_index();
+            self.text.remove(index);
+        }
+    }
+
+    fn take(&mut self) -> String {
+        self.cursor = 0;
+        std::mem::take(&mut self.text)
+    }
+}
+
+struct App {
+    address: String,
+    entries: VecDeque<Entry>,
+    /// Number of lines the message pane is scrolled up by.
+    scroll: usize,
+    input: Input,
+    users: Vec<User>,
+    nick: Option<String>,
+    room: String,
+    connected: bool,
+    /// The attempt to reconnect in progress, if the connection was lost.
+    reconnecting: Option<u32>,
+    /// Lines sent to the worker and not answered yet, with the time of sending.
+    pending: VecDeque<(String, Instant)>,
+    /// Progress of the upload in progress (if any).
+    upload: Option<Progress>,
+    users_refreshed: Instant,
+    quit: bool,
+}
+
+/// Runs the full-screen interface until the user quits.
+pub(crate) fn run(client: ChatClient, address: String) -> io::Result<()> {
+    let (logs_sender, logs) = channel();
+    logging::redirect_console(move |level, message| {
+        let _ = logs_sender.send((level, message.to_string()));
+    })
+    .map_err(|e| io::Error::other(e.to_string()))?;
+
+    let events = client.subscribe();
+    let (jobs, worker_jobs) = channel();
+    let (worker_outcomes, outcomes) = channel();
+    thread::Builder::new()
+        .name("worker".to_string())
+        .spawn(move || work(client, worker_jobs, worker_outcomes))?;
+
+    let mut app = App::new(address);
+    for job in [Job::Input(".motd".to_string()), Job::Users] {
+        app.submit(&jobs, job);
+    }
+
+    let mut terminal = ratatui::init();
+    let result = app.run(&mut terminal, &jobs, &events, &outcomes, &logs);
+    ratatui::restore();
+    result
+}
+
+fn work(mut client: ChatClient, jobs: Receiver<Job>, outcomes: Sender<Outcome>) {
+    for job in jobs {
+        let outcome = match job {
+            Job::Input(line) => Outcome::Input(handle_command(&mut client, &line)),
+            Job::Users => Outcome::Users(client.users()),
+        };
+        if outcomes.send(outcome).is_err() {
+            break;
+        }
+    }
+}
+
+impl App {
+    fn new(address: String) -> Self {
+        App {
+            address,
+            entries: VecDeque::new(),
+            scroll: 0,
+            input: Input::default(),
+            users: Vec::new(),
+            nick: None,
+            room: "lobby".to_string(),
+            connected: true,
+            reconnecting: None,
+            pending: VecDeque::new(),
+            upload: None,
+            users_refreshed: Instant::now(),
+            quit: false,
+        }
+    }
+
+    fn run(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        jobs: &Sender<Job>,
+        events: &Receiver<Event>,
+        outcomes: &Receiver<Outcome>,
+        logs: &Receiver<(Level, String)>,
+    ) -> io::Result<()> {
+        while !self.quit {
+            terminal.draw(|frame| self.draw(frame))?;
+            if terminal::poll(TICK)? {
+                if let terminal::Event::Key(key) = terminal::read()? {
+                    if key.kind == KeyEventKind::Press {
+                        self.on_key(key, jobs);
+                    }
+                }
+            }
+            while let Ok(event) = events.try_recv() {
+                self.on_event(event, jobs);
+            }
+            while let Ok(outcome) = outcomes.try_recv() {
+                self.on_outcome(outcome);
+            }
+            while let Ok((level, message)) = logs.try_recv() {
+                self.push(Entry::Log(level, message));
+            }
+            if self.connected
+                && self.reconnecting.is_none()
+                && self.users_refreshed.elapsed() >= USERS_REFRESH_INTERVAL
+            {
+                self.submit(jobs, Job::Users);
+            }
+        }
+        Ok(())
+    }
+
+    fn submit(&mut self, jobs: &Sender<Job>, job: Job) {
+        match &job {
+            Job::Input(line) => self.pending.push_back((line.clone(), Instant::now())),
+            Job::Users => self.users_refreshed = Instant::now(),
+        }
+        // the worker lives as long as the jobs are received
+        let _ = jobs.send(job);
+    }
+
+    fn push(&mut self, entry: Entry) {
+        if self.entries.len() == MAX_ENTRIES {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    fn on_key(&mut self, key: KeyEvent, jobs: &Sender<Job>) {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Esc => self.quit = true,
+            KeyCode::Char('c') if ctrl => self.quit = true,
+            KeyCode::Char('d') if ctrl && self.input.text.is_empty() => self.quit = true,
+            KeyCode::Char('u') if ctrl => {
+                self.input.take();
+            }
+            KeyCode::Char('a') if ctrl => self.input.cursor = 0,
+            KeyCode::Char('e') if ctrl => self.input.cursor = self.input.len(),
+            KeyCode::Char(c) if !ctrl => self.input.insert(c),
+            KeyCode::Backspace => self.input.backspace(),
+            KeyCode::Delete => self.input.delete(),
+            KeyCode::Left => self.input.cursor = self.input.cursor.saturating_sub(1),
+            KeyCode::Right => self.input.cursor = (self.input.cursor + 1).min(self.input.len()),
+            KeyCode::Home => self.input.cursor = 0,
+            KeyCode::End => self.input.cursor = self.input.len(),
+            KeyCode::PageUp => self.scroll += 10,
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(10),
+            KeyCode::Enter => self.on_enter(jobs),
+            _ => {}
+        }
+    }
+
+    fn on_enter(&mut self, jobs: &Sender<Job>) {
+        let input = self.input.take();
+        let line = input.trim();
+        match line {
+            "" => {}
+            QUIT => self.quit = true,
+            _ => {
+                if !line.starts_with('.') {
+                    self.push(Entry::Own(line.to_string()));
+                }
+                self.scroll = 0;
+                self.submit(jobs, Job::Input(line.to_string()));
+            }
+        }
+    }
+
+    fn on_event(&mut self, event: Event, jobs: &Sender<Job>) {
+        match event {
+            Event::Message { from, text } => self.push(Entry::Message { from, text }),
+            Event::Notice(text) => self.push(Entry::Notice(text)),
+            // the users are refreshed only when needed, the automatic requests count against the rate limit as well
+            Event::Joined { user, room } => {
+                self.push(Entry::Notice(format!("{} joined #{}", user, room)));
+                self.submit(jobs, Job::Users);
+            }
+            Event::Left { user, room } => {
+                self.push(Entry::Notice(format!("{} left #{}", user, room)));
+                self.submit(jobs, Job::Users);
+            }
+            Event::NickChanged { from, to } => {
+                self.push(Entry::Notice(format!("{} is now known as {}", from, to)));
+                self.submit(jobs, Job::Users);
+            }
+            Event::SessionChanged { room, nick } => {
+                self.nick = nick;
+                if self.room != room {
+                    self.room = room;
+                    self.submit(jobs, Job::Users);
+                }
+            }
+            Event::Progress(progress) => self.upload = Some(progress),
+            Event::Reconnecting { attempt, delay } => {
+                if self.reconnecting.is_none() {
+                    self.push(Entry::Error("Connection to the server lost".to_string()));
+                }
+                self.reconnecting = Some(attempt);
+                self.push(Entry::Notice(format!(
+                    "Reconnecting in {:.1}s (attempt {})",
+                    delay.as_secs_f64(),
+                    attempt
+                )));
+            }
+            Event::Reconnected => {
+                self.reconnecting = None;
+                self.push(Entry::Notice("Reconnected to the server".to_string()));
+                self.submit(jobs, Job::Users);
+            }
+            Event::RestoreFailed { command, error } => self.push(Entry::Error(format!(
+                "Failed to restore {}: {}",
+                command, error
+            ))),
+            Event::Disconnected => {
+                self.connected = false;
+                self.reconnecting = None;
+                self.push(Entry::Error("Connection to the server lost".to_string()));
+            }
+            // the outcomes of the requests are received from the worker
+            _ => {}
+        }
+    }
+
+    fn on_outcome(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Input(result) => {
+                self.pending.pop_front();
+                self.upload = None;
+                match result {
+                    Ok(response) => {
+                        for line in response.lines() {
+                            self.push(Entry::Response(line.to_string()));
+                        }
+                    }
+                    Err(e) => self.push(Entry::Error(e.to_string())),
+                }
+            }
+            Outcome::Users(Ok(users)) => self.users = users,
+            Outcome::Users(Err(_)) => {}
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [main, status, input] = Layout::vertical([
+            Constraint::Min(3),
+            Constraint::Length(1),
+            Constraint::Length(3),
+        ])
+        .areas(frame.area());
+        let [messages, sidebar] =
+            Layout::horizontal([Constraint::Min(20), Constraint::Length(SIDEBAR_WIDTH)])
+                .areas(main);
+        self.draw_messages(frame, messages);
+        self.draw_sidebar(frame, sidebar);
+        self.draw_status(frame, status);
+        self.draw_input(frame, input);
+    }
+
+    fn draw_messages(&self, frame: &mut Frame, area: Rect) {
+        let own = self.nick.clone().unwrap_or_else(|| "me".to_string());
+        let lines = self
+            .entries
+            .iter()
+            .map(|entry| match entry {
+                Entry::Own(text) => Line::from(vec![
+                    Span::from(format!("<{}> ", own)).bold().fg(Color::Green),
+                    Span::from(text.as_str()),
+                ]),
+                Entry::Message { from, text } => Line::from(vec![
+                    Span::from(format!("<{}> ", from)).bold().fg(Color::Cyan),
+                    Span::from(text.as_str()),
+                ]),
+                Entry::Notice(text) => Line::from(format!("*** {}", text)).fg(Color::Yellow),
+                Entry::Response(text) => Line::from(text.as_str()).fg(Color::Gray),
+                Entry::Error(text) => Line::from(format!("!!! {}", text)).fg(Color::Red),
+                Entry::Log(level, text) => {
+                    let color = match level {
+                        Level::Error => Color::Red,
+                        Level::Warn => Color::Yellow,
+                        _ => Color::DarkGray,
+                    };
+                    Line::from(text.as_str()).fg(color)
+                }
+            })
+            .collect::<Vec<_>>();
+        let block = Block::default()
+            .borders(Borders::ALL)
+            .title(format!(" #{} ", self.room));
+        let paragraph = Paragraph::new(lines).wrap(Wrap { trim: false });
+        // the pane sticks to the bottom unless scrolled up
+        let inner = block.inner(area);
+        let total = paragraph.line_count(inner.width);
+        let offset = total
+            .saturating_sub(inner.height as usize)
+            .saturating_sub(self.scroll);
+        frame.render_widget(paragraph.block(block).scroll((offset as u16, 0)), area);
+    }
+
+    fn draw_sidebar(&self, frame: &mut Frame, area: Rect) {
+        let mut rooms = BTreeMap::<&str, usize>::new();
+        rooms.insert(&self.room, 0);
+        for user in &self.users {
+            *rooms.entry(&user.room).or_default() += 1;
+        }
+        let [rooms_area, users_area] = Layout::vertical([
+            Constraint::Length(rooms.len() as u16 + 2),
+            Constraint::Min(3),
+        ])
+        .areas(area);
+
+        let rooms = rooms
+            .into_iter()
+            .map(|(room, count)| {
+                let item = ListItem::new(format!("#{} ({})", room, count));
+                if room == self.room {
+                    item.add_modifier(Modifier::BOLD)
+                } else {
+                    item
+                }
+            })
+            .collect::<Vec<_>>();
+        let rooms = List::new(rooms).block(Block::default().borders(Borders::ALL).title(" Rooms "));
+        frame.render_widget(rooms, rooms_area);
+
+        let users = self
+            .users
+            .iter()
+            .filter(|user| user.room == self.room)
+            .map(|user| ListItem::new(user.name.as_str()))
+            .collect::<Vec<_>>();
+        let users = List::new(users).block(Block::default().borders(Borders::ALL).title(" Users "));
+        frame.render_widget(users, users_area);
+    }
+
+    fn draw_status(&self, frame: &mut Frame, area: Rect) {
+        let mut status = if let Some(attempt) = self.reconnecting {
+            format!(" Reconnecting to {} (attempt {})", self.address, attempt)
+        } else if self.connected {
+            format!(
+                " Connected to {} as {}",
+                self.address,
+                self.nick.as_deref().unwrap_or("(no nick)")
+            )
+        } else {
+            format!(" Disconnected from {}", self.address)
+        };
+        if let Some(upload) = &self.upload {
+            status.push_str(&format!(
+                " | Uploading {} {}",
+                upload.name,
+                progress::summary(upload)
+            ));
+        } else if let Some((line, since)) = self.pending.front() {
+            status.push_str(&format!(
+                " | {} ({}s)",
+                describe(line),
+                since.elapsed().as_secs()
+            ));
+        }
+        if self.scroll > 0 {
+            status.push_str(" | scrolled (PageDown to follow)");
+        }
+        let style = if self.reconnecting.is_some() {
+            Style::default().bg(Color::Yellow).fg(Color::Black)
+        } else if self.connected {
+            Style::default().bg(Color::Blue).fg(Color::White)
+        } else {
+            Style::default().bg(Color::Red).fg(Color::White)
+        };
+        frame.render_widget(Paragraph::new(status).style(style), area);
+    }
+
+    fn draw_input(&self, frame: &mut Frame, area: Rect) {
+        // the line is scrolled horizontally to keep the cursor visible
+        let width = area.width.saturating_sub(2) as usize;
+        let start = (self.input.cursor + 1).saturating_sub(width);
+        let visible = self.input.text.chars().skip(start).collect::<String>();
+        let block = Block::default()
+            .borders(Borders::ALL)
+            .title(" Message or .command (Esc to quit) ");
+        frame.render_widget(Paragraph::new(visible).block(block), area);
+        frame.set_cursor_position(Position::new(
+            area.x + 1 + (self.input.cursor - start) as u16,
+            area.y + 1,
+        ));
+    }
+}
+
+/// Describes the request in progress for the status bar.
+fn describe(line: &str) -> String {
+    let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+    match (name, next_token(args)) {
+        (".file" | ".image", Ok(Some((path, _)))) => match fs::metadata(&path) {
+            Ok(metadata) => format!("Uploading {} ({} bytes)", path, metadata.len()),
+            Err(_) => format!("Uploading {}", path),
+        },
+        _ => format!("Waiting for {}", name),
+    }
+}
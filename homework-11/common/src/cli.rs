@@ -1,76 +1,730 @@
 //! Common command-line parsing utilities for any and all used parameters across client and server.
 //!
-//! This module provides functionality to parse command-line arguments
-//! and extract values for different parameters such as host and port.
+//! This module provides functionality to resolve the runtime settings
+//! from the command line, the environment and an optional TOML configuration file.
 //!
 //! The `CliArg` enum defines the different parameters that can be received,
 //! the module also covers defaults shared across client and server.
+//!
+//! Each setting is resolved with the following precedence (the first one found wins):
+//!
+//! 1. command-line flag (e.g. `--port 2222`),
+//! 2. environment variable (e.g. `CHATEE_PORT=2222`),
+//! 3. key in the configuration file given by `--config` or `CHATEE_CONFIG` (e.g. `port = 2222`),
+//! 4. built-in default.
 
-use clap::{Arg, ArgMatches, Command};
+use crate::compression::Compression;
+use crate::content_type;
+use crate::logging::LogSettings;
+use crate::util::{parse_duration, parse_size};
+use clap::{Arg, ArgAction, ArgMatches, Command};
 use std::error::Error;
+use std::fs;
+use std::str::FromStr;
+use std::time::Duration;
 
-const HOST_DEFAULT: &'static str = "localhost";
-const PORT_DEFAULT: &'static str = "11111";
-const FILE_DIRECTORY_DEFAULT: &'static str = "files";
-const IMAGE_DIRECTORY_DEFAULT: &'static str = "images";
+const HOST_DEFAULT: &str = "localhost";
+const PORT_DEFAULT: &str = "11111";
+const FILE_DIRECTORY_DEFAULT: &str = "files";
+const IMAGE_DIRECTORY_DEFAULT: &str = "images";
+const BAN_FILE_DEFAULT: &str = "bans.txt";
+const MAX_UPLOAD_SIZE_DEFAULT: &str = "100M";
+const RATE_LIMIT_DEFAULT: &str = "0";
+const IMAGE_FORMAT_DEFAULT: &str = "png";
+const LOG_LEVEL_DEFAULT: &str = "info";
+const LOG_FORMAT_DEFAULT: &str = "text";
+const LOG_MAX_SIZE_DEFAULT: &str = "10M";
+const UI_DEFAULT: &str = "line";
+const EDIT_MODE_DEFAULT: &str = "emacs";
+const OUTPUT_DEFAULT: &str = "text";
+const COMPRESSION_DEFAULT: &str = "deflate";
+/// The image formats the server can decode.
+const ALLOW_IMAGE_TYPES_DEFAULT: &str = "png,jpeg,gif,webp,bmp,tiff,ico";
+const CATALOG_FILE_DEFAULT: &str = "catalog.txt";
+const QUOTA_DEFAULT: &str = "0";
+const MIN_FREE_SPACE_DEFAULT: &str = "0";
+const RETENTION_SIZE_DEFAULT: &str = "0";
+const RETENTION_INTERVAL_DEFAULT: &str = "1h";
 
+const ENV_PREFIX: &str = "CHATEE_";
 
+#[derive(Clone, Copy)]
 pub enum CliArg {
+    Config,
     Host,
     Port,
     FileDir,
     ImageDir,
+    AdminToken,
+    BanFile,
+    MaxUploadSize,
+    RateLimit,
+    Motd,
+    ImageFormat,
+    LogLevel,
+    LogFormat,
+    LogFile,
+    LogMaxSize,
+    MetricsAddr,
+    AuditLog,
+    Ui,
+    HistoryFile,
+    EditMode,
+    Script,
+    Exec,
+    KeepGoing,
+    Output,
+    Compression,
+    AllowFileTypes,
+    DenyFileTypes,
+    AllowImageTypes,
+    DenyImageTypes,
+    CatalogFile,
+    UserQuota,
+    StorageQuota,
+    MinFreeSpace,
+    RetentionAge,
+    RetentionSize,
+    RetentionInterval,
+    RetentionArchiveDir,
+    RetentionDryRun,
+    WsAddr,
+    HttpAddr,
+}
+
+/// How the value of a parameter is given.
+enum ValueKind {
+    /// A single value (e.g. `--port 2222`).
+    Single,
+    /// A switch without a value (e.g. `--keep-going`), resolved to `true` if present.
+    Flag,
+    /// A value that can be repeated (e.g. `--exec .a --exec .b`), resolved to the values on separate lines.
+    Multiple,
+}
+
+/// Format of the results of the commands run in the batch mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!(
+                "Invalid output format '{}', valid are: text, json",
+                s
+            )),
+        }
+    }
+}
+
+/// User interface of the client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UiMode {
+    /// Line-by-line console interface.
+    Line,
+    /// Full-screen terminal interface.
+    Tui,
+}
+
+/// Key bindings of the client input line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EditMode {
+    Emacs,
+    Vi,
+}
+
+impl FromStr for EditMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "emacs" => Ok(EditMode::Emacs),
+            "vi" => Ok(EditMode::Vi),
+            _ => Err(format!("Invalid edit mode '{}', valid are: emacs, vi", s)),
+        }
+    }
+}
+
+impl FromStr for UiMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "line" => Ok(UiMode::Line),
+            "tui" => Ok(UiMode::Tui),
+            _ => Err(format!(
+                "Invalid user interface '{}', valid are: line, tui",
+                s
+            )),
+        }
+    }
+}
+
+/// Fully resolved runtime settings.
+///
+/// Settings not requested by the application are left at their defaults.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub config: Option<String>,
+    pub host: String,
+    pub port: u16,
+    pub file_dir: String,
+    pub image_dir: String,
+    pub admin_token: Option<String>,
+    pub ban_file: String,
+    pub max_upload_size: u64,
+    pub rate_limit: u32,
+    pub motd: Option<String>,
+    pub image_format: String,
+    pub log: LogSettings,
+    pub metrics_addr: Option<String>,
+    pub audit_log: Option<String>,
+    pub ui: UiMode,
+    pub history_file: Option<String>,
+    pub edit_mode: EditMode,
+    pub script: Option<String>,
+    pub exec: Vec<String>,
+    pub keep_going: bool,
+    pub output: OutputFormat,
+    /// Transfer compression, `None` if disabled.
+    pub compression: Option<Compression>,
+    /// Content types allowed for `.file` (all if empty), see `content_type`.
+    pub allow_file_types: Vec<String>,
+    pub deny_file_types: Vec<String>,
+    /// Content types allowed for `.image` (all if empty).
+    pub allow_image_types: Vec<String>,
+    pub deny_image_types: Vec<String>,
+    pub catalog_file: String,
+    /// Storage quota per user in bytes, 0 for unlimited.
+    pub user_quota: u64,
+    /// Quota of the whole storage (files and images) in bytes, 0 for unlimited.
+    pub storage_quota: u64,
+    /// Free space left on the storage filesystem, uploads are refused below it.
+    pub min_free_space: u64,
+    /// Age the uploads are removed at, kept forever if `None`.
+    pub retention_age: Option<Duration>,
+    /// Size of the storage the oldest uploads are removed beyond, 0 for unlimited.
+    pub retention_size: u64,
+    pub retention_interval: Duration,
+    /// Directory the removed uploads are moved to, deleted if `None`.
+    pub retention_archive_dir: Option<String>,
+    pub retention_dry_run: bool,
+    pub ws_addr: Option<String>,
+    pub http_addr: Option<String>,
+}
+
+impl Default for Settings {
+    /// Settings with all the parameters at their defaults.
+    fn default() -> Self {
+        build_settings(None, |arg| Ok(arg.default_value().map(String::from)))
+            .expect("Default parameter values must be valid")
+    }
 }
 
 impl CliArg {
-    fn as_arg(&self) -> Arg {
+    const ALL: [CliArg; 40] = [
+        CliArg::Config,
+        CliArg::Host,
+        CliArg::Port,
+        CliArg::FileDir,
+        CliArg::ImageDir,
+        CliArg::AdminToken,
+        CliArg::BanFile,
+        CliArg::MaxUploadSize,
+        CliArg::RateLimit,
+        CliArg::Motd,
+        CliArg::ImageFormat,
+        CliArg::LogLevel,
+        CliArg::LogFormat,
+        CliArg::LogFile,
+        CliArg::LogMaxSize,
+        CliArg::MetricsAddr,
+        CliArg::AuditLog,
+        CliArg::Ui,
+        CliArg::HistoryFile,
+        CliArg::EditMode,
+        CliArg::Script,
+        CliArg::Exec,
+        CliArg::KeepGoing,
+        CliArg::Output,
+        CliArg::Compression,
+        CliArg::AllowFileTypes,
+        CliArg::DenyFileTypes,
+        CliArg::AllowImageTypes,
+        CliArg::DenyImageTypes,
+        CliArg::CatalogFile,
+        CliArg::UserQuota,
+        CliArg::StorageQuota,
+        CliArg::MinFreeSpace,
+        CliArg::RetentionAge,
+        CliArg::RetentionSize,
+        CliArg::RetentionInterval,
+        CliArg::RetentionArchiveDir,
+        CliArg::RetentionDryRun,
+        CliArg::WsAddr,
+        CliArg::HttpAddr,
+    ];
+
+    /// Long name of the parameter, also used (in its `snake_case` form) as the configuration file key.
+    fn name(&self) -> &'static str {
+        match self {
+            CliArg::Config => "config",
+            CliArg::Host => "host",
+            CliArg::Port => "port",
+            CliArg::FileDir => "file-dir",
+            CliArg::ImageDir => "image-dir",
+            CliArg::AdminToken => "admin-token",
+            CliArg::BanFile => "ban-file",
+            CliArg::MaxUploadSize => "max-upload-size",
+            CliArg::RateLimit => "rate-limit",
+            CliArg::Motd => "motd",
+            CliArg::ImageFormat => "image-format",
+            CliArg::LogLevel => "log-level",
+            CliArg::LogFormat => "log-format",
+            CliArg::LogFile => "log-file",
+            CliArg::LogMaxSize => "log-max-size",
+            CliArg::MetricsAddr => "metrics-addr",
+            CliArg::AuditLog => "audit-log",
+            CliArg::Ui => "ui",
+            CliArg::HistoryFile => "history-file",
+            CliArg::EditMode => "edit-mode",
+            CliArg::Script => "script",
+            CliArg::Exec => "exec",
+            CliArg::KeepGoing => "keep-going",
+            CliArg::Output => "output",
+            CliArg::Compression => "compression",
+            CliArg::AllowFileTypes => "allow-file-types",
+            CliArg::DenyFileTypes => "deny-file-types",
+            CliArg::AllowImageTypes => "allow-image-types",
+            CliArg::DenyImageTypes => "deny-image-types",
+            CliArg::CatalogFile => "catalog-file",
+            CliArg::UserQuota => "user-quota",
+            CliArg::StorageQuota => "storage-quota",
+            CliArg::MinFreeSpace => "min-free-space",
+            CliArg::RetentionAge => "retention-age",
+            CliArg::RetentionSize => "retention-size",
+            CliArg::RetentionInterval => "retention-interval",
+            CliArg::RetentionArchiveDir => "retention-archive-dir",
+            CliArg::RetentionDryRun => "retention-dry-run",
+            CliArg::WsAddr => "ws-addr",
+            CliArg::HttpAddr => "http-addr",
+        }
+    }
+
+    fn key(&self) -> String {
+        self.name().replace('-', "_")
+    }
+
+    fn env_var(&self) -> String {
+        format!("{}{}", ENV_PREFIX, self.key().to_uppercase())
+    }
+
+    fn default_value(&self) -> Option<&'static str> {
+        match self {
+            CliArg::Config
+            | CliArg::AdminToken
+            | CliArg::Motd
+            | CliArg::LogFile
+            | CliArg::MetricsAddr
+            | CliArg::AuditLog
+            | CliArg::HistoryFile
+            | CliArg::Script
+            | CliArg::Exec
+            | CliArg::KeepGoing
+            | CliArg::AllowFileTypes
+            | CliArg::DenyFileTypes
+            | CliArg::DenyImageTypes
+            | CliArg::RetentionAge
+            | CliArg::RetentionArchiveDir
+            | CliArg::RetentionDryRun
+            | CliArg::WsAddr
+            | CliArg::HttpAddr => None,
+            CliArg::Host => Some(HOST_DEFAULT),
+            CliArg::Port => Some(PORT_DEFAULT),
+            CliArg::FileDir => Some(FILE_DIRECTORY_DEFAULT),
+            CliArg::ImageDir => Some(IMAGE_DIRECTORY_DEFAULT),
+            CliArg::BanFile => Some(BAN_FILE_DEFAULT),
+            CliArg::MaxUploadSize => Some(MAX_UPLOAD_SIZE_DEFAULT),
+            CliArg::RateLimit => Some(RATE_LIMIT_DEFAULT),
+            CliArg::ImageFormat => Some(IMAGE_FORMAT_DEFAULT),
+            CliArg::LogLevel => Some(LOG_LEVEL_DEFAULT),
+            CliArg::LogFormat => Some(LOG_FORMAT_DEFAULT),
+            CliArg::LogMaxSize => Some(LOG_MAX_SIZE_DEFAULT),
+            CliArg::Ui => Some(UI_DEFAULT),
+            CliArg::EditMode => Some(EDIT_MODE_DEFAULT),
+            CliArg::Output => Some(OUTPUT_DEFAULT),
+            CliArg::Compression => Some(COMPRESSION_DEFAULT),
+            CliArg::AllowImageTypes => Some(ALLOW_IMAGE_TYPES_DEFAULT),
+            CliArg::CatalogFile => Some(CATALOG_FILE_DEFAULT),
+            CliArg::UserQuota | CliArg::StorageQuota => Some(QUOTA_DEFAULT),
+            CliArg::MinFreeSpace => Some(MIN_FREE_SPACE_DEFAULT),
+            CliArg::RetentionSize => Some(RETENTION_SIZE_DEFAULT),
+            CliArg::RetentionInterval => Some(RETENTION_INTERVAL_DEFAULT),
+        }
+    }
+
+    fn value_kind(&self) -> ValueKind {
         match self {
-            CliArg::Host => Arg::new("host")
-                .short('H')
-                .long("host")
-                .default_value(HOST_DEFAULT)
-                .help("Sets the server host"),
-            CliArg::Port => Arg::new("port")
-                .short('p')
-                .long("port")
-                .default_value(PORT_DEFAULT)
-                .help("Sets the server port"),
-            CliArg::FileDir => Arg::new("file-dir")
-                .short('f')
-                .long("file-dir")
-                .default_value(FILE_DIRECTORY_DEFAULT)
-                .help("Sets the file directory"),
-            CliArg::ImageDir => Arg::new("image-dir")
-                .short('i')
-                .long("image-dir")
-                .default_value(IMAGE_DIRECTORY_DEFAULT)
-                .help("Sets the image directory"),
+            CliArg::KeepGoing | CliArg::RetentionDryRun => ValueKind::Flag,
+            CliArg::Exec => ValueKind::Multiple,
+            _ => ValueKind::Single,
         }
     }
 
-    fn get_value<'a>(&self, matches: &'a ArgMatches) -> Result<String, Box<dyn Error>> {
-        let result: Option<&String> = match self {
-            CliArg::Host => matches.get_one::<String>("host"),
-            CliArg::Port => matches.get_one::<String>("port"),
-            CliArg::FileDir => matches.get_one::<String>("file-dir"),
-            CliArg::ImageDir => matches.get_one::<String>("image-dir"),
+    fn short(&self) -> Option<char> {
+        match self {
+            CliArg::Config => Some('c'),
+            CliArg::Host => Some('H'),
+            CliArg::Port => Some('p'),
+            CliArg::FileDir => Some('f'),
+            CliArg::ImageDir => Some('i'),
+            CliArg::AdminToken => Some('a'),
+            CliArg::BanFile => Some('b'),
+            _ => None,
+        }
+    }
+
+    fn help(&self) -> &'static str {
+        match self {
+            CliArg::Config => "Sets the TOML configuration file",
+            CliArg::Host => "Sets the server host",
+            CliArg::Port => "Sets the server port",
+            CliArg::FileDir => "Sets the file directory",
+            CliArg::ImageDir => "Sets the image directory",
+            CliArg::AdminToken => "Sets the shared admin token (admin commands are disabled if not set)",
+            CliArg::BanFile => "Sets the file used to persist the ban list",
+            CliArg::MaxUploadSize => "Sets the maximum upload size (e.g. 512K, 10M, 1G)",
+            CliArg::RateLimit => "Sets the maximum of messages per minute per connection (0 for unlimited)",
+            CliArg::Motd => "Sets the message of the day",
+            CliArg::ImageFormat => "Sets the format images are converted to (e.g. png, jpeg, webp)",
+            CliArg::LogLevel => "Sets the log level (error, warn, info, debug)",
+            CliArg::LogFormat => "Sets the log format (text, json)",
+            CliArg::LogFile => "Sets the log file (logs to the console if not set)",
+            CliArg::LogMaxSize => "Sets the size the log file is rotated at (e.g. 512K, 10M)",
+            CliArg::MetricsAddr => "Sets the address to serve Prometheus metrics on (disabled if not set)",
+            CliArg::AuditLog => "Sets the audit log file (disabled if not set)",
+            CliArg::Ui => "Sets the user interface (line, tui)",
+            CliArg::HistoryFile => "Sets the input history file (defaults to .chatee_history in the home directory)",
+            CliArg::EditMode => "Sets the key bindings of the input line (emacs, vi)",
+            CliArg::Script => "Runs the commands from the file (one per line, '-' for stdin) instead of the interactive input",
+            CliArg::Exec => "Runs the command instead of the interactive input (can be repeated, runs before --script)",
+            CliArg::KeepGoing => "Continues running the commands after a failure",
+            CliArg::Output => "Sets the format of the command results (text, json)",
+            CliArg::Compression => "Sets the compression of the transferred files (deflate, none)",
+            CliArg::AllowFileTypes => "Sets the content types allowed for files, comma-separated (all if not set)",
+            CliArg::DenyFileTypes => "Sets the content types refused for files, comma-separated (e.g. elf,exe)",
+            CliArg::AllowImageTypes => "Sets the content types allowed for images, comma-separated",
+            CliArg::DenyImageTypes => "Sets the content types refused for images, comma-separated",
+            CliArg::CatalogFile => "Sets the file used to persist the catalog of the uploads",
+            CliArg::UserQuota => "Sets the storage quota per user (e.g. 100M, 0 for unlimited)",
+            CliArg::StorageQuota => "Sets the quota of the whole storage, files and images together (e.g. 10G, 0 for unlimited)",
+            CliArg::MinFreeSpace => "Sets the free space to keep on the storage filesystem, uploads are refused below it (e.g. 1G, 0 to disable)",
+            CliArg::RetentionAge => "Sets the age the stored uploads are removed at (e.g. 30d, kept forever if not set)",
+            CliArg::RetentionSize => "Sets the storage size the oldest uploads are removed beyond (e.g. 10G, 0 for unlimited)",
+            CliArg::RetentionInterval => "Sets the interval of applying the retention policy (e.g. 15m, 1h)",
+            CliArg::RetentionArchiveDir => "Sets the directory the removed uploads are moved to (deleted if not set)",
+            CliArg::RetentionDryRun => "Only logs the uploads the retention policy would remove",
+            CliArg::WsAddr => "Sets the address to accept WebSocket connections on (disabled if not set)",
+            CliArg::HttpAddr => "Sets the address to serve the HTTP API of the storage on (disabled if not set)",
+        }
+    }
+
+    fn as_arg(&self) -> Arg {
+        // defaults are not handed over to clap, as they have the lowest precedence of all sources
+        let default = self
+            .default_value()
+            .map_or(String::new(), |default| format!(" [default: {}]", default));
+        let action = match self.value_kind() {
+            ValueKind::Single => ArgAction::Set,
+            ValueKind::Flag => ArgAction::SetTrue,
+            ValueKind::Multiple => ArgAction::Append,
         };
-        result
-            .map(|s| Ok(s.clone()))
-            .unwrap_or_else(|| Err("Parameter not found".into()))
+        Arg::new(self.name())
+            .short(self.short())
+            .long(self.name())
+            .action(action)
+            .help(format!(
+                "{} [env: {}]{}",
+                self.help(),
+                self.env_var(),
+                default
+            ))
+    }
+
+    fn get_value(&self, matches: &ArgMatches) -> Option<String> {
+        match self.value_kind() {
+            ValueKind::Single => matches.get_one::<String>(self.name()).cloned(),
+            ValueKind::Flag => matches.get_flag(self.name()).then(|| "true".to_string()),
+            ValueKind::Multiple => matches
+                .get_many::<String>(self.name())
+                .map(|values| values.cloned().collect::<Vec<_>>().join("\n")),
+        }
+    }
+}
+
+/// Configuration file contents, i.e. the third level of the settings precedence.
+struct ConfigFile {
+    path: String,
+    table: toml::Table,
+}
+
+impl ConfigFile {
+    fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read configuration file {}: {}", path, e))?;
+        let table = content
+            .parse::<toml::Table>()
+            .map_err(|e| format!("Failed to parse configuration file {}: {}", path, e))?;
+        let known = CliArg::ALL.iter().map(|arg| arg.key()).collect::<Vec<_>>();
+        if let Some(key) = table.keys().find(|key| !known.contains(key)) {
+            return Err(format!("Unknown key '{}' in configuration file {}", key, path).into());
+        }
+        Ok(ConfigFile {
+            path: path.to_string(),
+            table,
+        })
+    }
+
+    fn get_value(&self, arg: &CliArg) -> Result<Option<String>, Box<dyn Error>> {
+        match self.table.get(&arg.key()) {
+            None => Ok(None),
+            Some(toml::Value::String(value)) => Ok(Some(value.clone())),
+            Some(toml::Value::Integer(value)) => Ok(Some(value.to_string())),
+            Some(toml::Value::Boolean(value)) => Ok(Some(value.to_string())),
+            Some(toml::Value::Array(values)) if matches!(arg.value_kind(), ValueKind::Multiple) => {
+                let values = values
+                    .iter()
+                    .map(|value| value.as_str().map(String::from))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| {
+                        format!(
+                            "Invalid value for '{}' in configuration file {}, strings expected",
+                            arg.key(),
+                            self.path
+                        )
+                    })?;
+                Ok(Some(values.join("\n")))
+            }
+            Some(value) => Err(format!(
+                "Invalid value {} for '{}' in configuration file {}",
+                value,
+                arg.key(),
+                self.path
+            )
+            .into()),
+        }
+    }
+}
+
+fn resolve(
+    arg: &CliArg,
+    matches: &ArgMatches,
+    file: Option<&ConfigFile>,
+) -> Result<Option<String>, Box<dyn Error>> {
+    if let Some(value) = arg.get_value(matches) {
+        return Ok(Some(value));
+    }
+    if let Ok(value) = std::env::var(arg.env_var()) {
+        return Ok(Some(value));
+    }
+    if let Some(value) = file.map(|file| file.get_value(arg)).transpose()?.flatten() {
+        return Ok(Some(value));
+    }
+    Ok(arg.default_value().map(String::from))
+}
+
+/// Parses the given parameters for the application and resolves them into `Settings`.
+pub fn parse_args(app: &'static str, args: &[CliArg]) -> Result<Settings, Box<dyn Error>> {
+    let matches = command(app, args).get_matches();
+    resolve_settings(args, &matches)
+}
+
+fn command(app: &'static str, args: &[CliArg]) -> Command {
+    args.iter()
+        .fold(Command::new(app), |command, arg| command.arg(arg.as_arg()))
+}
+
+/// Resolves the settings from the parsed command line, the environment and the configuration file.
+fn resolve_settings(args: &[CliArg], matches: &ArgMatches) -> Result<Settings, Box<dyn Error>> {
+    let config = if args.iter().any(|arg| matches!(arg, CliArg::Config)) {
+        resolve(&CliArg::Config, matches, None)?
+    } else {
+        None
+    };
+    let file = config.as_deref().map(ConfigFile::load).transpose()?;
+
+    build_settings(config, |arg| {
+        if args.iter().any(|a| a.name() == arg.name()) {
+            resolve(&arg, matches, file.as_ref())
+        } else {
+            Ok(arg.default_value().map(String::from))
+        }
+    })
+}
+
+fn build_settings(
+    config: Option<String>,
+    value: impl Fn(CliArg) -> Result<Option<String>, Box<dyn Error>>,
+) -> Result<Settings, Box<dyn Error>> {
+    let required = |arg: CliArg| -> Result<String, Box<dyn Error>> {
+        value(arg)?.ok_or_else(|| format!("Parameter '{}' not found", arg.name()).into())
+    };
+
+    let port = required(CliArg::Port)?;
+    let rate_limit = required(CliArg::RateLimit)?;
+    Ok(Settings {
+        host: required(CliArg::Host)?,
+        port: port
+            .parse()
+            .map_err(|_| format!("Invalid port '{}'", port))?,
+        file_dir: required(CliArg::FileDir)?,
+        image_dir: required(CliArg::ImageDir)?,
+        admin_token: value(CliArg::AdminToken)?.filter(|token| !token.is_empty()),
+        ban_file: required(CliArg::BanFile)?,
+        max_upload_size: parse_size(&required(CliArg::MaxUploadSize)?)?,
+        rate_limit: rate_limit
+            .parse()
+            .map_err(|_| format!("Invalid rate limit '{}'", rate_limit))?,
+        motd: value(CliArg::Motd)?.filter(|motd| !motd.is_empty()),
+        image_format: required(CliArg::ImageFormat)?,
+        log: LogSettings {
+            level: required(CliArg::LogLevel)?.parse()?,
+            format: required(CliArg::LogFormat)?.parse()?,
+            file: value(CliArg::LogFile)?.filter(|file| !file.is_empty()),
+            max_size: parse_size(&required(CliArg::LogMaxSize)?)?,
+        },
+        metrics_addr: value(CliArg::MetricsAddr)?.filter(|addr| !addr.is_empty()),
+        audit_log: value(CliArg::AuditLog)?.filter(|file| !file.is_empty()),
+        ui: required(CliArg::Ui)?.parse()?,
+        history_file: value(CliArg::HistoryFile)?.filter(|file| !file.is_empty()),
+        edit_mode: required(CliArg::EditMode)?.parse()?,
+        script: value(CliArg::Script)?.filter(|file| !file.is_empty()),
+        exec: value(CliArg::Exec)?
+            .map(|commands| commands.lines().map(String::from).collect())
+            .unwrap_or_default(),
+        keep_going: value(CliArg::KeepGoing)?
+            .map_or(Ok(false), |flag| parse_flag(CliArg::KeepGoing, &flag))?,
+        output: required(CliArg::Output)?.parse()?,
+        compression: match required(CliArg::Compression)?.as_str() {
+            "none" => None,
+            compression => Some(compression.parse()?),
+        },
+        allow_file_types: parse_types(value(CliArg::AllowFileTypes)?)?,
+        deny_file_types: parse_types(value(CliArg::DenyFileTypes)?)?,
+        allow_image_types: parse_types(value(CliArg::AllowImageTypes)?)?,
+        deny_image_types: parse_types(value(CliArg::DenyImageTypes)?)?,
+        catalog_file: required(CliArg::CatalogFile)?,
+        user_quota: parse_size(&required(CliArg::UserQuota)?)?,
+        storage_quota: parse_size(&required(CliArg::StorageQuota)?)?,
+        min_free_space: parse_size(&required(CliArg::MinFreeSpace)?)?,
+        retention_age: value(CliArg::RetentionAge)?
+            .filter(|age| !age.is_empty())
+            .map(|age| parse_duration(&age))
+            .transpose()?,
+        retention_size: parse_size(&required(CliArg::RetentionSize)?)?,
+        retention_interval: parse_duration(&required(CliArg::RetentionInterval)?)?,
+        retention_archive_dir: value(CliArg::RetentionArchiveDir)?.filter(|dir| !dir.is_empty()),
+        retention_dry_run: value(CliArg::RetentionDryRun)?
+            .map_or(Ok(false), |flag| parse_flag(CliArg::RetentionDryRun, &flag))?,
+        ws_addr: value(CliArg::WsAddr)?.filter(|addr| !addr.is_empty()),
+        http_addr: value(CliArg::HttpAddr)?.filter(|addr| !addr.is_empty()),
+        config,
+    })
+}
+
+/// Parses a comma-separated list of content type names.
+fn parse_types(value: Option<String>) -> Result<Vec<String>, Box<dyn Error>> {
+    let known = content_type::names();
+    value
+        .unwrap_or_default()
+        .split(',')
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .map(|name| match known.contains(&name.as_str()) {
+            true => Ok(name),
+            false => Err(format!(
+                "Unknown content type '{}', valid are: {}",
+                name,
+                known.join(", ")
+            )
+            .into()),
+        })
+        .collect()
+}
+
+fn parse_flag(arg: CliArg, value: &str) -> Result<bool, Box<dyn Error>> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "1" => Ok(true),
+        "false" | "no" | "0" | "" => Ok(false),
+        _ => Err(format!(
+            "Invalid value '{}' for '{}', expected true or false",
+            value,
+            arg.name()
+        )
+        .into()),
     }
 }
 
-pub fn parse_args(app: &'static str, args: &[CliArg]) -> Result<Vec<String>, Box<dyn Error>> {
-    let mut command = Command::new(app);
-    for arg in args {
-        command = command.arg(arg.as_arg());
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_line_wins_over_environment_over_file_over_default() {
+        let path = std::env::temp_dir().join(format!("cli-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "host = \"file.example\"\nport = 3333\nrate_limit = 3\nmotd = \"from file\"\n",
+        )
+        .unwrap();
+        // the variables are not used by any other test
+        std::env::set_var("CHATEE_RATE_LIMIT", "2");
+        std::env::set_var("CHATEE_MOTD", "from env");
+        let args = [
+            CliArg::Config,
+            CliArg::Host,
+            CliArg::Port,
+            CliArg::RateLimit,
+            CliArg::Motd,
+            CliArg::ImageFormat,
+        ];
+        let matches = command("test", &args)
+            .try_get_matches_from([
+                "test",
+                "--config",
+                path.to_str().unwrap(),
+                "--motd",
+                "from cli",
+            ])
+            .unwrap();
+        let settings = resolve_settings(&args, &matches);
+        std::env::remove_var("CHATEE_RATE_LIMIT");
+        std::env::remove_var("CHATEE_MOTD");
+        let _ = fs::remove_file(&path);
+
+        let settings = settings.unwrap();
+        assert_eq!(settings.motd.as_deref(), Some("from cli"));
+        assert_eq!(settings.rate_limit, 2);
+        assert_eq!(settings.host, "file.example");
+        assert_eq!(settings.port, 3333);
+        assert_eq!(settings.image_format, IMAGE_FORMAT_DEFAULT);
     }
-    let matches = command.get_matches();
 
-    let mut values = Vec::new();
-    for arg in args {
-        values.push(arg.get_value(&matches)?);
+    #[test]
+    fn arguments_not_taken_by_the_application_are_defaults() {
+        let matches = command("test", &[CliArg::Host])
+            .try_get_matches_from(["test", "--host", "example"])
+            .unwrap();
+        let settings = resolve_settings(&[CliArg::Host], &matches).unwrap();
+        assert_eq!(settings.host, "example");
+        assert_eq!(settings.port.to_string(), PORT_DEFAULT);
+        assert_eq!(settings.config, None);
     }
-    Ok(values)
 }
@@ -0,0 +1,421 @@
+//! Extensible command registry shared by client and server.
+//!
+//! A command is anything implementing the `Command` trait: it describes itself using a `CommandSpec`
+//! (name, description, argument schema) and handles the already validated arguments within a context
+//! (the client connection or the server session). Commands are collected in a `Registry`, which
+//! generates the help, validates the arguments and offers completion out of the same definitions.
+//!
+//! Simple commands don't need their own type, a `FnCommand` wraps a spec along with a plain function.
+//!
+//! Arguments are split in a shell-like way: words are separated by whitespace, a word containing whitespace
+//! can be enclosed in single quotes (taken literally) or double quotes (where `\"` and `\\` are escapes),
+//! or the whitespace can be escaped by a backslash. Backslashes not forming an escape are kept as they are,
+//! so that e.g. Windows paths don't need any escaping.
+
+use crate::error::{ChatError, ChatResult, ErrorCode};
+use crate::util::{parse_duration, parse_size};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArgKind {
+    /// A single word.
+    Word,
+    /// A non-negative integer, optionally with a size suffix (K, M, G).
+    Number,
+    /// A duration with a unit suffix (s, m, h, d).
+    Duration,
+    /// A (local or remote) file path.
+    Path,
+    /// Free text, taking the rest of the line.
+    Text,
+}
+
+impl ArgKind {
+    /// Whether the argument takes the rest of the line (hence it must be the last one).
+    fn is_rest(&self) -> bool {
+        *self == ArgKind::Text
+    }
+
+    fn validate(&self, value: &str) -> Result<(), String> {
+        match self {
+            ArgKind::Number => parse_size(value).map(|_| ()).map_err(|e| e.to_string()),
+            ArgKind::Duration => parse_duration(value).map(|_| ()).map_err(|e| e.to_string()),
+            ArgKind::Word if value.contains(char::is_whitespace) => {
+                Err("must be a single word".to_string())
+            }
+            ArgKind::Word | ArgKind::Path if value.is_empty() => {
+                Err("must not be empty".to_string())
+            }
+            ArgKind::Word | ArgKind::Path | ArgKind::Text => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Arg {
+    pub name: &'static str,
+    pub kind: ArgKind,
+    pub required: bool,
+}
+
+impl Arg {
+    pub fn required(name: &'static str, kind: ArgKind) -> Self {
+        Arg {
+            name,
+            kind,
+            required: true,
+        }
+    }
+
+    pub fn optional(name: &'static str, kind: ArgKind) -> Self {
+        Arg {
+            name,
+            kind,
+            required: false,
+        }
+    }
+}
+
+/// Declarative description of a command.
+#[derive(Clone, Debug)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub args: Vec<Arg>,
+    /// Available to admin sessions only.
+    pub admin: bool,
+}
+
+impl CommandSpec {
+    pub fn new(name: &'static str, description: &'static str) -> Self {
+        CommandSpec {
+            name,
+            description,
+            args: Vec::new(),
+            admin: false,
+        }
+    }
+
+    pub fn arg(mut self, arg: Arg) -> Self {
+        self.args.push(arg);
+        self
+    }
+
+    pub fn admin(mut self) -> Self {
+        self.admin = true;
+        self
+    }
+
+    /// Usage line, e.g. `.ban <target> [duration]`.
+    pub fn usage(&self) -> String {
+        self.args.iter().fold(self.name.to_string(), |usage, arg| {
+            if arg.required {
+                format!("{} <{}>", usage, arg.name)
+            } else {
+                format!("{} [{}]", usage, arg.name)
+            }
+        })
+    }
+
+    /// Splits the input (the line without the command name) according to the argument schema.
+    pub fn parse(&self, input: &str) -> ChatResult<Args> {
+        let invalid = |message: String| {
+            ChatError::new(
+                ErrorCode::InvalidArgument,
+                format!("{}, usage: {}", message, self.usage()),
+            )
+        };
+        let mut rest = input;
+        let mut values = Vec::new();
+        for arg in &self.args {
+            let value = if arg.kind.is_rest() {
+                let text = std::mem::take(&mut rest).trim();
+                (!text.is_empty()).then(|| text.to_string())
+            } else {
+                next_token(rest)
+                    .map_err(|e| invalid(format!("Invalid <{}> parameter: {}", arg.name, e)))?
+                    .map(|(value, remaining)| {
+                        rest = remaining;
+                        value
+                    })
+            };
+            let Some(value) = value else {
+                if arg.required {
+                    return Err(invalid(format!(
+                        "Command '{}' requires a <{}> parameter",
+                        self.name, arg.name
+                    )));
+                }
+                break;
+            };
+            arg.kind.validate(&value).map_err(|e| {
+                invalid(format!(
+                    "Invalid <{}> parameter '{}': {}",
+                    arg.name, value, e
+                ))
+            })?;
+            values.push((arg.name, value));
+        }
+        if !rest.trim().is_empty() {
+            return Err(invalid(format!(
+                "Command '{}' got unexpected parameters '{}' (values with spaces must be quoted)",
+                self.name,
+                rest.trim()
+            )));
+        }
+        Ok(Args { values })
+    }
+}
+
+/// Splits the next word off the input, returning it along with the remaining input.
+pub fn next_token(input: &str) -> Result<Option<(String, &str)>, String> {
+    let input = input.trim_start();
+    if input.is_empty() {
+        return Ok(None);
+    }
+    let mut token = String::new();
+    let mut quote = None;
+    let mut chars = input.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            (None, c) if c.is_whitespace() => return Ok(Some((token, &input[i..]))),
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => token.push(c),
+            (_, '\\') => match chars.peek() {
+                Some(&(_, next)) if is_escapable(next, quote) => {
+                    token.push(next);
+                    chars.next();
+                }
+                _ => token.push(c),
+            },
+            (_, c) => token.push(c),
+        }
+    }
+    match quote {
+        Some(q) => Err(format!("unterminated {} quote", q)),
+        None => Ok(Some((token, ""))),
+    }
+}
+
+fn is_escapable(c: char, quote: Option<char>) -> bool {
+    match quote {
+        None => c.is_whitespace() || matches!(c, '"' | '\'' | '\\'),
+        Some(_) => matches!(c, '"' | '\\'),
+    }
+}
+
+/// Quotes the value (if needed), so that `next_token` reads it back as a single word.
+pub fn quote(value: &str) -> String {
+    if !value.is_empty() && !value.contains(|c: char| c.is_whitespace() || "\"'\\".contains(c)) {
+        return value.to_string();
+    }
+    let escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+    format!("\"{}\"", escaped)
+}
+
+/// Arguments of a command, already validated against its `CommandSpec`.
+#[derive(Clone, Debug, Default)]
+pub struct Args {
+    values: Vec<(&'static str, String)>,
+}
+
+impl Args {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Value of a required argument (or empty if the argument is optional and missing).
+    pub fn value(&self, name: &str) -> &str {
+        self.get(name).unwrap_or("")
+    }
+
+    pub fn number(&self, name: &str) -> Option<u64> {
+        self.get(name).and_then(|value| parse_size(value).ok())
+    }
+
+    pub fn duration(&self, name: &str) -> Option<Duration> {
+        self.get(name).and_then(|value| parse_duration(value).ok())
+    }
+}
+
+/// A command handled within the context `C` (e.g. the server session).
+pub trait Command<C>: Send + Sync {
+    fn spec(&self) -> &CommandSpec;
+
+    fn execute(&self, context: &mut C, args: &Args) -> ChatResult<String>;
+}
+
+pub type CommandFn<C> = fn(&mut C, &Args) -> ChatResult<String>;
+
+/// A command defined by its spec and a plain handler function.
+pub struct FnCommand<C> {
+    spec: CommandSpec,
+    func: CommandFn<C>,
+}
+
+impl<C> FnCommand<C> {
+    pub fn new(spec: CommandSpec, func: CommandFn<C>) -> Self {
+        FnCommand { spec, func }
+    }
+}
+
+impl<C> Command<C> for FnCommand<C> {
+    fn spec(&self) -> &CommandSpec {
+        &self.spec
+    }
+
+    fn execute(&self, context: &mut C, args: &Args) -> ChatResult<String> {
+        (self.func)(context, args)
+    }
+}
+
+pub struct Registry<C> {
+    commands: BTreeMap<&'static str, Box<dyn Command<C>>>,
+}
+
+impl<C> Default for Registry<C> {
+    fn default() -> Self {
+        Registry {
+            commands: BTreeMap::new(),
+        }
+    }
+}
+
+impl<C> Registry<C> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the command, replacing any command of the same name.
+    pub fn register(&mut self, command: impl Command<C> + 'static) -> &mut Self {
+        self.commands.insert(command.spec().name, Box::new(command));
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Command<C>> {
+        self.commands.get(name).map(|command| command.as_ref())
+    }
+
+    /// All the command specs, sorted by name.
+    pub fn specs(&self) -> impl Iterator<Item = &CommandSpec> {
+        self.commands.values().map(|command| command.spec())
+    }
+
+    /// Help lines (usage and description) of the commands passing the filter.
+    pub fn help(&self, filter: impl Fn(&CommandSpec) -> bool) -> Vec<String> {
+        let specs = self.specs().filter(|spec| filter(spec)).collect::<Vec<_>>();
+        let width = specs
+            .iter()
+            .map(|spec| spec.usage().len())
+            .max()
+            .unwrap_or(0);
+        specs
+            .iter()
+            .map(|spec| {
+                format!(
+                    "{:<width$}  {}",
+                    spec.usage(),
+                    spec.description,
+                    width = width
+                )
+            })
+            .collect()
+    }
+
+    /// Names of the commands completing the (partially typed) command name.
+    pub fn complete(&self, prefix: &str) -> Vec<&'static str> {
+        self.commands
+            .keys()
+            .filter(|name| name.starts_with(prefix))
+            .copied()
+            .collect()
+    }
+
+    /// The argument being typed at the end of the (partial) input line, if any.
+    pub fn arg_at(&self, line: &str) -> Option<&Arg> {
+        let (name, input) = line.split_once(' ')?;
+        let args = &self.get(name)?.spec().args;
+        let mut rest = input;
+        for arg in args {
+            match next_token(rest) {
+                // the token is complete once followed by whitespace
+                Ok(Some((_, remaining))) if !arg.kind.is_rest() && !remaining.is_empty() => {
+                    rest = remaining;
+                }
+                _ => return Some(arg),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(input: &str) -> Option<(String, &str)> {
+        next_token(input).unwrap()
+    }
+
+    #[test]
+    fn next_token_splits_words() {
+        assert_eq!(token("  one two"), Some(("one".to_string(), " two")));
+        assert_eq!(token("last"), Some(("last".to_string(), "")));
+        assert_eq!(token(""), None);
+        assert_eq!(token("   "), None);
+    }
+
+    #[test]
+    fn next_token_handles_quotes() {
+        assert_eq!(
+            token("\"two words\" rest"),
+            Some(("two words".to_string(), " rest"))
+        );
+        assert_eq!(token("'it\"s'"), Some(("it\"s".to_string(), "")));
+        assert_eq!(
+            token("pre\"fix suf\"fix"),
+            Some(("prefix suffix".to_string(), ""))
+        );
+        assert_eq!(token("\"\""), Some((String::new(), "")));
+        assert!(next_token("\"unterminated").is_err());
+        assert!(next_token("'unterminated").is_err());
+    }
+
+    #[test]
+    fn next_token_handles_escapes() {
+        assert_eq!(token("two\\ words"), Some(("two words".to_string(), "")));
+        assert_eq!(token("\"a\\\"b\""), Some(("a\"b".to_string(), "")));
+        assert_eq!(token("\"a\\\\b\""), Some(("a\\b".to_string(), "")));
+        // nothing is escaped within single quotes, other backslashes are kept
+        assert_eq!(token("'a\\ b'"), Some(("a\\ b".to_string(), "")));
+        assert_eq!(token("c:\\dir"), Some(("c:\\dir".to_string(), "")));
+    }
+
+    #[test]
+    fn quote_round_trips() {
+        assert_eq!(quote("plain"), "plain");
+        assert_eq!(quote("two words"), "\"two words\"");
+        for value in [
+            "plain",
+            "",
+            "two words",
+            "it's",
+            "a\"b",
+            "back\\slash",
+            "tab\there",
+            "\\\"",
+        ] {
+            assert_eq!(
+                token(&quote(value)),
+                Some((value.to_string(), "")),
+                "{}",
+                value
+            );
+        }
+    }
+}
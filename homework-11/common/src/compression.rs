@@ -0,0 +1,100 @@
+//! Compression of the transferred content, negotiated per connection.
+//!
+//! The client offers the algorithms it supports by `.compress <algorithm>...`, the server answers
+//! with the one chosen (`Compression: deflate`) or `Compression: none`. Afterwards an upload may be sent
+//! compressed as `.file <size> <name> <algorithm> <original size>`, the `<size>` being the number
+//! of the bytes that follow (and the server sends the downloads the same way, see `download`).
+//! Content which is compressed already (archives, most media formats) is sent as is, as compressing
+//! it again only costs time.
+
+use crate::content_type;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use std::fmt;
+use std::io::{self, Read};
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// Raw deflate stream (RFC 1951).
+    Deflate,
+}
+
+impl Compression {
+    pub const ALL: [Compression; 1] = [Compression::Deflate];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Compression::Deflate => "deflate",
+        }
+    }
+
+    pub fn compress(&self, content: &[u8]) -> io::Result<Vec<u8>> {
+        self.compress_from(&mut &content[..])
+    }
+
+    /// Compresses the content read to its end (e.g. from a file, without holding the original in memory).
+    pub fn compress_from(&self, reader: &mut impl Read) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                io::copy(reader, &mut encoder)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    /// Compresses the content if worth it, i.e. unless it is compressed already or does not get smaller.
+    pub fn compress_if_smaller(&self, content: &[u8]) -> Option<Vec<u8>> {
+        if is_compressed(content) {
+            return None;
+        }
+        let compressed = self.compress(content).ok()?;
+        (compressed.len() < content.len()).then_some(compressed)
+    }
+
+    /// Decompresses the content, which has to expand to the given (original) size exactly.
+    pub fn decompress(&self, content: &[u8], size: usize) -> io::Result<Vec<u8>> {
+        let mut decompressed = Vec::with_capacity(size);
+        // never more than announced, whatever the content expands to
+        self.decoder(content)
+            .take(size as u64 + 1)
+            .read_to_end(&mut decompressed)?;
+        if decompressed.len() != size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("not of the announced {} bytes", size),
+            ));
+        }
+        Ok(decompressed)
+    }
+
+    /// Wraps the reader of the compressed content into a reader of the original content.
+    pub fn decoder<'a>(&self, reader: impl Read + 'a) -> Box<dyn Read + 'a> {
+        match self {
+            Compression::Deflate => Box::new(DeflateDecoder::new(reader)),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Compression::ALL
+            .into_iter()
+            .find(|compression| compression.as_str() == s.to_lowercase())
+            .ok_or(format!("Unknown compression '{}'", s))
+    }
+}
+
+/// Tells whether the content is in a compressed format already (judging by its magic bytes).
+pub fn is_compressed(content: &[u8]) -> bool {
+    content_type::detect(content).compressed
+}
@@ -0,0 +1,160 @@
+//! Detection of the content type by the magic bytes at its start ("sniffing").
+//!
+//! The types are named by short lowercase names (`png`, `pdf`, `zip`, `elf`, ...), as used e.g.
+//! in the allow and deny lists of the server. Content matching no signature is `text` if it looks
+//! like UTF-8 text, `binary` otherwise.
+
+/// Number of the leading bytes needed to detect any of the types.
+pub const SNIFF_LENGTH: usize = 512;
+
+/// A detected content type.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ContentType {
+    pub name: &'static str,
+    /// Whether the format is compressed already (archives, most media formats).
+    pub compressed: bool,
+}
+
+/// Signatures (magic bytes at the given offset) of the known types, with the compression flag.
+///
+/// The first matching signature wins, hence the long ones at fixed offsets precede the short ones
+/// at the start (a tar archive starts with the name of its first member, which may be e.g. `BM...`).
+#[rustfmt::skip]
+const SIGNATURES: [(usize, &[u8], &str, bool); 26] = [
+    (257, b"ustar", "tar", false),
+    (0, b"\x89PNG\r\n\x1a\n", "png", true),
+    (0, b"\xff\xd8\xff", "jpeg", true),
+    (0, b"GIF8", "gif", true),
+    (8, b"WEBP", "webp", true),
+    (0, b"II*\x00", "tiff", false),
+    (0, b"MM\x00*", "tiff", false),
+    (0, b"\x00\x00\x01\x00", "ico", false),
+    (0, b"BM", "bmp", false),
+    (0, b"%PDF-", "pdf", false),
+    (0, b"PK\x03\x04", "zip", true),        // also docx, xlsx, jar, ...
+    (0, b"\x1f\x8b", "gzip", true),
+    (0, b"\x28\xb5\x2f\xfd", "zstd", true),
+    (0, b"\xfd7zXZ\x00", "xz", true),
+    (0, b"BZh", "bzip2", true),
+    (0, b"7z\xbc\xaf\x27\x1c", "7z", true),
+    (0, b"Rar!\x1a\x07", "rar", true),
+    (4, b"ftyp", "mp4", true),              // also mov, heic
+    (8, b"WAVE", "wav", false),
+    (0, b"OggS", "ogg", true),
+    (0, b"ID3", "mp3", true),
+    (0, b"\x7fELF", "elf", false),
+    (0, b"MZ", "exe", false),
+    (0, b"\xca\xfe\xba\xbe", "macho", false),
+    (0, b"\x00asm", "wasm", false),
+    (0, b"#!", "script", false),
+];
+
+impl ContentType {
+    /// The media type (e.g. for HTTP), `application/octet-stream` if there is no specific one.
+    pub fn mime(&self) -> &'static str {
+        match self.name {
+            "png" => "image/png",
+            "jpeg" => "image/jpeg",
+            "gif" => "image/gif",
+            "webp" => "image/webp",
+            "tiff" => "image/tiff",
+            "bmp" => "image/bmp",
+            "ico" => "image/x-icon",
+            "pdf" => "application/pdf",
+            "zip" => "application/zip",
+            "gzip" => "application/gzip",
+            "zstd" => "application/zstd",
+            "xz" => "application/x-xz",
+            "bzip2" => "application/x-bzip2",
+            "7z" => "application/x-7z-compressed",
+            "rar" => "application/vnd.rar",
+            "tar" => "application/x-tar",
+            "mp4" => "video/mp4",
+            "wav" => "audio/wav",
+            "ogg" => "audio/ogg",
+            "mp3" => "audio/mpeg",
+            "wasm" => "application/wasm",
+            "text" | "script" => "text/plain; charset=utf-8",
+            _ => "application/octet-stream",
+        }
+    }
+}
+
+/// Detects the type from the leading bytes (see `SNIFF_LENGTH`) of the content.
+pub fn detect(head: &[u8]) -> ContentType {
+    let head = &head[..head.len().min(SNIFF_LENGTH)];
+    let signature = SIGNATURES.iter().find(|(offset, signature, _, _)| {
+        head.get(*offset..*offset + signature.len()) == Some(*signature)
+    });
+    match signature {
+        Some((_, _, name, compressed)) => ContentType {
+            name,
+            compressed: *compressed,
+        },
+        None if is_text(head) => ContentType {
+            name: "text",
+            compressed: false,
+        },
+        None => ContentType {
+            name: "binary",
+            compressed: false,
+        },
+    }
+}
+
+/// All the type names which can be detected.
+pub fn names() -> Vec<&'static str> {
+    let mut names = SIGNATURES
+        .iter()
+        .map(|(_, _, name, _)| *name)
+        .chain(["text", "binary"])
+        .collect::<Vec<_>>();
+    names.dedup();
+    names
+}
+
+fn is_text(head: &[u8]) -> bool {
+    if head.contains(&0) {
+        return false;
+    }
+    match std::str::from_utf8(head) {
+        Ok(_) => true,
+        // the head may end in the middle of a character
+        Err(e) => e.error_len().is_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Header of a tar archive with a single member of the given name.
+    fn tar_header(name: &str) -> Vec<u8> {
+        let mut header = vec![0; SNIFF_LENGTH];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[257..263].copy_from_slice(b"ustar\0");
+        header
+    }
+
+    #[test]
+    fn detects_by_signature() {
+        assert_eq!(detect(b"\x89PNG\r\n\x1a\n....").name, "png");
+        assert_eq!(detect(b"BM\x36\x00\x00\x00").name, "bmp");
+        assert_eq!(detect(b"MZ\x90\x00").name, "exe");
+        assert_eq!(detect(b"#!/bin/sh\n").name, "script");
+        assert!(detect(b"PK\x03\x04").compressed);
+    }
+
+    #[test]
+    fn tar_is_detected_whatever_its_first_member() {
+        for name in ["BMP/notes.txt", "MZ.exe", "#!script", "plain.txt"] {
+            assert_eq!(detect(&tar_header(name)).name, "tar", "{}", name);
+        }
+    }
+
+    #[test]
+    fn unknown_content_is_text_or_binary() {
+        assert_eq!(detect(b"Hello world").name, "text");
+        assert_eq!(detect(b"\x00\x01\x02\x03").name, "binary");
+    }
+}
@@ -0,0 +1,59 @@
+//! Header of a download, i.e. of the response to `.get`.
+//!
+//! The response is a single line `DOWNLOAD: <size> <name> [<compression> <original size>]` (terminated
+//! by an empty line, like any response) followed by `<size>` bytes of the content. The content is sent
+//! compressed only if the compression was negotiated (see `compression`) and it is worth it,
+//! the same way as an upload.
+
+use crate::command::{next_token, quote};
+use crate::compression::Compression;
+
+const WIRE_PREFIX: &str = "DOWNLOAD:";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Download {
+    pub name: String,
+    /// Number of the bytes following the response.
+    pub wire_size: usize,
+    pub compression: Option<Compression>,
+    /// Size of the original (decompressed) content.
+    pub size: usize,
+}
+
+impl Download {
+    /// Encodes the header into a single response line (without the line terminator).
+    pub fn to_wire(&self) -> String {
+        match self.compression {
+            Some(compression) => format!(
+                "{} {} {} {} {}",
+                WIRE_PREFIX,
+                self.wire_size,
+                quote(&self.name),
+                compression,
+                self.size
+            ),
+            None => format!("{} {} {}", WIRE_PREFIX, self.wire_size, quote(&self.name)),
+        }
+    }
+
+    /// Decodes the header, `None` if the response is not a (valid) download.
+    pub fn from_wire(response: &str) -> Option<Self> {
+        let header = response.strip_prefix(WIRE_PREFIX)?.trim();
+        let (wire_size, rest) = header.split_once(' ')?;
+        let wire_size = wire_size.parse().ok()?;
+        let (name, rest) = next_token(rest).ok().flatten()?;
+        let mut rest = rest.split_whitespace();
+        let (compression, size) = match (rest.next(), rest.next()) {
+            (Some(compression), Some(size)) => {
+                (Some(compression.parse().ok()?), size.parse().ok()?)
+            }
+            _ => (None, wire_size),
+        };
+        Some(Download {
+            name,
+            wire_size,
+            compression,
+            size,
+        })
+    }
+}
@@ -0,0 +1,173 @@
+//! Error type shared by client and server, with stable machine-readable codes.
+//!
+//! Errors are transmitted from the server to the client as a single response line
+//! in the form `ERROR: <CODE>: <message>`, e.g. `ERROR: TOO_LARGE: File a.bin exceeds ...`.
+//! The codes are part of the protocol, hence existing ones must never be renamed.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+const WIRE_PREFIX: &str = "ERROR:";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    /// The command is not known (to the server or client).
+    UnknownCommand,
+    /// The command arguments are missing or malformed.
+    InvalidArgument,
+    /// The upload exceeds the configured limit.
+    TooLarge,
+    /// The uploaded content could not be decoded (e.g. not an image).
+    DecodeFailed,
+    /// The detected type of the uploaded content is not allowed for the command.
+    UnsupportedType,
+    /// The upload would exceed a storage quota or the free space kept on the server.
+    QuotaExceeded,
+    /// The command requires privileges the session does not have.
+    Forbidden,
+    /// The feature is disabled in the server configuration.
+    Disabled,
+    /// The session sends messages faster than the configured rate limit.
+    RateLimited,
+    /// The referenced entity (nick, file, ...) does not exist.
+    NotFound,
+    /// The request conflicts with the current state (e.g. nick already taken).
+    Conflict,
+    /// The configuration could not be loaded.
+    InvalidConfig,
+    /// Reading or writing a (local) file failed.
+    Io,
+    /// The connection to the peer failed, the only error the client does not recover from.
+    Connection,
+    /// The client lost the connection and is reconnecting, the request was not sent (client-side only).
+    Offline,
+    /// Any other failure.
+    Internal,
+}
+
+impl ErrorCode {
+    const ALL: [ErrorCode; 16] = [
+        ErrorCode::UnknownCommand,
+        ErrorCode::InvalidArgument,
+        ErrorCode::TooLarge,
+        ErrorCode::DecodeFailed,
+        ErrorCode::UnsupportedType,
+        ErrorCode::QuotaExceeded,
+        ErrorCode::Forbidden,
+        ErrorCode::Disabled,
+        ErrorCode::RateLimited,
+        ErrorCode::NotFound,
+        ErrorCode::Conflict,
+        ErrorCode::InvalidConfig,
+        ErrorCode::Io,
+        ErrorCode::Connection,
+        ErrorCode::Offline,
+        ErrorCode::Internal,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::UnknownCommand => "UNKNOWN_COMMAND",
+            ErrorCode::InvalidArgument => "INVALID_ARGUMENT",
+            ErrorCode::TooLarge => "TOO_LARGE",
+            ErrorCode::DecodeFailed => "DECODE_FAILED",
+            ErrorCode::UnsupportedType => "UNSUPPORTED_TYPE",
+            ErrorCode::QuotaExceeded => "QUOTA_EXCEEDED",
+            ErrorCode::Forbidden => "FORBIDDEN",
+            ErrorCode::Disabled => "DISABLED",
+            ErrorCode::RateLimited => "RATE_LIMITED",
+            ErrorCode::NotFound => "NOT_FOUND",
+            ErrorCode::Conflict => "CONFLICT",
+            ErrorCode::InvalidConfig => "INVALID_CONFIG",
+            ErrorCode::Io => "IO_ERROR",
+            ErrorCode::Connection => "CONNECTION",
+            ErrorCode::Offline => "OFFLINE",
+            ErrorCode::Internal => "INTERNAL",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ErrorCode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ErrorCode::ALL
+            .into_iter()
+            .find(|code| code.as_str() == s)
+            .ok_or(format!("Unknown error code '{}'", s))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChatError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+pub type ChatResult<T> = Result<T, ChatError>;
+
+impl ChatError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ChatError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Wraps a failure of the connection itself (as opposed to a failure reported by the peer).
+    pub fn connection(e: impl fmt::Display) -> Self {
+        ChatError::new(ErrorCode::Connection, e.to_string())
+    }
+
+    pub fn is_fatal(&self) -> bool {
+        self.code == ErrorCode::Connection
+    }
+
+    /// Encodes the error into a single response line (without the line terminator).
+    pub fn to_wire(&self) -> String {
+        let message = self.message.replace('\n', " ");
+        format!("{} {}: {}", WIRE_PREFIX, self.code, message)
+    }
+
+    /// Decodes an error response, `None` if the response is not an error.
+    ///
+    /// Errors without a (known) code are reported as `Internal`, keeping the whole text as the message.
+    pub fn from_wire(response: &str) -> Option<Self> {
+        let error = response.strip_prefix(WIRE_PREFIX)?.trim();
+        let parsed = error
+            .split_once(':')
+            .and_then(|(code, message)| Some((code.parse().ok()?, message.trim())));
+        Some(match parsed {
+            Some((code, message)) => ChatError::new(code, message),
+            None => ChatError::new(ErrorCode::Internal, error),
+        })
+    }
+}
+
+impl fmt::Display for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+impl Error for ChatError {}
+
+impl From<io::Error> for ChatError {
+    fn from(e: io::Error) -> Self {
+        ChatError::new(ErrorCode::Io, e.to_string())
+    }
+}
+
+impl From<std::num::ParseIntError> for ChatError {
+    fn from(e: std::num::ParseIntError) -> Self {
+        ChatError::new(ErrorCode::InvalidArgument, e.to_string())
+    }
+}
@@ -0,0 +1,171 @@
+//! Events sent by the server on its own (not as a response to a request of the client).
+//!
+//! An event is a single line in the form `EVENT: <KIND> <arguments>` terminated by an empty line
+//! (like any response), so that the client can tell it apart from the responses to its requests.
+
+use crate::command::{next_token, quote};
+use std::fmt;
+
+const WIRE_PREFIX: &str = "EVENT:";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ServerEvent {
+    /// A plain message sent by another user (identified by the nick or the address).
+    Message { from: String, text: String },
+    /// A notification from the server itself.
+    Notice(String),
+    /// Another user (identified by the nick or the address) entered the room.
+    Joined { user: String, room: String },
+    /// Another user left the room (for another one or by disconnecting).
+    Left { user: String, room: String },
+    /// Another user in the room changed the nick (from the previous one or the address).
+    NickChanged { from: String, to: String },
+    /// The room or the nick of the receiving client itself changed (sent on connecting as well).
+    Session { room: String, nick: Option<String> },
+}
+
+impl ServerEvent {
+    /// Encodes the event into a single line (without the line terminator).
+    pub fn to_wire(&self) -> String {
+        match self {
+            ServerEvent::Message { from, text } => {
+                format!(
+                    "{} MESSAGE {} {}",
+                    WIRE_PREFIX,
+                    quote(from),
+                    single_line(text)
+                )
+            }
+            ServerEvent::Notice(text) => format!("{} NOTICE {}", WIRE_PREFIX, single_line(text)),
+            ServerEvent::Joined { user, room } => {
+                format!("{} JOINED {} {}", WIRE_PREFIX, quote(user), quote(room))
+            }
+            ServerEvent::Left { user, room } => {
+                format!("{} LEFT {} {}", WIRE_PREFIX, quote(user), quote(room))
+            }
+            ServerEvent::NickChanged { from, to } => {
+                format!("{} NICK {} {}", WIRE_PREFIX, quote(from), quote(to))
+            }
+            ServerEvent::Session { room, nick: None } => {
+                format!("{} SESSION {}", WIRE_PREFIX, quote(room))
+            }
+            ServerEvent::Session {
+                room,
+                nick: Some(nick),
+            } => format!("{} SESSION {} {}", WIRE_PREFIX, quote(room), quote(nick)),
+        }
+    }
+
+    /// Decodes an event, `None` if the frame is not an event.
+    ///
+    /// Events of unknown kinds (sent by newer servers) are reported as notices.
+    pub fn from_wire(frame: &str) -> Option<Self> {
+        let event = frame.strip_prefix(WIRE_PREFIX)?.trim();
+        let (kind, rest) = event.split_once(' ').unwrap_or((event, ""));
+        let parsed = match kind {
+            "MESSAGE" => next_token(rest)
+                .ok()
+                .flatten()
+                .map(|(from, text)| ServerEvent::Message {
+                    from,
+                    text: text.trim().to_string(),
+                }),
+            "NOTICE" => Some(ServerEvent::Notice(rest.trim().to_string())),
+            "JOINED" => two_tokens(rest).map(|(user, room)| ServerEvent::Joined { user, room }),
+            "LEFT" => two_tokens(rest).map(|(user, room)| ServerEvent::Left { user, room }),
+            "NICK" => two_tokens(rest).map(|(from, to)| ServerEvent::NickChanged { from, to }),
+            "SESSION" => next_token(rest)
+                .ok()
+                .flatten()
+                .map(|(room, rest)| ServerEvent::Session {
+                    room,
+                    nick: next_token(rest).ok().flatten().map(|(nick, _)| nick),
+                }),
+            _ => None,
+        };
+        Some(parsed.unwrap_or_else(|| ServerEvent::Notice(event.to_string())))
+    }
+}
+
+impl fmt::Display for ServerEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerEvent::Message { from, text } => write!(f, "<{}> {}", from, text),
+            ServerEvent::Notice(text) => write!(f, "*** {}", text),
+            ServerEvent::Joined { user, room } => write!(f, "*** {} joined #{}", user, room),
+            ServerEvent::Left { user, room } => write!(f, "*** {} left #{}", user, room),
+            ServerEvent::NickChanged { from, to } => {
+                write!(f, "*** {} is now known as {}", from, to)
+            }
+            ServerEvent::Session { room, nick: None } => write!(f, "*** You are in #{}", room),
+            ServerEvent::Session {
+                room,
+                nick: Some(nick),
+            } => write!(f, "*** You are {} in #{}", nick, room),
+        }
+    }
+}
+
+fn single_line(text: &str) -> String {
+    text.replace('\n', " ")
+}
+
+/// The first two tokens of the arguments, `None` if there are fewer of them.
+fn two_tokens(arguments: &str) -> Option<(String, String)> {
+    let (first, rest) = next_token(arguments).ok().flatten()?;
+    let (second, _) = next_token(rest).ok().flatten()?;
+    Some((first, second))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_survive_the_wire() {
+        let events = [
+            ServerEvent::Message {
+                from: "ann".to_string(),
+                text: "hi there".to_string(),
+            },
+            ServerEvent::Notice("Kicked by an admin".to_string()),
+            ServerEvent::Joined {
+                user: "127.0.0.1:4321".to_string(),
+                room: "dev".to_string(),
+            },
+            ServerEvent::Left {
+                user: "ann".to_string(),
+                room: "lobby".to_string(),
+            },
+            ServerEvent::NickChanged {
+                from: "127.0.0.1:4321".to_string(),
+                to: "bob".to_string(),
+            },
+            ServerEvent::Session {
+                room: "dev".to_string(),
+                nick: None,
+            },
+            ServerEvent::Session {
+                room: "dev".to_string(),
+                nick: Some("bob".to_string()),
+            },
+        ];
+        for event in events {
+            assert_eq!(ServerEvent::from_wire(&event.to_wire()), Some(event));
+        }
+    }
+
+    #[test]
+    fn unknown_or_malformed_events_are_notices() {
+        assert_eq!(
+            ServerEvent::from_wire("EVENT: WEATHER sunny"),
+            Some(ServerEvent::Notice("WEATHER sunny".to_string()))
+        );
+        assert_eq!(
+            ServerEvent::from_wire("EVENT: JOINED ann"),
+            Some(ServerEvent::Notice("JOINED ann".to_string()))
+        );
+        assert_eq!(ServerEvent::from_wire("Joined #dev"), None);
+    }
+}
@@ -0,0 +1,48 @@
+//! Framing of the responses and events sent by the server.
+//!
+//! Every frame is a sequence of non-empty lines terminated by an empty line, possibly followed
+//! by raw content announced by the frame (e.g. a download, see `download`).
+
+use std::io::{self, BufRead};
+
+/// Reads a single frame (without the terminating empty line), `None` once the connection is closed.
+///
+/// The reader must be kept for the whole connection, the content following a frame may already be buffered.
+pub fn read_frame(reader: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut buffer = String::new();
+    let mut frame = String::new();
+    loop {
+        buffer.clear();
+        if reader.read_line(&mut buffer)? == 0 {
+            return Ok(None);
+        }
+        if buffer.trim().is_empty() {
+            return Ok(Some(frame.trim().to_string()));
+        }
+        frame.push_str(&buffer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_are_split_at_empty_lines() {
+        let mut input = &b"first\nsecond\n\nthird\r\n\r\nrest"[..];
+        assert_eq!(
+            read_frame(&mut input).unwrap(),
+            Some("first\nsecond".to_string())
+        );
+        assert_eq!(read_frame(&mut input).unwrap(), Some("third".to_string()));
+        // an unterminated frame is lost with the connection
+        assert_eq!(read_frame(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn content_after_frame_is_left_in_reader() {
+        let mut input = &b"DOWNLOAD: 3 a\n\nabc"[..];
+        read_frame(&mut input).unwrap();
+        assert_eq!(input, b"abc");
+    }
+}
@@ -1,45 +1,40 @@
 pub mod cli;
+pub mod command;
+pub mod compression;
+pub mod content_type;
+pub mod download;
+pub mod error;
+pub mod event;
+pub mod frame;
+pub mod logging;
 pub mod util;
 
 // all macros (exported at library level, hence not in a specific module)
 
 #[macro_export]
-macro_rules! log {
+macro_rules! error {
     ($($arg:tt)*) => {
-        let message = format!($($arg)*);
-        println!("[{}] {}", std::thread::current().name().unwrap(), message.trim());
-        flush();
+        $crate::logging::log($crate::logging::Level::Error, format_args!($($arg)*))
     };
 }
 
 #[macro_export]
-macro_rules! elog {
+macro_rules! warn {
     ($($arg:tt)*) => {
-        let message = format!($($arg)*);
-        eprintln!("[{}] {}", std::thread::current().name().unwrap(), message.trim());
-        flush();
+        $crate::logging::log($crate::logging::Level::Warn, format_args!($($arg)*))
     };
 }
 
 #[macro_export]
-macro_rules! stream {
-    ($stream:expr, $($arg:tt)*) => {
-        let message = format!($($arg)*);
-        if ! message.trim().is_empty() {
-            println!("[{}] {}", std::thread::current().name().unwrap(), message.trim());
-        }
-        $stream.write_all(message.as_bytes()).unwrap();
-        flush();
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::Level::Info, format_args!($($arg)*))
     };
 }
 
 #[macro_export]
-macro_rules! estream {
-    ($stream:expr, $($arg:tt)*) => {
-        let message = format!($($arg)*);
-        let full_message = format!("ERROR: {}", message);
-        eprintln!("[{}] {}", std::thread::current().name().unwrap(), &full_message.trim());
-        $stream.write_all(full_message.as_bytes()).unwrap();
-        flush();
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::Level::Debug, format_args!($($arg)*))
     };
 }
@@ -0,0 +1,356 @@
+//! Structured logging shared by client and server.
+//!
+//! Every record carries a timestamp, a level, the name of the emitting thread, the fields
+//! attached to the current thread (e.g. peer address and nick of the handled connection)
+//! and the message itself. Records are written either as human-readable text or as JSON lines
+//! and go either to the console (errors and warnings to stderr, the rest to stdout)
+//! or to a log file rotated once it grows over the configured size.
+//!
+//! The logger is configured once on startup using `init`, records logged before that
+//! are written as text to the console on the `Info` level.
+//! The records are meant to be emitted using the `error!`, `warn!`, `info!` and `debug!` macros.
+//! Applications taking over the whole terminal redirect the console records to themselves using `redirect_console`.
+
+use chrono::{SecondsFormat, Utc};
+use std::cell::RefCell;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+
+/// Number of rotated log files kept next to the current one (`<file>.1` being the most recent).
+const ROTATED_FILES: usize = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            _ => Err(format!(
+                "Invalid log level '{}', valid are: error, warn, info, debug",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("Invalid log format '{}', valid are: text, json", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LogSettings {
+    pub level: Level,
+    pub format: Format,
+    pub file: Option<String>,
+    pub max_size: u64,
+}
+
+struct RotatingFile {
+    path: String,
+    max_size: u64,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &str, max_size: u64) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_string(),
+            max_size,
+            file,
+            size,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.max_size > 0 && self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for i in (1..ROTATED_FILES).rev() {
+            let from = format!("{}.{}", self.path, i);
+            if fs::metadata(&from).is_ok() {
+                fs::rename(&from, format!("{}.{}", self.path, i + 1))?;
+            }
+        }
+        fs::rename(&self.path, format!("{}.1", self.path))?;
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+struct Logger {
+    level: Level,
+    format: Format,
+    file: Option<Mutex<RotatingFile>>,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+type ConsoleSink = Box<dyn Fn(Level, &str) + Send + Sync>;
+
+static CONSOLE: OnceLock<ConsoleSink> = OnceLock::new();
+
+thread_local! {
+    static FIELDS: RefCell<Vec<(&'static str, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Configures the logger, can be called only once (before any concurrent logging starts).
+pub fn init(settings: &LogSettings) -> Result<(), Box<dyn Error>> {
+    let file = match &settings.file {
+        Some(path) => Some(Mutex::new(
+            RotatingFile::open(path, settings.max_size)
+                .map_err(|e| format!("Failed to open log file {}: {}", path, e))?,
+        )),
+        None => None,
+    };
+    let logger = Logger {
+        level: settings.level,
+        format: settings.format,
+        file,
+    };
+    LOGGER
+        .set(logger)
+        .map_err(|_| "Logging is already initialized".into())
+}
+
+/// Hands the records meant for the console over to the sink (as plain messages) instead of printing them,
+/// can be called only once. The records still go to the log file, if configured.
+pub fn redirect_console(
+    sink: impl Fn(Level, &str) + Send + Sync + 'static,
+) -> Result<(), Box<dyn Error>> {
+    CONSOLE
+        .set(Box::new(sink))
+        .map_err(|_| "Console is already redirected".into())
+}
+
+/// Formats the record the same way as it would be printed to the console (e.g. by a sink of `redirect_console`).
+pub fn format_line(level: Level, message: &str) -> String {
+    let format = LOGGER.get().map_or(Format::Text, |logger| logger.format);
+    format_record(format, level, message)
+}
+
+/// Attaches a field to all the records subsequently logged by the current thread.
+pub fn set_field(name: &'static str, value: impl fmt::Display) {
+    FIELDS.with(|fields| {
+        let mut fields = fields.borrow_mut();
+        let value = value.to_string();
+        match fields.iter_mut().find(|(n, _)| *n == name) {
+            Some(field) => field.1 = value,
+            None => fields.push((name, value)),
+        }
+    });
+}
+
+pub fn enabled(level: Level) -> bool {
+    level <= LOGGER.get().map_or(Level::Info, |logger| logger.level)
+}
+
+/// Emits a single record, use the logging macros instead of calling this directly.
+pub fn log(level: Level, message: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+    let format = LOGGER.get().map_or(Format::Text, |logger| logger.format);
+    let message = message.to_string();
+    let file = LOGGER.get().and_then(|logger| logger.file.as_ref());
+    if let (None, Some(console)) = (file, CONSOLE.get()) {
+        console(level, message.trim());
+        return;
+    }
+    let line = format_record(format, level, &message);
+    match file {
+        Some(file) => {
+            if let Err(e) = file.lock().unwrap().write_line(&line) {
+                eprintln!("Failed to write to log file: {}\n{}", e, line);
+            }
+        }
+        None if level <= Level::Warn => {
+            eprintln!("{}", line);
+        }
+        None => {
+            println!("{}", line);
+            crate::util::flush();
+        }
+    }
+}
+
+fn format_record(format: Format, level: Level, message: &str) -> String {
+    let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    let thread = std::thread::current();
+    let thread = thread.name().unwrap_or("unnamed");
+    let message = message.trim();
+    FIELDS.with(|fields| {
+        let fields = fields.borrow();
+        match format {
+            Format::Text => {
+                let mut line = format!("{} {:<5} [{}]", timestamp, level.as_str(), thread);
+                for (name, value) in fields.iter() {
+                    line.push_str(&format!(" {}={}", name, text_value(value)));
+                }
+                line.push(' ');
+                line.push_str(message);
+                line
+            }
+            Format::Json => {
+                let mut record = serde_json::Map::new();
+                record.insert("ts".into(), timestamp.into());
+                record.insert("level".into(), level.as_str().into());
+                record.insert("thread".into(), thread.into());
+                for (name, value) in fields.iter() {
+                    record.insert(name.to_string(), value.clone().into());
+                }
+                record.insert("msg".into(), message.into());
+                serde_json::Value::Object(record).to_string()
+            }
+        }
+    })
+}
+
+/// The field value as is if it is a single word, quoted and escaped otherwise (e.g. a nick with spaces),
+/// so that the fields of a text record can be told apart.
+fn text_value(value: &str) -> String {
+    if value.is_empty()
+        || value
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || c == '"' || c == '=')
+    {
+        format!("{:?}", value)
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("logging-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn levels_and_formats_are_parsed_case_insensitively() {
+        assert_eq!("error".parse(), Ok(Level::Error));
+        assert_eq!("Warn".parse(), Ok(Level::Warn));
+        assert_eq!("INFO".parse(), Ok(Level::Info));
+        assert_eq!("debug".parse(), Ok(Level::Debug));
+        assert!("trace".parse::<Level>().is_err());
+        assert!(Level::Error < Level::Debug);
+
+        assert_eq!("text".parse(), Ok(Format::Text));
+        assert_eq!("JSON".parse(), Ok(Format::Json));
+        assert!("xml".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn text_record_quotes_values_with_spaces() {
+        set_field("peer", "127.0.0.1:1234");
+        set_field("nick", "john doe");
+        let line = format_record(Format::Text, Level::Warn, "Hello\n");
+        assert!(
+            line.contains(" WARN  [")
+                && line.ends_with("] peer=127.0.0.1:1234 nick=\"john doe\" Hello"),
+            "{}",
+            line
+        );
+
+        set_field("nick", "");
+        let line = format_record(Format::Text, Level::Info, "Hello");
+        assert!(line.contains(" nick=\"\" "), "{}", line);
+    }
+
+    #[test]
+    fn json_record_carries_fields() {
+        set_field("peer", "127.0.0.1:1234");
+        set_field("nick", "john \"doe\"");
+        let line = format_record(Format::Json, Level::Info, "  Hello world\n");
+        let record: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(record["level"], "INFO");
+        assert_eq!(
+            record["thread"],
+            "logging::tests::json_record_carries_fields"
+        );
+        assert_eq!(record["peer"], "127.0.0.1:1234");
+        assert_eq!(record["nick"], "john \"doe\"");
+        assert_eq!(record["msg"], "Hello world");
+        assert!(record["ts"].as_str().unwrap().ends_with('Z'));
+    }
+
+    #[test]
+    fn rotation_shifts_and_drops_the_oldest_files() {
+        let directory = temp_dir("rotate");
+        let path = directory.join("app.log").to_string_lossy().to_string();
+        // every line (with its newline) takes 6 bytes, two of them fit
+        let mut file = RotatingFile::open(&path, 12).unwrap();
+        for i in 0..14 {
+            file.write_line(&format!("line{}", i % 10)).unwrap();
+        }
+        let content = |suffix: &str| fs::read_to_string(format!("{}{}", path, suffix)).ok();
+        assert_eq!(content("").as_deref(), Some("line2\nline3\n"));
+        assert_eq!(content(".1").as_deref(), Some("line0\nline1\n"));
+        assert_eq!(content(".2").as_deref(), Some("line8\nline9\n"));
+        assert_eq!(content(".5").as_deref(), Some("line2\nline3\n"));
+        assert_eq!(content(".6"), None);
+
+        // the size of the existing file counts
+        let mut file = RotatingFile::open(&path, 12).unwrap();
+        file.write_line("next").unwrap();
+        assert_eq!(content("").as_deref(), Some("next\n"));
+        assert_eq!(content(".1").as_deref(), Some("line2\nline3\n"));
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
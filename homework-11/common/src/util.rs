@@ -1,22 +1,76 @@
+use std::error::Error;
 use std::io::stdout;
 use std::io::Write;
-use std::path::Path;
-use std::fs;
+use std::time::Duration;
 
 pub fn flush() {
     if let Err(e) = stdout().flush() {
         eprintln!("Error flushing stdout: {}", e);
-        return;
     }
 }
 
-pub fn ensure_directory(directory: &String) {
-    let files_dir = Path::new(directory);
-    if !files_dir.exists() {
-        if let Err(e) = fs::create_dir(files_dir) {
-            eprintln!("Failed to create storage directory: {}", e);
-            std::process::exit(1);
-        }
-        println!("Created directory: {}/", directory);
+/// Parses a duration like `90s`, `15m`, `2h` or `7d` (a bare number means seconds).
+pub fn parse_duration(input: &str) -> Result<Duration, Box<dyn Error>> {
+    let input = input.trim();
+    let (number, multiplier) = match input.char_indices().last() {
+        Some((i, 's')) => (&input[..i], 1),
+        Some((i, 'm')) => (&input[..i], 60),
+        Some((i, 'h')) => (&input[..i], 60 * 60),
+        Some((i, 'd')) => (&input[..i], 24 * 60 * 60),
+        Some(_) => (input, 1),
+        None => return Err("Empty duration".into()),
+    };
+    let number = number
+        .parse::<u64>()
+        .map_err(|_| format!("Invalid duration '{}'", input))?;
+    let secs = number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("Duration '{}' is too long", input))?;
+    Ok(Duration::from_secs(secs))
+}
+
+/// Parses a size like `512`, `64K`, `10M` or `2G` (binary multiples, a bare number means bytes).
+pub fn parse_size(input: &str) -> Result<u64, Box<dyn Error>> {
+    let input = input.trim();
+    let (number, multiplier) = match input.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&input[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&input[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&input[..i], 1 << 30),
+        Some(_) => (input, 1),
+        None => return Err("Empty size".into()),
+    };
+    let number = number
+        .parse::<u64>()
+        .map_err(|_| format!("Invalid size '{}'", input))?;
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("Size '{}' is too large", input).into())
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (days, hours, minutes, seconds) =
+        (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    if days > 0 {
+        format!("{}d {:02}h {:02}m {:02}s", days, hours, minutes, seconds)
+    } else if hours > 0 {
+        format!("{}h {:02}m {:02}s", hours, minutes, seconds)
+    } else {
+        format!("{}m {:02}s", minutes, seconds)
+    }
+}
+
+/// Formats a number of bytes for humans, e.g. `512 B`, `2.3 MB` (decimal multiples).
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["kB", "MB", "GB", "TB"];
+    if bytes < 1000 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1000.0;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
     }
+    format!("{:.1} {}", size, UNITS[unit])
 }
@@ -0,0 +1,183 @@
+//! Administrative commands for the server.
+//!
+//! A session becomes an admin one after presenting the shared admin token via `.admin <token>`.
+//! All the other commands in this module are available to admin sessions only
+//! (the check itself is done centrally in the `command` module).
+
+use crate::ban;
+use crate::state::Session;
+use common::command::Args;
+use common::error::{ChatError, ChatResult, ErrorCode};
+use common::event::ServerEvent;
+use common::util::format_duration;
+use common::{info, warn};
+use sha2::{Digest, Sha256};
+use std::net::{IpAddr, SocketAddr};
+
+pub(crate) fn admin(session: &mut Session, args: &Args) -> ChatResult<String> {
+    let result = elevate(args.value("token"), session);
+    // the token itself is deliberately not recorded
+    session.state.audit.admin(session, ".admin", "", &result);
+    result
+}
+
+fn elevate(token: &str, session: &mut Session) -> ChatResult<String> {
+    let config = session.config();
+    let expected = config.admin_token.as_deref().ok_or_else(|| {
+        ChatError::new(
+            ErrorCode::Disabled,
+            "Admin commands are disabled on this server",
+        )
+    })?;
+    if !tokens_match(token, expected) {
+        warn!("Invalid admin token presented");
+        return Err(ChatError::new(ErrorCode::Forbidden, "Invalid admin token"));
+    }
+    session.admin = true;
+    info!("Session elevated to admin");
+    Ok("Admin mode enabled".to_string())
+}
+
+/// Compares the digests of the tokens byte by byte in constant time, so that the time taken
+/// tells nothing about the expected token (not even its length).
+fn tokens_match(token: &str, expected: &str) -> bool {
+    let (token, expected) = (Sha256::digest(token), Sha256::digest(expected));
+    let difference = token
+        .iter()
+        .zip(expected.iter())
+        .fold(0, |difference, (a, b)| difference | (a ^ b));
+    difference == 0
+}
+
+pub(crate) fn who(session: &mut Session, _: &Args) -> ChatResult<String> {
+    let connections = session.state.connections.lock().unwrap();
+    let mut lines = connections
+        .iter()
+        .map(|(addr, connection)| {
+            format!(
+                "  {:<22} {:<16} {}",
+                addr,
+                connection.nick.as_deref().unwrap_or("-"),
+                format_duration(connection.connected_at.elapsed())
+            )
+        })
+        .collect::<Vec<_>>();
+    lines.sort();
+    Ok(format!(
+        "Connections ({}):\n{}",
+        lines.len(),
+        lines.join("\n")
+    ))
+}
+
+pub(crate) fn kick(session: &mut Session, args: &Args) -> ChatResult<String> {
+    let nick = args.value("nick");
+    let addr = session.state.find_by_nick(nick).ok_or_else(|| {
+        ChatError::new(
+            ErrorCode::NotFound,
+            format!("No user with nick '{}' is connected", nick),
+        )
+    })?;
+    disconnect(session, "You have been kicked by an administrator", |a| {
+        *a == addr
+    });
+    info!("Kicked {} ({})", nick, addr);
+    Ok(format!("Kicked {} ({})", nick, addr))
+}
+
+pub(crate) fn ban(session: &mut Session, args: &Args) -> ChatResult<String> {
+    let target = args.value("target");
+    let duration = args.duration("duration");
+
+    let ip = match target.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(_) => session
+            .state
+            .find_by_nick(target)
+            .ok_or_else(|| {
+                ChatError::new(
+                    ErrorCode::NotFound,
+                    format!("'{}' is neither an IP address nor a connected nick", target),
+                )
+            })?
+            .ip(),
+    };
+    if ip == session.peer.ip() {
+        return Err(ChatError::new(
+            ErrorCode::InvalidArgument,
+            "Refusing to ban your own address",
+        ));
+    }
+
+    if duration.is_some_and(|duration| ban::expiry(duration).is_none()) {
+        return Err(ChatError::new(
+            ErrorCode::InvalidArgument,
+            "The ban duration is too long",
+        ));
+    }
+
+    session
+        .state
+        .bans
+        .lock()
+        .unwrap()
+        .ban(ip, duration)
+        .map_err(|e| {
+            ChatError::new(
+                ErrorCode::Internal,
+                format!("Failed to save ban list: {}", e),
+            )
+        })?;
+    let kicked = disconnect(session, "You have been banned by an administrator", |a| {
+        a.ip() == ip
+    });
+    let until = duration.map_or("permanently".to_string(), |d| {
+        format!("for {}", format_duration(d))
+    });
+    info!("Banned {} {} ({} connection(s) closed)", ip, until, kicked);
+    Ok(format!(
+        "Banned {} {} ({} connection(s) closed)",
+        ip, until, kicked
+    ))
+}
+
+pub(crate) fn stats(session: &mut Session, _: &Args) -> ChatResult<String> {
+    let state = &session.state;
+    let metrics = &state.metrics;
+    let active = state.connections.lock().unwrap().len();
+    Ok(format!(
+        "Server statistics:\n  uptime: {}\n  connections: {} active, {} total\n  messages: {}\n  commands: {}\n  uploads: {} ({} bytes)",
+        format_duration(state.started_at.elapsed()),
+        active,
+        metrics.connections.get(),
+        metrics.messages.get(),
+        metrics.commands.total(),
+        metrics.uploads.get(),
+        metrics.upload_bytes.get(),
+    ))
+}
+
+fn disconnect(session: &Session, reason: &str, filter: impl Fn(&SocketAddr) -> bool) -> usize {
+    let connections = session.state.connections.lock().unwrap();
+    let mut count = 0;
+    for (_, connection) in connections.iter().filter(|(addr, _)| filter(addr)) {
+        connection.send(&ServerEvent::Notice(reason.to_string()));
+        connection.disconnect();
+        count += 1;
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_match_only_when_equal() {
+        assert!(tokens_match("s3cret", "s3cret"));
+        assert!(!tokens_match("s3cret", "s3cre"));
+        assert!(!tokens_match("s3cret", "s3cret "));
+        assert!(!tokens_match("", "s3cret"));
+        assert!(!tokens_match("S3CRET", "s3cret"));
+    }
+}
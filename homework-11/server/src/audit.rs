@@ -0,0 +1,149 @@
+//! Audit log of uploads, deletions and administrative actions.
+//!
+//! The audit log is an append-only file with one JSON object per line, written only when
+//! the audit log file is configured. The file is opened for every record, so that it can be
+//! moved away by external tools (and reconfigured by a reload) without restarting the server.
+//!
+//! Every record has the `ts`, `event`, `peer` and `nick` fields (`null` for the actions of the server
+//! itself, e.g. the retention policy), the rest depends on the event:
+//!
+//! - `upload` - `kind`, `name`, `size`, then `path`, `stored_size`, `sha256` and `converted` on success,
+//!   or `code` and `error` when the upload was rejected or failed,
+//! - `download` - `id`, `path` and `size`,
+//! - `delete` - `path`, `size`, `sha256` (`null` for an unpacked archive) and `reason` (`admin`) for a `.delete`,
+//! - `retention` - `path`, `size`, `reason` (`age` or `size`) and `archived_to` (if moved to the archive),
+//! - `admin` - `action`, `args` and `result` (`ok` or `error` along with the `code` and `error` message).
+
+use crate::config::Config;
+use crate::file::StoredFile;
+use crate::state::Session;
+use chrono::{SecondsFormat, Utc};
+use common::error;
+use common::error::ChatResult;
+use serde_json::{json, Map, Value};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Mutex;
+
+#[derive(Default)]
+pub(crate) struct AuditLog {
+    // serializes the writes of the records from all the threads
+    lock: Mutex<()>,
+}
+
+impl AuditLog {
+    pub(crate) fn upload(
+        &self,
+        session: &Session,
+        kind: &str,
+        name: &str,
+        size: usize,
+        result: &ChatResult<StoredFile>,
+    ) {
+        let mut details = json!({ "kind": kind, "name": name, "size": size });
+        match result {
+            Ok(stored) => {
+                details["path"] = stored.path.clone().into();
+                details["type"] = stored.content_type.into();
+                details["stored_size"] = stored.size.into();
+                details["sha256"] = stored.sha256.clone().into();
+                details["converted"] = stored.converted.into();
+            }
+            Err(e) => {
+                details["error"] = e.message.clone().into();
+                details["code"] = e.code.as_str().into();
+            }
+        }
+        self.record(session, "upload", details);
+    }
+
+    pub(crate) fn download(&self, session: &Session, id: &str, path: &str, size: usize) {
+        let details = json!({ "id": id, "path": path, "size": size });
+        self.record(session, "download", details);
+    }
+
+    pub(crate) fn delete(
+        &self,
+        session: &Session,
+        path: &str,
+        size: u64,
+        sha256: Option<&str>,
+        reason: &str,
+    ) {
+        let details = json!({ "path": path, "size": size, "sha256": sha256, "reason": reason });
+        self.record(session, "delete", details);
+    }
+
+    pub(crate) fn retention(
+        &self,
+        config: &Config,
+        path: &str,
+        size: u64,
+        reason: &str,
+        archived_to: Option<&str>,
+    ) {
+        let details =
+            json!({ "path": path, "size": size, "reason": reason, "archived_to": archived_to });
+        self.write(config, "retention", None, details);
+    }
+
+    pub(crate) fn admin(
+        &self,
+        session: &Session,
+        action: &str,
+        args: &str,
+        result: &ChatResult<String>,
+    ) {
+        let mut details = json!({ "action": action, "args": args });
+        match result {
+            Ok(_) => {
+                details["result"] = "ok".into();
+            }
+            Err(e) => {
+                details["result"] = "error".into();
+                details["error"] = e.message.clone().into();
+                details["code"] = e.code.as_str().into();
+            }
+        }
+        self.record(session, "admin", details);
+    }
+
+    fn record(&self, session: &Session, event: &str, details: Value) {
+        self.write(&session.config(), event, Some(session), details);
+    }
+
+    fn write(&self, config: &Config, event: &str, session: Option<&Session>, details: Value) {
+        let Some(path) = config.audit_log.clone() else {
+            return;
+        };
+        let mut record = Map::new();
+        record.insert(
+            "ts".into(),
+            Utc::now()
+                .to_rfc3339_opts(SecondsFormat::Millis, true)
+                .into(),
+        );
+        record.insert("event".into(), event.into());
+        record.insert(
+            "peer".into(),
+            session.map(|session| session.peer.to_string()).into(),
+        );
+        record.insert(
+            "nick".into(),
+            session.and_then(|session| session.nick()).into(),
+        );
+        if let Value::Object(details) = details {
+            record.extend(details);
+        }
+
+        let _lock = self.lock.lock().unwrap();
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| writeln!(file, "{}", Value::Object(record)));
+        if let Err(e) = result {
+            error!("Failed to write to audit log {}: {}", path, e);
+        }
+    }
+}
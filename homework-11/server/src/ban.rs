@@ -0,0 +1,106 @@
+//! Ban list handling.
+//!
+//! Bans are kept per IP address and persisted in a plain text file, one ban per line
+//! in the form `<ip> <expiry>`, where the expiry is either a UNIX timestamp (in seconds)
+//! or `never` for permanent bans. Expired bans are dropped on load and on every check.
+
+use common::warn;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const PERMANENT: &str = "never";
+
+pub(crate) struct BanList {
+    path: String,
+    entries: HashMap<IpAddr, Option<u64>>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl BanList {
+    pub(crate) fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let mut entries = HashMap::new();
+        if Path::new(path).exists() {
+            for line in fs::read_to_string(path)?.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                match parse_entry(line) {
+                    Ok((ip, expiry)) => {
+                        entries.insert(ip, expiry);
+                    }
+                    Err(e) => {
+                        warn!("Skipping invalid ban entry '{}': {}", line, e);
+                    }
+                }
+            }
+        }
+        let mut list = BanList {
+            path: path.to_string(),
+            entries,
+        };
+        list.purge_expired();
+        Ok(list)
+    }
+
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        let mut content = String::new();
+        for (ip, expiry) in &self.entries {
+            let expiry = expiry.map_or(PERMANENT.to_string(), |e| e.to_string());
+            content.push_str(&format!("{} {}\n", ip, expiry));
+        }
+        fs::write(&self.path, content)?;
+        Ok(())
+    }
+
+    fn purge_expired(&mut self) {
+        let now = now();
+        self.entries
+            .retain(|_, expiry| expiry.is_none_or(|expiry| expiry > now));
+    }
+
+    /// Bans the address for the given duration (or permanently) and persists the list.
+    pub(crate) fn ban(
+        &mut self,
+        ip: IpAddr,
+        duration: Option<Duration>,
+    ) -> Result<(), Box<dyn Error>> {
+        let expiry = match duration {
+            Some(duration) => Some(expiry(duration).ok_or("Ban duration is too long")?),
+            None => None,
+        };
+        self.entries.insert(ip, expiry);
+        self.purge_expired();
+        self.save()
+    }
+
+    pub(crate) fn is_banned(&mut self, ip: &IpAddr) -> bool {
+        self.purge_expired();
+        self.entries.contains_key(ip)
+    }
+}
+
+/// UNIX time (in seconds) of the end of a ban of the duration starting now, `None` if out of range.
+pub(crate) fn expiry(duration: Duration) -> Option<u64> {
+    now().checked_add(duration.as_secs())
+}
+
+fn parse_entry(line: &str) -> Result<(IpAddr, Option<u64>), Box<dyn Error>> {
+    let mut parts = line.split_whitespace();
+    let ip = parts.next().ok_or("Missing address")?.parse::<IpAddr>()?;
+    let expiry = match parts.next() {
+        None | Some(PERMANENT) => None,
+        Some(expiry) => Some(expiry.parse::<u64>()?),
+    };
+    Ok((ip, expiry))
+}
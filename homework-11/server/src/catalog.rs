@@ -0,0 +1,227 @@
+//! Catalog of the stored uploads.
+//!
+//! Every upload is recorded with a short ID (e.g. `a1b2`, referring to it in `.get`) and its owner
+//! (the nick of the uploader, or the IP address if no nick was set), so that the storage usage can be
+//! accounted per user. The catalog is persisted in a plain text file, one upload per line in the form
+//! `<timestamp> <id> <owner> <kind> <size> <path>`, where the timestamp is the UNIX time (in seconds)
+//! of the upload. Uploads no longer found in the storage (e.g. removed by hand) are dropped on load.
+
+use common::warn;
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Minimal length of an ID, made longer only in case of a collision.
+const ID_LENGTH: usize = 4;
+
+#[derive(Clone)]
+pub(crate) struct Entry {
+    pub(crate) uploaded_at: u64,
+    pub(crate) id: String,
+    pub(crate) owner: String,
+    /// The upload command, i.e. `file`, `image` or `archive`.
+    pub(crate) kind: String,
+    pub(crate) size: u64,
+    /// The stored file, or the directory an archive was unpacked into.
+    pub(crate) path: String,
+}
+
+impl Entry {
+    /// The name of the upload, i.e. the stored name without the timestamp prefix.
+    pub(crate) fn name(&self) -> String {
+        let name = Path::new(&self.path)
+            .file_name()
+            .map_or(self.path.clone(), |name| name.to_string_lossy().to_string());
+        match name.split_once('_') {
+            Some((_, name)) => name.to_string(),
+            None => name,
+        }
+    }
+
+    /// The line of the catalog file, refused if the entry would span more lines.
+    fn to_line(&self) -> io::Result<String> {
+        if self.path.contains(['\n', '\r']) || self.owner.contains(['\n', '\r']) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Line break in the catalog entry of {}",
+                    self.path.escape_debug()
+                ),
+            ));
+        }
+        Ok(format!(
+            "{} {} {} {} {} {}\n",
+            self.uploaded_at, self.id, self.owner, self.kind, self.size, self.path
+        ))
+    }
+}
+
+pub(crate) struct Catalog {
+    path: String,
+    entries: Vec<Entry>,
+    /// Sizes of the uploads in progress by their owners, see `quota`.
+    reserved: Vec<(String, u64)>,
+}
+
+impl Catalog {
+    pub(crate) fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let mut entries: Vec<Entry> = Vec::new();
+        if Path::new(path).exists() {
+            for line in fs::read_to_string(path)?.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                match parse_entry(line) {
+                    Ok(entry) if Path::new(&entry.path).exists() => entries.push(entry),
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!("Skipping invalid catalog entry '{}': {}", line, e);
+                    }
+                }
+            }
+        }
+        Ok(Catalog {
+            path: path.to_string(),
+            entries,
+            reserved: Vec::new(),
+        })
+    }
+
+    /// Takes the entries of the freshly loaded catalog over, keeping the uploads in progress.
+    pub(crate) fn reload(&mut self, catalog: Catalog) {
+        self.path = catalog.path;
+        self.entries = catalog.entries;
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let content = self
+            .entries
+            .iter()
+            .map(Entry::to_line)
+            .collect::<io::Result<String>>()?;
+        fs::write(&self.path, content)
+    }
+
+    /// Records the upload, appending it to the catalog file, and returns its ID.
+    pub(crate) fn add(
+        &mut self,
+        owner: String,
+        kind: &str,
+        size: u64,
+        path: String,
+    ) -> io::Result<String> {
+        let uploaded_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let entry = Entry {
+            id: new_id(&self.entries, &path, uploaded_at),
+            uploaded_at,
+            owner,
+            kind: kind.to_string(),
+            size,
+            path,
+        };
+        let line = entry.to_line()?;
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(line.as_bytes())?;
+        let id = entry.id.clone();
+        self.entries.push(entry);
+        Ok(id)
+    }
+
+    pub(crate) fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Finds the upload by its ID (with or without the leading `#`).
+    pub(crate) fn find(&self, id: &str) -> Option<&Entry> {
+        let id = id.strip_prefix('#').unwrap_or(id).to_lowercase();
+        self.entries.iter().find(|entry| entry.id == id)
+    }
+
+    /// Removes the upload stored at the path (which has to exist yet), returning whether it was found.
+    pub(crate) fn remove(&mut self, path: &Path) -> io::Result<bool> {
+        let canonical = fs::canonicalize(path)?;
+        let count = self.entries.len();
+        self.entries
+            .retain(|entry| fs::canonicalize(&entry.path).ok().as_ref() != Some(&canonical));
+        if self.entries.len() == count {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Number and total size of the uploads of the owner.
+    pub(crate) fn usage(&self, owner: &str) -> (usize, u64) {
+        self.entries
+            .iter()
+            .filter(|entry| entry.owner == owner)
+            .fold((0, 0), |(count, size), entry| {
+                (count + 1, size + entry.size)
+            })
+    }
+
+    /// Total size of the uploads in progress, of the given owner or of all of them.
+    pub(crate) fn reserved(&self, owner: Option<&str>) -> u64 {
+        self.reserved
+            .iter()
+            .filter(|(reserved_by, _)| owner.is_none_or(|owner| owner == reserved_by))
+            .map(|(_, size)| size)
+            .sum()
+    }
+
+    pub(crate) fn reserve(&mut self, owner: &str, size: u64) {
+        self.reserved.push((owner.to_string(), size));
+    }
+
+    pub(crate) fn release(&mut self, owner: &str, size: u64) {
+        if let Some(index) = self
+            .reserved
+            .iter()
+            .position(|(reserved_by, reserved)| reserved_by == owner && *reserved == size)
+        {
+            self.reserved.swap_remove(index);
+        }
+    }
+}
+
+/// The shortest unique prefix (of at least `ID_LENGTH`) of the hash of the upload path and time.
+fn new_id(entries: &[Entry], path: &str, uploaded_at: u64) -> String {
+    let hash = Sha256::digest(format!("{} {}", uploaded_at, path))
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    (ID_LENGTH..hash.len())
+        .map(|length| &hash[..length])
+        .find(|id| !entries.iter().any(|entry| entry.id == *id))
+        .unwrap_or(&hash)
+        .to_string()
+}
+
+fn parse_entry(line: &str) -> Result<Entry, Box<dyn Error>> {
+    let mut parts = line.splitn(6, ' ');
+    let mut next = |name: &str| parts.next().ok_or(format!("Missing {}", name));
+    let uploaded_at = next("timestamp")?.parse::<u64>()?;
+    let id = next("id")?.to_string();
+    let owner = next("owner")?.to_string();
+    let kind = next("kind")?.to_string();
+    let size = next("size")?.parse::<u64>()?;
+    let path = next("path")?.to_string();
+    Ok(Entry {
+        uploaded_at,
+        id,
+        owner,
+        kind,
+        size,
+        path,
+    })
+}
@@ -1,73 +1,221 @@
 //! Command handling for the server.
 //!
-//! The module handles all commands (including a declarative help for all of them).
+//! The module defines the built-in commands (their argument schema drives the validation and `.help`)
+//! and dispatches the received lines to them.
 
-use crate::config::Config;
-use crate::file::{post_process_image, store_file};
-use common::log;
-use common::util::flush;
-use lazy_static::lazy_static;
-use std::collections::HashMap;
-use std::error::Error;
-use std::net::TcpStream;
+use crate::admin::{admin, ban, kick, stats, who};
+use crate::event::Event;
+use crate::file::{delete_file, discard_upload, get_file, post_process_image, store_file, Storing};
+use crate::quota::quota;
+use crate::reload::reload;
+use crate::room::{announce, join, rooms, send_session, users};
+use crate::state::Session;
+use common::command::{Arg, ArgKind, Args, CommandFn, CommandSpec, FnCommand, Registry};
+use common::compression::Compression;
+use common::error::{ChatError, ChatResult, ErrorCode};
+use common::event::ServerEvent;
+use common::info;
+use common::logging::set_field;
 
-struct Command {
-    pub func: fn(&mut TcpStream, &str, &Config) -> Result<String, Box<dyn Error>>,
-    pub description: String,
+/// Registry of the built-in server commands.
+pub(crate) fn registry() -> Registry<Session> {
+    use ArgKind::*;
+    #[rustfmt::skip]
+    let commands: [(CommandSpec, CommandFn<Session>); 20] = [
+        (CommandSpec::new(".help", "Lists all commands"), help),
+        (CommandSpec::new(".file", "Stores a generic file").arg(Arg::required("size", Number)).arg(Arg::required("name", Path)).arg(Arg::optional("compression", Word)).arg(Arg::optional("original_size", Number)), file),
+        (CommandSpec::new(".image", "Stores an image file").arg(Arg::required("size", Number)).arg(Arg::required("name", Path)).arg(Arg::optional("compression", Word)).arg(Arg::optional("original_size", Number)), image),
+        (CommandSpec::new(".archive", "Stores a tar archive unpacked into a directory of its own").arg(Arg::required("size", Number)).arg(Arg::required("name", Path)).arg(Arg::optional("compression", Word)).arg(Arg::optional("original_size", Number)), archive),
+        (CommandSpec::new(".get", "Downloads the upload with the given ID (e.g. #a1b2)").arg(Arg::required("id", Word)), get_file),
+        (CommandSpec::new(".compress", "Negotiates the compression of the transfers (the first supported is chosen)").arg(Arg::required("algorithms", Text)), compress),
+        (CommandSpec::new(".info", "Logs an info text on server side").arg(Arg::optional("text", Text)), info),
+        (CommandSpec::new(".nick", "Sets the nick of the user").arg(Arg::required("nick", Word)), nick),
+        (CommandSpec::new(".join", "Moves to the given room (the lobby if not given)").arg(Arg::optional("room", Word)), join),
+        (CommandSpec::new(".rooms", "Lists the rooms with the number of users in each"), rooms),
+        (CommandSpec::new(".users", "Lists the online users with their rooms"), users),
+        (CommandSpec::new(".admin", "Enables admin commands (requires the admin token)").arg(Arg::required("token", Word)), admin),
+        (CommandSpec::new(".who", "Lists all connections with their nick and uptime").admin(), who),
+        (CommandSpec::new(".kick", "Disconnects the user with the given nick").arg(Arg::required("nick", Word)).admin(), kick),
+        (CommandSpec::new(".ban", "Bans a nick or IP address, optionally for a duration (e.g. 30m, 2h, 7d)").arg(Arg::required("target", Word)).arg(Arg::optional("duration", Duration)).admin(), ban),
+        (CommandSpec::new(".motd", "Shows the message of the day"), motd),
+        (CommandSpec::new(".stats", "Reports server statistics").admin(), stats),
+        (CommandSpec::new(".reload", "Reloads the server configuration").admin(), reload_config),
+        (CommandSpec::new(".quota", "Shows the storage usage of the user (your own if not given) and of the server").arg(Arg::optional("user", Word)), quota),
+        (CommandSpec::new(".delete", "Deletes a stored file or unpacked archive (by the path reported on upload)").arg(Arg::required("path", Path)).admin(), delete),
+    ];
+    let mut registry = Registry::new();
+    for (spec, func) in commands {
+        registry.register(FnCommand::new(spec, func));
+    }
+    registry
 }
 
-lazy_static! {
-    static ref COMMANDS: HashMap<&'static str, Command> = {
-        #[rustfmt::skip]
-        let functions = [
-            (".help", Command { func: help, description: "Lists all commands".to_string() }),
-            (".file", Command { func: file, description: "Stores a generic file".to_string() }),
-            (".image", Command { func: image, description: "Stores an image file".to_string() }),
-            (".info", Command { func: info, description: "Logs an info text on server side".to_string() }),
-        ];
-        functions.into_iter().collect()
-    };
+fn help(session: &mut Session, _: &Args) -> ChatResult<String> {
+    let admin = session.admin;
+    let commands = session.state.commands.help(|spec| admin || !spec.admin);
+    let message = format!("Available commands:\n  {}", commands.join("\n  "));
+    Ok(message)
 }
 
-fn help(_: &mut TcpStream, _: &str, _config: &Config) -> Result<String, Box<dyn Error>> {
-    let commands = COMMANDS
-        .iter()
-        .map(|(name, command)| format!("  {}: {}", name, command.description))
-        .collect::<Vec<_>>()
-        .join("\n");
-    let message = format!("Available commands:\n{}", commands);
-    Ok(message)
+fn info(_: &mut Session, args: &Args) -> ChatResult<String> {
+    Ok(format!("Info received: {}", args.value("text")))
+}
+
+fn motd(session: &mut Session, _: &Args) -> ChatResult<String> {
+    Ok(session.config().motd.clone().unwrap_or_default())
+}
+
+fn file(session: &mut Session, args: &Args) -> ChatResult<String> {
+    let directory = session.config().file_dir.clone();
+    store_file(session, args, &directory, Storing::File)
+}
+
+fn image(session: &mut Session, args: &Args) -> ChatResult<String> {
+    let directory = session.config().image_dir.clone();
+    store_file(
+        session,
+        args,
+        &directory,
+        Storing::Processed(post_process_image),
+    )
+}
+
+fn archive(session: &mut Session, args: &Args) -> ChatResult<String> {
+    let directory = session.config().file_dir.clone();
+    store_file(session, args, &directory, Storing::Unpacked)
 }
 
-fn info(_: &mut TcpStream, input: &str, _config: &Config) -> Result<String, Box<dyn Error>> {
-    Ok(format!("Info received: {}", input))
+fn compress(session: &mut Session, args: &Args) -> ChatResult<String> {
+    let accepted = session.config().compression;
+    session.compression = args
+        .value("algorithms")
+        .split_whitespace()
+        .filter_map(|algorithm| algorithm.parse::<Compression>().ok())
+        .find(|algorithm| Some(*algorithm) == accepted);
+    let chosen = session
+        .compression
+        .map_or("none", |compression| compression.as_str());
+    Ok(format!("Compression: {}", chosen))
 }
 
-fn file(stream: &mut TcpStream, input: &str, config: &Config) -> Result<String, Box<dyn Error>> {
-    store_file(stream, input, &config.file_dir, None)
+fn delete(session: &mut Session, args: &Args) -> ChatResult<String> {
+    delete_file(args.value("path"), session)
 }
 
-fn image(stream: &mut TcpStream, input: &str, config: &Config) -> Result<String, Box<dyn Error>> {
-    store_file(stream, input, &config.image_dir, Some(post_process_image))
+fn reload_config(session: &mut Session, _: &Args) -> ChatResult<String> {
+    reload(&session.shared_config, &session.state)
 }
 
-pub(crate) fn handle_command(
-    stream: &mut TcpStream,
-    input: &str,
-    config: &Config,
-) -> Result<String, Box<dyn Error>> {
-    if !input.starts_with('.') {
-        log!("Message: {}", input.trim());
+fn nick(session: &mut Session, args: &Args) -> ChatResult<String> {
+    let nick = args.value("nick");
+    let mut connections = session.state.connections.lock().unwrap();
+    if connections
+        .iter()
+        .any(|(addr, connection)| *addr != session.peer && connection.nick.as_deref() == Some(nick))
+    {
+        return Err(ChatError::new(
+            ErrorCode::Conflict,
+            format!("Nick '{}' is already taken", nick),
+        ));
+    }
+    let Some(connection) = connections.get_mut(&session.peer) else {
+        return Err(ChatError::new(
+            ErrorCode::Internal,
+            "Connection not registered",
+        ));
+    };
+    let previous = connection
+        .nick
+        .replace(nick.to_string())
+        .unwrap_or_else(|| session.peer.to_string());
+    let room = connection.room.clone();
+    drop(connections);
+    if previous != nick {
+        announce(
+            session,
+            &room,
+            ServerEvent::NickChanged {
+                from: previous,
+                to: nick.to_string(),
+            },
+        );
+    }
+    send_session(session);
+    set_field("nick", nick);
+    info!("Nick set to {}", nick);
+    Ok(format!("Nick set to {}", nick))
+}
+
+pub(crate) fn handle_command(line: &str, session: &mut Session) -> ChatResult<String> {
+    let (name, input) = line.split_once(' ').unwrap_or((line, ""));
+    if !session.within_rate_limit() {
+        skip_upload(session, name, input)?;
+        let limit = session.config().rate_limit;
+        return Err(ChatError::new(
+            ErrorCode::RateLimited,
+            format!("Rate limit of {} messages per minute exceeded", limit),
+        ));
+    }
+    if !name.starts_with('.') {
+        session.state.metrics.messages.inc();
+        info!("Message: {}", line.trim());
+        let nick = session.nick();
+        let event = ServerEvent::Message {
+            from: session.display_name(),
+            text: line.trim().to_string(),
+        };
+        session
+            .state
+            .broadcast(&session.room(), session.peer, &event);
+        session.state.notify(Event::Message {
+            peer: session.peer,
+            nick,
+            text: line.trim().to_string(),
+        });
         return Ok("".to_string());
     }
 
-    let mut parts = input.splitn(2, ' ');
-    let command = parts.next().unwrap();
-    let input = parts.next().unwrap_or("");
-    if let Some(command) = COMMANDS.get(command) {
-        (command.func)(stream, input, config)
-    } else {
-        let commands = COMMANDS.keys().collect::<Vec<_>>();
-        Err(format!("Invalid command {}, valid are: {:?}", command, commands).into())
+    let state = session.state.clone();
+    let command = state.commands.get(name);
+    session
+        .state
+        .metrics
+        .commands
+        .inc(command.map_or("unknown", |command| command.spec().name));
+    let Some(command) = command else {
+        let admin = session.admin;
+        let commands = state
+            .commands
+            .specs()
+            .filter(|spec| admin || !spec.admin)
+            .map(|spec| spec.name)
+            .collect::<Vec<_>>();
+        return Err(ChatError::new(
+            ErrorCode::UnknownCommand,
+            format!("Invalid command {}, valid are: {:?}", name, commands),
+        ));
+    };
+    let spec = command.spec();
+    if spec.admin && !session.admin {
+        return Err(ChatError::new(
+            ErrorCode::Forbidden,
+            format!("Command {} requires admin privileges", name),
+        ));
+    }
+    let result = match spec.parse(input) {
+        Ok(args) => command.execute(session, &args),
+        Err(e) => skip_upload(session, name, input).and(Err(e)),
+    };
+    if spec.admin {
+        session.state.audit.admin(session, name, input, &result);
+    }
+    result
+}
+
+/// Skips the content following a rejected upload command, so that it is not mistaken for the next command.
+fn skip_upload(session: &mut Session, name: &str, input: &str) -> ChatResult<()> {
+    match name {
+        ".file" | ".image" | ".archive" => discard_upload(session, input),
+        _ => Ok(()),
     }
 }
@@ -2,9 +2,168 @@
 //!
 //! Please note: this is not a complete configuration of all the server settings,
 //! but only the settings that are passed into the processing threads and their functions.
+//!
+//! The configuration can be reloaded at runtime, hence the threads do not own a copy of it,
+//! but share a `SharedConfig` and take a snapshot of the current `Config` whenever they need it.
 
-#[derive(Clone)]
+use common::cli::{parse_args, CliArg, Settings};
+use common::compression::Compression;
+use image::ImageFormat;
+use std::error::Error;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+#[rustfmt::skip]
+const ARGS: [CliArg; 33] = [
+    CliArg::Config, CliArg::Host, CliArg::Port, CliArg::FileDir, CliArg::ImageDir, CliArg::AdminToken,
+    CliArg::BanFile, CliArg::MaxUploadSize, CliArg::RateLimit, CliArg::Motd, CliArg::ImageFormat,
+    CliArg::LogLevel, CliArg::LogFormat, CliArg::LogFile, CliArg::LogMaxSize, CliArg::MetricsAddr,
+    CliArg::AuditLog, CliArg::Compression, CliArg::AllowFileTypes, CliArg::DenyFileTypes,
+    CliArg::AllowImageTypes, CliArg::DenyImageTypes, CliArg::CatalogFile, CliArg::UserQuota,
+    CliArg::StorageQuota, CliArg::MinFreeSpace, CliArg::RetentionAge, CliArg::RetentionSize,
+    CliArg::RetentionInterval, CliArg::RetentionArchiveDir, CliArg::RetentionDryRun, CliArg::WsAddr,
+    CliArg::HttpAddr,
+];
+
+/// Resolves the settings from the command line, environment and configuration file.
+///
+/// Called on startup and on every reload (the sources are re-read every time).
+pub fn load_settings() -> Result<Settings, Box<dyn Error>> {
+    parse_args("server", &ARGS)
+}
+
+/// Source of a fresh configuration on reload.
+pub type ConfigLoader = Box<dyn Fn() -> Result<Config, Box<dyn Error>> + Send + Sync>;
+
+#[derive(Clone, Debug)]
 pub struct Config {
+    pub(crate) config_file: Option<String>,
+    pub(crate) address: String,
+    pub(crate) metrics_address: Option<String>,
+    /// Address of the WebSocket listener, disabled if `None`.
+    pub(crate) ws_address: Option<String>,
+    /// Address of the HTTP API of the storage, disabled if `None`.
+    pub(crate) http_address: Option<String>,
     pub(crate) file_dir: String,
     pub(crate) image_dir: String,
+    pub(crate) admin_token: Option<String>,
+    pub(crate) ban_file: String,
+    pub(crate) max_upload_size: u64,
+    pub(crate) rate_limit: u32,
+    pub(crate) motd: Option<String>,
+    pub(crate) image_format: ImageFormat,
+    pub(crate) audit_log: Option<String>,
+    /// Compression accepted for the transfers, `None` if disabled.
+    pub(crate) compression: Option<Compression>,
+    pub(crate) file_types: TypeFilter,
+    pub(crate) image_types: TypeFilter,
+    pub(crate) catalog_file: String,
+    /// Quotas in bytes, 0 for unlimited.
+    pub(crate) user_quota: u64,
+    pub(crate) storage_quota: u64,
+    pub(crate) min_free_space: u64,
+    pub(crate) retention: Retention,
+}
+
+/// Retention policy of the stored uploads, see `retention`.
+#[derive(Clone, Debug)]
+pub(crate) struct Retention {
+    pub(crate) max_age: Option<Duration>,
+    /// Size of the storage beyond which the oldest uploads are removed, 0 for unlimited.
+    pub(crate) max_size: u64,
+    pub(crate) interval: Duration,
+    pub(crate) archive_dir: Option<String>,
+    pub(crate) dry_run: bool,
+}
+
+impl Retention {
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.max_age.is_some() || self.max_size > 0
+    }
+}
+
+/// Allow and deny lists of the content types of an upload command.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TypeFilter {
+    /// Allowed types, all (but the denied ones) if empty.
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl TypeFilter {
+    pub(crate) fn allows(&self, name: &str) -> bool {
+        !self.deny.iter().any(|denied| denied == name)
+            && (self.allow.is_empty() || self.allow.iter().any(|allowed| allowed == name))
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config::from_settings(Settings::default()).expect("Default settings must be valid")
+    }
+}
+
+impl Config {
+    pub fn from_settings(settings: Settings) -> Result<Self, Box<dyn Error>> {
+        let image_format = ImageFormat::from_extension(&settings.image_format)
+            .filter(|format| format.writing_enabled())
+            .ok_or(format!(
+                "Unsupported image format '{}'",
+                settings.image_format
+            ))?;
+        Ok(Config {
+            config_file: settings.config,
+            address: format!("{}:{}", settings.host, settings.port),
+            metrics_address: settings.metrics_addr,
+            ws_address: settings.ws_addr,
+            http_address: settings.http_addr,
+            file_dir: settings.file_dir,
+            image_dir: settings.image_dir,
+            admin_token: settings.admin_token,
+            ban_file: settings.ban_file,
+            max_upload_size: settings.max_upload_size,
+            rate_limit: settings.rate_limit,
+            motd: settings.motd,
+            image_format,
+            audit_log: settings.audit_log,
+            compression: settings.compression,
+            file_types: TypeFilter {
+                allow: settings.allow_file_types,
+                deny: settings.deny_file_types,
+            },
+            image_types: TypeFilter {
+                allow: settings.allow_image_types,
+                deny: settings.deny_image_types,
+            },
+            catalog_file: settings.catalog_file,
+            user_quota: settings.user_quota,
+            storage_quota: settings.storage_quota,
+            min_free_space: settings.min_free_space,
+            retention: Retention {
+                max_age: settings.retention_age,
+                max_size: settings.retention_size,
+                interval: settings.retention_interval,
+                archive_dir: settings.retention_archive_dir,
+                dry_run: settings.retention_dry_run,
+            },
+        })
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct SharedConfig(Arc<RwLock<Arc<Config>>>);
+
+impl SharedConfig {
+    pub(crate) fn new(config: Config) -> Self {
+        SharedConfig(Arc::new(RwLock::new(Arc::new(config))))
+    }
+
+    /// Returns a snapshot of the current configuration (not affected by later reloads).
+    pub(crate) fn get(&self) -> Arc<Config> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub(crate) fn replace(&self, config: Config) {
+        *self.0.write().unwrap() = Arc::new(config);
+    }
 }
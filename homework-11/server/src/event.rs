@@ -0,0 +1,31 @@
+//! Server events reported to the hooks registered by the embedding application.
+
+use std::net::SocketAddr;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Event {
+    /// A client connected (and was not rejected as banned).
+    Connected { peer: SocketAddr },
+    /// The connection of a client was closed.
+    Disconnected { peer: SocketAddr },
+    /// A client sent a plain (non-command) message.
+    Message {
+        peer: SocketAddr,
+        nick: Option<String>,
+        text: String,
+    },
+    /// A client upload was stored (`kind` being `file`, `image` or `archive`).
+    Upload {
+        peer: SocketAddr,
+        nick: Option<String>,
+        kind: &'static str,
+        path: String,
+        size: usize,
+        /// ID to download the upload by (`.get`), `None` if it could not be recorded.
+        id: Option<String>,
+    },
+}
+
+/// Callback invoked (on the thread handling the connection) for every server event.
+pub type Hook = Box<dyn Fn(&Event) + Send + Sync>;
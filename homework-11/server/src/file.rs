@@ -1,22 +1,106 @@
 //! File handling functions.
 //!
 //! This module contains functions for handling the file storage on the server
-//! (file name deduction, receiving files, post-processing images).
+//! (file name deduction, receiving files, post-processing images, unpacking archives, downloads).
 
+use crate::catalog::Entry;
+use crate::config::{Config, TypeFilter};
+use crate::event::Event;
+use crate::quota;
+use crate::room::announce;
+use crate::state::Session;
+use crate::stream_handler::ClientStream;
 use chrono::{SecondsFormat, Utc};
-use common::log;
-use common::util::flush;
-use image::{ImageFormat, ImageReader};
+use common::command::Args;
+use common::compression::Compression;
+use common::content_type::{self, ContentType, SNIFF_LENGTH};
+use common::download::Download;
+use common::error::{ChatError, ChatResult, ErrorCode};
+use common::event::ServerEvent;
+use common::util::{format_size, parse_size};
+use common::{error, info, warn};
+use image::ImageReader;
 use regex::Regex;
-use std::error::Error;
-use std::fs::File;
-use std::io::{BufReader, Cursor, Read, Write};
-use std::net::TcpStream;
-use std::path::Path;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant};
+use tar::{Archive, Builder, EntryType};
 
-fn get_target_file(filename: &str, directory: &str) -> Result<String, Box<dyn Error>> {
+/// Uploads are read in chunks of this size.
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Interval of logging the progress of an upload, so only the large (or slow) ones are logged.
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Post-processes the received content, returning the new content, target file and a conversion flag.
+pub(crate) type PostProcessor = fn(&[u8], &str, &Config) -> ChatResult<(Vec<u8>, String, bool)>;
+
+/// How the received content is stored.
+#[derive(Clone, Copy)]
+pub(crate) enum Storing {
+    /// As is, into a single file.
+    File,
+    /// Post-processed (e.g. an image converted) into a single file.
+    Processed(PostProcessor),
+    /// Unpacked (being a tar archive) into a directory of its own.
+    Unpacked,
+}
+
+impl Storing {
+    fn kind(&self) -> &'static str {
+        match self {
+            Storing::File => "file",
+            Storing::Processed(_) => "image",
+            Storing::Unpacked => "archive",
+        }
+    }
+}
+
+/// Outcome of a successfully stored upload.
+pub(crate) struct StoredFile {
+    /// The stored file, or the directory an archive was unpacked into.
+    pub(crate) path: String,
+    pub(crate) size: usize,
+    pub(crate) sha256: String,
+    pub(crate) converted: bool,
+    /// Number of the stored files (more than one for an archive).
+    pub(crate) files: usize,
+    /// Type of the received content (as detected, not as named by the client).
+    pub(crate) content_type: &'static str,
+    /// ID in the catalog, `None` if the upload could not be recorded.
+    pub(crate) id: Option<String>,
+}
+
+fn sha256_hex(content: &[u8]) -> String {
+    Sha256::digest(content)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Refuses the names that would break the line-based catalog, events or HTTP headers (e.g. containing newlines).
+fn check_filename(filename: &str) -> ChatResult<()> {
+    if filename.is_empty() || filename.chars().any(char::is_control) {
+        return Err(ChatError::new(
+            ErrorCode::InvalidArgument,
+            format!("Invalid file name '{}'", filename.escape_debug()),
+        ));
+    }
+    Ok(())
+}
+
+fn get_target_file(filename: &str, directory: &str) -> ChatResult<String> {
     let path = Path::new(filename);
-    let filename = path.file_name().unwrap().to_str().unwrap();
+    let filename = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| {
+            ChatError::new(
+                ErrorCode::InvalidArgument,
+                format!("Invalid file name '{}'", filename),
+            )
+        })?;
     // We are not using a plain timestamp here as it's fairly unusable for the naked eye
     // Instead, we use standard ISO 8601 format typically used throughout the industry
     // But: we replace colons with dashes
@@ -31,81 +115,726 @@ fn get_target_file(filename: &str, directory: &str) -> Result<String, Box<dyn Er
 }
 
 pub(crate) fn store_file(
-    stream: &mut TcpStream,
-    input: &str,
+    session: &mut Session,
+    args: &Args,
     directory: &str,
-    post_processor: Option<fn(&[u8], &str) -> Result<(Vec<u8>, String, bool), Box<dyn Error>>>,
-) -> Result<String, Box<dyn Error>> {
-    let mut parts = input.splitn(2, ' ');
-    let size = parts.next().unwrap().parse::<usize>()?;
-    let filename = parts.next().unwrap_or("");
-    log!("Receiving {} (size {})", filename, size);
-    receive_file(stream, filename, size, directory, post_processor)
+    storing: Storing,
+) -> ChatResult<String> {
+    // the same parsing as by the validation of the argument (accepting e.g. `10K`)
+    let wire_size = args.number("size").ok_or_else(|| {
+        ChatError::new(
+            ErrorCode::InvalidArgument,
+            format!("Invalid size '{}'", args.value("size")),
+        )
+    })? as usize;
+    let filename = args.value("name");
+    // the limit applies to the original content, not to the compressed one
+    let (compression, size) = match args.get("compression") {
+        Some(compression) => {
+            let Some(size) = args.number("original_size") else {
+                discard_content(&mut session.stream, wire_size)?;
+                return Err(ChatError::new(
+                    ErrorCode::InvalidArgument,
+                    "The original size of compressed content is missing",
+                ));
+            };
+            (
+                Some(negotiated(session, compression, wire_size)?),
+                size as usize,
+            )
+        }
+        None => (None, wire_size),
+    };
+    let upload = Upload {
+        filename,
+        wire_size,
+        size,
+        compression,
+    };
+    let stored = store_upload(session, &upload, directory, storing)?;
+    let as_id = stored
+        .id
+        .as_ref()
+        .map_or(String::new(), |id| format!(" as #{}", id));
+    if let Storing::Unpacked = storing {
+        Ok(format!(
+            "Unpacked {} files ({} bytes) into {}{}",
+            stored.files, stored.size, stored.path, as_id
+        ))
+    } else if stored.converted {
+        Ok(format!(
+            "Received {} bytes ({}) and converted to {} bytes in {}{}",
+            size, stored.content_type, stored.size, stored.path, as_id
+        ))
+    } else {
+        Ok(format!(
+            "Stored {} bytes ({}) in {}{}",
+            size, stored.content_type, stored.path, as_id
+        ))
+    }
+}
+
+/// Receives the announced upload from the session stream, stores it and records it in the catalog.
+///
+/// The upload is checked against the limits first, its content is skipped if refused.
+pub(crate) fn store_upload(
+    session: &mut Session,
+    upload: &Upload,
+    directory: &str,
+    storing: Storing,
+) -> ChatResult<StoredFile> {
+    let Upload {
+        filename,
+        wire_size,
+        size,
+        compression,
+    } = *upload;
+    let config = session.config();
+    let started_at = Instant::now();
+    // the space stays reserved until the upload is recorded in the catalog
+    let reservation = if let Err(e) = check_filename(filename) {
+        Err(e)
+    } else if size.max(wire_size) as u64 > config.max_upload_size {
+        // the received (compressed) content is limited as well, it is read into memory as a whole
+        Err(ChatError::new(
+            ErrorCode::TooLarge,
+            format!(
+                "File {} of {} bytes exceeds the upload limit of {} bytes",
+                filename,
+                size.max(wire_size),
+                config.max_upload_size
+            ),
+        ))
+    } else {
+        quota::reserve(session, directory, size as u64)
+    };
+    let result = if let Err(e) = &reservation {
+        discard_content(&mut session.stream, wire_size)?;
+        Err(e.clone())
+    } else {
+        match compression {
+            Some(compression) => info!(
+                "Receiving {} (size {}, {} compressed to {})",
+                filename, size, compression, wire_size
+            ),
+            None => info!("Receiving {} (size {})", filename, size),
+        }
+        receive_file(&mut session.stream, upload, directory, storing, &config)
+    };
+    let kind = storing.kind();
+    session
+        .state
+        .audit
+        .upload(session, kind, filename, size, &result);
+
+    let mut stored = result?;
+    let owner = quota::owner(session);
+    let recorded = session.state.catalog.lock().unwrap().add(
+        owner,
+        kind,
+        stored.size as u64,
+        stored.path.clone(),
+    );
+    stored.id = match recorded {
+        Ok(id) => {
+            let text = format!(
+                "{} shared {} ({}) as #{}",
+                session.display_name(),
+                filename,
+                format_size(size as u64),
+                id
+            );
+            announce(session, &session.room(), ServerEvent::Notice(text));
+            Some(id)
+        }
+        Err(e) => {
+            error!("Failed to record {} in the catalog: {}", stored.path, e);
+            None
+        }
+    };
+    let metrics = &session.state.metrics;
+    metrics.uploads.inc();
+    metrics.upload_bytes.add(size as u64);
+    metrics.upload_duration.observe(started_at.elapsed());
+    if stored.converted {
+        metrics.image_conversions.inc();
+    }
+    session.state.notify(Event::Upload {
+        peer: session.peer,
+        nick: session.nick(),
+        kind,
+        path: stored.path.clone(),
+        size,
+        id: stored.id.clone(),
+    });
+    Ok(stored)
+}
+
+/// Checks that the compression of an upload was negotiated, skipping the content if not.
+fn negotiated(
+    session: &mut Session,
+    compression: &str,
+    wire_size: usize,
+) -> ChatResult<Compression> {
+    match compression.parse::<Compression>() {
+        Ok(compression) if session.compression == Some(compression) => Ok(compression),
+        _ => {
+            discard_content(&mut session.stream, wire_size)?;
+            Err(ChatError::new(
+                ErrorCode::InvalidArgument,
+                format!(
+                    "Compression '{}' was not negotiated (see .compress)",
+                    compression
+                ),
+            ))
+        }
+    }
+}
+
+/// Skips the content of a rejected upload, so that it is not mistaken for the next command.
+pub(crate) fn discard_upload(session: &mut Session, input: &str) -> ChatResult<()> {
+    let size = input.split(' ').next().unwrap();
+    let size = parse_size(size).map_err(|e| {
+        ChatError::new(
+            ErrorCode::InvalidArgument,
+            format!("Invalid size '{}': {}", size, e),
+        )
+    })?;
+    discard_content(&mut session.stream, size as usize)
+}
+
+fn discard_content(stream: &mut ClientStream, size: usize) -> ChatResult<()> {
+    let copied = io::copy(&mut stream.take(size as u64), &mut io::sink())?;
+    if copied < size as u64 {
+        return Err(ChatError::connection(
+            "Connection closed while skipping the upload",
+        ));
+    }
+    Ok(())
+}
+
+/// Upload announced by the command line (or by an HTTP request).
+#[derive(Clone, Copy)]
+pub(crate) struct Upload<'a> {
+    pub(crate) filename: &'a str,
+    /// Number of the bytes following the command line.
+    pub(crate) wire_size: usize,
+    /// Size of the original (decompressed) content.
+    pub(crate) size: usize,
+    pub(crate) compression: Option<Compression>,
 }
 
 fn receive_file(
-    stream: &mut TcpStream,
-    filename: &str,
-    size: usize,
+    stream: &mut ClientStream,
+    upload: &Upload,
     directory: &str,
-    post_processor: Option<fn(&[u8], &str) -> Result<(Vec<u8>, String, bool), Box<dyn Error>>>,
-) -> Result<String, Box<dyn Error>> {
-    let mut reader = BufReader::new(stream);
-    let mut buffer = vec![0; size];
-    reader.read_exact(&mut buffer)?;
+    storing: Storing,
+    config: &Config,
+) -> ChatResult<StoredFile> {
+    let filename = upload.filename;
+    let mut buffer = vec![0; upload.wire_size];
+    // the type is checked right after the first chunk, so that a refused upload is not read in full
+    let (head, rest) = buffer.split_at_mut(upload.wire_size.min(CHUNK_SIZE));
+    stream.read_exact(head)?;
+    let content_type = sniff(head, upload.compression);
+    if let Err(e) = check_type(storing, content_type, filename, config) {
+        discard_content(stream, rest.len())?;
+        return Err(e);
+    }
+    let started_at = Instant::now();
+    let mut logged_at = started_at;
+    let mut received = head.len();
+    for chunk in rest.chunks_mut(CHUNK_SIZE) {
+        stream.read_exact(chunk)?;
+        received += chunk.len();
+        if logged_at.elapsed() >= PROGRESS_LOG_INTERVAL {
+            logged_at = Instant::now();
+            let throughput = received as f64 / started_at.elapsed().as_secs_f64();
+            info!(
+                "Received {}% of {} ({} of {}, {}/s)",
+                received * 100 / upload.wire_size,
+                filename,
+                format_size(received as u64),
+                format_size(upload.wire_size as u64),
+                format_size(throughput as u64)
+            );
+        }
+    }
+    if let Some(compression) = upload.compression {
+        buffer = decompress(compression, &buffer, upload.size, filename)?;
+    }
+    let sha256 = sha256_hex(&buffer);
 
     let target_file = get_target_file(filename, directory)?;
-    if let Some(processor) = post_processor {
-        let (target_buffer, new_target_file, converted) = processor(&buffer, &target_file)?;
-        let mut target_file = File::create(new_target_file.clone())?;
-        target_file.write_all(&target_buffer)?;
-        if converted {
-            // let's get new file size
-            let new_size = target_buffer.len();
-            Ok(format!(
-                "Received {} bytes and converted to {} bytes in {}",
-                size, new_size, new_target_file
+    let (content, path, converted) = match storing {
+        Storing::File => (buffer, target_file, false),
+        Storing::Processed(processor) => processor(&buffer, &target_file, config)?,
+        Storing::Unpacked => {
+            let (path, files, size) = unpack_archive(&buffer, &target_file, &config.file_types)?;
+            return Ok(StoredFile {
+                path,
+                size,
+                sha256,
+                converted: false,
+                files,
+                content_type: content_type.name,
+                id: None,
+            });
+        }
+    };
+    let (mut file, path) = create_unique_file(&path)?;
+    file.write_all(&content)?;
+    Ok(StoredFile {
+        path,
+        size: content.len(),
+        sha256,
+        converted,
+        files: 1,
+        content_type: content_type.name,
+        id: None,
+    })
+}
+
+/// Detects the type of the content from its head (decompressing as much as possible of it if needed).
+fn sniff(head: &[u8], compression: Option<Compression>) -> ContentType {
+    match compression {
+        Some(compression) => {
+            let mut decompressed = Vec::new();
+            // the head is just the start of the compressed stream, the error at its end is expected
+            let _ = compression
+                .decoder(head)
+                .take(SNIFF_LENGTH as u64)
+                .read_to_end(&mut decompressed);
+            content_type::detect(&decompressed)
+        }
+        None => content_type::detect(head),
+    }
+}
+
+fn check_type(
+    storing: Storing,
+    content_type: ContentType,
+    filename: &str,
+    config: &Config,
+) -> ChatResult<()> {
+    let allowed = match storing {
+        Storing::File => config.file_types.allows(content_type.name),
+        Storing::Processed(_) => config.image_types.allows(content_type.name),
+        // the files in the archive are checked when unpacking
+        Storing::Unpacked => content_type.name == "tar",
+    };
+    if allowed {
+        return Ok(());
+    }
+    Err(ChatError::new(
+        ErrorCode::UnsupportedType,
+        format!(
+            "Content of {} ({}) is not allowed for .{}",
+            filename,
+            content_type.name,
+            storing.kind()
+        ),
+    ))
+}
+
+/// Unpacks the tar archive into a new directory, returning the directory, the number of the files
+/// and their total size.
+///
+/// Only the regular files and directories with plain relative paths are unpacked, so that nothing
+/// can be written outside of the directory. Nothing is left behind if the archive is rejected.
+fn unpack_archive(
+    content: &[u8],
+    directory: &str,
+    types: &TypeFilter,
+) -> ChatResult<(String, usize, usize)> {
+    let directory = create_unique_directory(directory)?;
+    match unpack_entries(content, Path::new(&directory), types) {
+        Ok((files, size)) => Ok((directory, files, size)),
+        Err(e) => {
+            let _ = fs::remove_dir_all(&directory);
+            Err(e)
+        }
+    }
+}
+
+/// Creates the directory, adding a numeric suffix if it exists already (e.g. uploaded within the same second).
+fn create_unique_directory(directory: &str) -> ChatResult<String> {
+    let mut candidate = directory.to_string();
+    for suffix in 2.. {
+        match fs::create_dir(&candidate) {
+            Ok(()) => break,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                candidate = format!("{}-{}", directory, suffix);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(candidate)
+}
+
+/// Creates the file, adding a numeric suffix to its stem if it exists already (e.g. uploaded within the same second).
+fn create_unique_file(path: &str) -> ChatResult<(File, String)> {
+    let stem = Path::new(path).with_extension("");
+    let extension = Path::new(path)
+        .extension()
+        .map_or(String::new(), |extension| {
+            format!(".{}", extension.to_string_lossy())
+        });
+    let mut candidate = path.to_string();
+    let mut suffix = 1;
+    loop {
+        match File::options()
+            .write(true)
+            .create_new(true)
+            .open(&candidate)
+        {
+            Ok(file) => return Ok((file, candidate)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                suffix += 1;
+                candidate = format!("{}-{}{}", stem.display(), suffix, extension);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+fn unpack_entries(
+    content: &[u8],
+    directory: &Path,
+    types: &TypeFilter,
+) -> ChatResult<(usize, usize)> {
+    let invalid =
+        |e: io::Error| ChatError::new(ErrorCode::DecodeFailed, format!("Invalid archive: {}", e));
+    let (mut files, mut size) = (0, 0);
+    for entry in Archive::new(content).entries().map_err(invalid)? {
+        let mut entry = entry.map_err(invalid)?;
+        let path = entry.path().map_err(invalid)?.into_owned();
+        let relative = safe_relative_path(&path).ok_or_else(|| {
+            ChatError::new(
+                ErrorCode::InvalidArgument,
+                format!("Unsafe path {} in the archive", path.display()),
+            )
+        })?;
+        let target = directory.join(relative);
+        match entry.header().entry_type() {
+            EntryType::Directory => fs::create_dir_all(&target)?,
+            EntryType::Regular | EntryType::Continuous => {
+                let mut content = Vec::new();
+                entry.read_to_end(&mut content).map_err(invalid)?;
+                let content_type = content_type::detect(&content);
+                if !types.allows(content_type.name) {
+                    return Err(ChatError::new(
+                        ErrorCode::UnsupportedType,
+                        format!(
+                            "Content of {} ({}) is not allowed for .file",
+                            path.display(),
+                            content_type.name
+                        ),
+                    ));
+                }
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                File::create(&target)?.write_all(&content)?;
+                size += content.len();
+                files += 1;
+            }
+            other => warn!(
+                "Skipping {} of type {:?} in the archive",
+                path.display(),
+                other
+            ),
+        }
+    }
+    Ok((files, size))
+}
+
+/// The path made of plain names only (no root, no `..`), `None` if there is anything else.
+fn safe_relative_path(path: &Path) -> Option<PathBuf> {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => relative.push(name),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    (!relative.as_os_str().is_empty()).then_some(relative)
+}
+
+/// Decompresses the received content, which has to match the announced size exactly.
+fn decompress(
+    compression: Compression,
+    content: &[u8],
+    size: usize,
+    filename: &str,
+) -> ChatResult<Vec<u8>> {
+    compression.decompress(content, size).map_err(|e| {
+        ChatError::new(
+            ErrorCode::DecodeFailed,
+            format!("Failed to decompress {}: {}", filename, e),
+        )
+    })
+}
+
+/// Sends the upload with the given ID right after the response (compressed if negotiated), see `common::download`.
+pub(crate) fn get_file(session: &mut Session, args: &Args) -> ChatResult<String> {
+    let id = args.value("id");
+    let entry = session
+        .state
+        .catalog
+        .lock()
+        .unwrap()
+        .find(id)
+        .cloned()
+        .ok_or_else(|| ChatError::new(ErrorCode::NotFound, format!("No upload {} found", id)))?;
+    let (name, content) = upload_content(&entry)?;
+    let size = content.len();
+    let compressed = session
+        .compression
+        .and_then(|compression| Some((compression, compression.compress_if_smaller(&content)?)));
+    let (compression, content) = match compressed {
+        Some((compression, compressed)) => (Some(compression), compressed),
+        None => (None, content),
+    };
+    let download = Download {
+        name,
+        wire_size: content.len(),
+        compression,
+        size,
+    };
+    session
+        .state
+        .audit
+        .download(session, &entry.id, &entry.path, size);
+    let metrics = &session.state.metrics;
+    metrics.downloads.inc();
+    metrics.download_bytes.add(size as u64);
+    session.attachment = Some(content);
+    Ok(download.to_wire())
+}
+
+/// Name and content of the upload as downloaded, an unpacked archive being packed into a tar archive again.
+pub(crate) fn upload_content(entry: &Entry) -> ChatResult<(String, Vec<u8>)> {
+    let unreadable =
+        |e: io::Error| ChatError::new(ErrorCode::Io, format!("Cannot read #{}: {}", entry.id, e));
+    let name = entry.name();
+    match entry.kind.as_str() {
+        "archive" => {
+            let mut builder = Builder::new(Vec::new());
+            append_sorted(&mut builder, Path::new(&name), Path::new(&entry.path))
+                .map_err(unreadable)?;
+            Ok((
+                format!("{}.tar", name),
+                builder.into_inner().map_err(unreadable)?,
             ))
+        }
+        _ => Ok((name, fs::read(&entry.path).map_err(unreadable)?)),
+    }
+}
+
+/// Appends the directory with all its content, in the order of the names (so that the same directory
+/// is always packed the same way, e.g. for the HTTP ranges).
+fn append_sorted(builder: &mut Builder<Vec<u8>>, name: &Path, path: &Path) -> io::Result<()> {
+    builder.append_dir(name, path)?;
+    let mut children = fs::read_dir(path)?.collect::<io::Result<Vec<_>>>()?;
+    children.sort_by_key(|child| child.file_name());
+    for child in children {
+        let child_name = name.join(child.file_name());
+        if child.file_type()?.is_dir() {
+            append_sorted(builder, &child_name, &child.path())?;
         } else {
-            Ok(format!("Stored {} bytes in {}", size, new_target_file))
+            builder.append_path_with_name(child.path(), &child_name)?;
         }
-    } else {
-        let mut file = File::create(target_file.clone())?;
-        file.write_all(&buffer)?;
-        Ok(format!("Stored {} bytes in {}", size, target_file))
     }
+    Ok(())
+}
+
+/// Deletes a stored file (or an unpacked archive), the path has to point into one of the storage directories.
+pub(crate) fn delete_file(path: &str, session: &Session) -> ChatResult<String> {
+    let config = session.config();
+    let canonical = fs::canonicalize(path).map_err(|e| {
+        ChatError::new(
+            ErrorCode::NotFound,
+            format!("Cannot delete {}: {}", path, e),
+        )
+    })?;
+    let in_storage = [&config.file_dir, &config.image_dir]
+        .iter()
+        .any(|directory| {
+            fs::canonicalize(directory)
+                .map(|directory| canonical.starts_with(directory))
+                .unwrap_or(false)
+        });
+    // only the directories of the unpacked archives, not e.g. a storage directory itself
+    let archive = canonical.is_dir()
+        && session
+            .state
+            .catalog
+            .lock()
+            .unwrap()
+            .entries()
+            .iter()
+            .any(|entry| {
+                entry.kind == "archive"
+                    && fs::canonicalize(&entry.path).ok().as_ref() == Some(&canonical)
+            });
+    if !in_storage || !(canonical.is_file() || archive) {
+        return Err(ChatError::new(
+            ErrorCode::NotFound,
+            format!("{} is not a stored file", path),
+        ));
+    }
+
+    let (size, sha256) = match archive {
+        true => (quota::directory_size(&canonical), None),
+        false => {
+            let content = fs::read(&canonical)?;
+            (content.len() as u64, Some(sha256_hex(&content)))
+        }
+    };
+    if let Err(e) = session.state.catalog.lock().unwrap().remove(&canonical) {
+        error!("Failed to remove {} from the catalog: {}", path, e);
+    }
+    match archive {
+        true => fs::remove_dir_all(&canonical)?,
+        false => fs::remove_file(&canonical)?,
+    }
+    info!("Deleted {}", path);
+    session
+        .state
+        .audit
+        .delete(session, path, size, sha256.as_deref(), "admin");
+    Ok(format!("Deleted {} ({} bytes)", path, size))
 }
 
 pub(crate) fn post_process_image(
     buffer: &[u8],
     target_file: &str,
-) -> Result<(Vec<u8>, String, bool), Box<dyn Error>> {
+    config: &Config,
+) -> ChatResult<(Vec<u8>, String, bool)> {
     let img = match ImageReader::new(Cursor::new(buffer))
         .with_guessed_format()?
         .decode()
     {
         Ok(img) => img,
-        Err(_) => return Err("Failed to decode image".into()),
+        Err(_) => {
+            return Err(ChatError::new(
+                ErrorCode::DecodeFailed,
+                "Failed to decode image",
+            ))
+        }
     };
 
     let format = ImageReader::new(Cursor::new(buffer))
         .with_guessed_format()?
         .format()
-        .ok_or("Unknown image format")?;
+        .ok_or_else(|| ChatError::new(ErrorCode::DecodeFailed, "Unknown image format"))?;
 
-    if format == ImageFormat::Png {
+    let target_format = config.image_format;
+    if format == target_format {
         return Ok((buffer.to_vec(), target_file.to_string(), false));
     }
 
-    log!("Converting from {:?}", format);
+    info!("Converting from {:?} to {:?}", format, target_format);
 
-    let png_target_file = target_file.rsplit_once('.').map_or_else(
-        || format!("{}.png", target_file),
-        |(base, _)| format!("{}.png", base),
+    let extension = target_format.extensions_str()[0];
+    let converted_target_file = target_file.rsplit_once('.').map_or_else(
+        || format!("{}.{}", target_file, extension),
+        |(base, _)| format!("{}.{}", base, extension),
     );
 
-    let mut png_buffer = Vec::new();
-    img.write_to(&mut Cursor::new(&mut png_buffer), ImageFormat::Png)?;
+    let mut converted_buffer = Vec::new();
+    img.write_to(&mut Cursor::new(&mut converted_buffer), target_format)
+        .map_err(|e| {
+            ChatError::new(
+                ErrorCode::Internal,
+                format!("Failed to convert image: {}", e),
+            )
+        })?;
+
+    Ok((converted_buffer, converted_target_file, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ban::BanList;
+    use crate::catalog::Catalog;
+    use crate::config::SharedConfig;
+    use crate::state::ServerState;
+    use common::command::Registry;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+
+    fn safe(path: &str) -> Option<PathBuf> {
+        safe_relative_path(Path::new(path))
+    }
 
-    Ok((png_buffer, png_target_file, true))
+    #[test]
+    fn safe_relative_path_keeps_plain_names() {
+        assert_eq!(safe("a.txt"), Some(PathBuf::from("a.txt")));
+        assert_eq!(safe("dir/sub/a.txt"), Some(PathBuf::from("dir/sub/a.txt")));
+        assert_eq!(safe("./dir/./a.txt"), Some(PathBuf::from("dir/a.txt")));
+        assert_eq!(safe("dir/"), Some(PathBuf::from("dir")));
+    }
+
+    #[test]
+    fn safe_relative_path_refuses_escapes() {
+        assert_eq!(safe("../a.txt"), None);
+        assert_eq!(safe("dir/../../a.txt"), None);
+        assert_eq!(safe("dir/.."), None);
+        assert_eq!(safe("/etc/passwd"), None);
+        assert_eq!(safe(""), None);
+        assert_eq!(safe("."), None);
+    }
+
+    #[test]
+    fn check_filename_refuses_control_characters() {
+        assert!(check_filename("report v2.pdf").is_ok());
+        assert!(check_filename("été.txt").is_ok());
+        assert!(check_filename("").is_err());
+        assert!(check_filename("x\n1 aaaa me file 1 config.toml").is_err());
+        assert!(check_filename("x\r\n").is_err());
+        assert!(check_filename("tab\there").is_err());
+        assert!(check_filename("del\u{7f}").is_err());
+    }
+
+    #[test]
+    fn reservation_is_released_when_upload_fails() {
+        let storage = std::env::temp_dir().join(format!("server-file-{}", std::process::id()));
+        let config = Config {
+            file_dir: storage.join("files").to_string_lossy().to_string(),
+            catalog_file: storage.join("catalog.txt").to_string_lossy().to_string(),
+            user_quota: 100,
+            ..Config::default()
+        };
+        let state = Arc::new(ServerState::new(
+            BanList::load(&config.ban_file).unwrap(),
+            Catalog::load(&config.catalog_file).unwrap(),
+            Registry::new(),
+            Vec::new(),
+            None,
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, peer) = listener.accept().unwrap();
+        let directory = config.file_dir.clone();
+        let mut session =
+            Session::new(peer, stream, SharedConfig::new(config), state.clone()).unwrap();
+
+        // the connection is closed in the middle of the content
+        client.write_all(b"hello").unwrap();
+        drop(client);
+        let upload = Upload {
+            filename: "a.txt",
+            wire_size: 10,
+            size: 10,
+            compression: None,
+        };
+        let result = store_upload(&mut session, &upload, &directory, Storing::File);
+        assert!(result.is_err());
+        assert_eq!(state.catalog.lock().unwrap().reserved(None), 0);
+        let _ = fs::remove_dir_all(&storage);
+    }
 }
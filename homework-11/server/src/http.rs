@@ -0,0 +1,658 @@
+//! HTTP API of the storage (e.g. for CI jobs and other tools not speaking the chat protocol).
+//!
+//! When the HTTP address is configured, a dedicated thread accepts the connections (one request each,
+//! up to `MAX_ACTIVE_REQUESTS` at once) and serves:
+//!
+//! - `GET /health` - the server status
+//! - `GET /files` - the catalog of the uploads (as JSON)
+//! - `GET /files/{id}` and `GET /images/{id}` - the content of the upload (supporting single byte ranges),
+//!   streamed right from the storage
+//! - `POST /files?name=<name>` - an upload, stored the same way as by `.file`
+//!
+//! The uploads are shared with the TCP commands: they are identified by the same IDs (see `catalog`),
+//! the HTTP uploads are subject to the same limits and quotas and announced in the lobby.
+
+use crate::config::SharedConfig;
+use crate::file::{store_upload, upload_content, Storing, Upload};
+use crate::server::is_banned;
+use crate::state::{ServerState, Session};
+use chrono::DateTime;
+use common::content_type::{self, SNIFF_LENGTH};
+use common::error::{ChatError, ChatResult, ErrorCode};
+use common::logging::set_field;
+use common::{error, info, warn};
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Time limit of reading the request (and of every chunk of an upload).
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// Limit of the request line and the headers together.
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+/// Limit of the requests served at once (each by a thread of its own), the others are refused right away.
+const MAX_ACTIVE_REQUESTS: usize = 64;
+
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn query(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(parameter, _)| parameter == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Body,
+}
+
+enum Body {
+    Bytes(Vec<u8>),
+    /// The given number of bytes of the file from its current position, streamed as they are read.
+    File(File, u64),
+}
+
+impl Body {
+    fn len(&self) -> u64 {
+        match self {
+            Body::Bytes(bytes) => bytes.len() as u64,
+            Body::File(_, length) => *length,
+        }
+    }
+
+    /// The leading bytes of the content (see `content_type::SNIFF_LENGTH`).
+    fn head(&mut self) -> io::Result<Vec<u8>> {
+        match self {
+            Body::Bytes(bytes) => Ok(bytes[..bytes.len().min(SNIFF_LENGTH)].to_vec()),
+            Body::File(file, length) => {
+                let mut head = Vec::new();
+                file.take((*length).min(SNIFF_LENGTH as u64))
+                    .read_to_end(&mut head)?;
+                file.rewind()?;
+                Ok(head)
+            }
+        }
+    }
+
+    /// The bytes from the first to the last one (both included).
+    fn range(self, first: u64, last: u64) -> io::Result<Body> {
+        match self {
+            Body::Bytes(bytes) => Ok(Body::Bytes(bytes[first as usize..=last as usize].to_vec())),
+            Body::File(mut file, _) => {
+                file.seek(SeekFrom::Start(first))?;
+                Ok(Body::File(file, last - first + 1))
+            }
+        }
+    }
+}
+
+impl Response {
+    fn new(status: &'static str, content_type: &'static str, body: Vec<u8>) -> Self {
+        Response::with_body(status, content_type, Body::Bytes(body))
+    }
+
+    fn with_body(status: &'static str, content_type: &'static str, body: Body) -> Self {
+        Response {
+            status,
+            content_type,
+            headers: Vec::new(),
+            body,
+        }
+    }
+
+    fn json(status: &'static str, body: &Value) -> Self {
+        Response::new(
+            status,
+            "application/json",
+            format!("{}\n", body).into_bytes(),
+        )
+    }
+
+    fn error(e: &ChatError) -> Self {
+        let body = json!({"code": e.code.as_str(), "error": e.message});
+        Response::json(status(e.code), &body)
+    }
+
+    fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+}
+
+/// Starts serving the HTTP API in a background thread.
+pub(crate) fn serve(
+    listener: TcpListener,
+    config: SharedConfig,
+    state: Arc<ServerState>,
+) -> io::Result<()> {
+    info!("Serving HTTP API on http://{}", listener.local_addr()?);
+    let active = Arc::new(AtomicUsize::new(0));
+    thread::Builder::new()
+        .name("http".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                if state.is_stopping() {
+                    break;
+                }
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = spawn_request(stream, &config, &state, &active) {
+                            error!("Failed to handle HTTP connection: {}", e);
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to accept HTTP connection: {}", e);
+                    }
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn spawn_request(
+    stream: TcpStream,
+    config: &SharedConfig,
+    state: &Arc<ServerState>,
+    active: &Arc<AtomicUsize>,
+) -> io::Result<()> {
+    let addr = stream.peer_addr()?;
+    if is_banned(&addr, state) {
+        return stream.shutdown(Shutdown::Both);
+    }
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    // the session is not registered, it is only the context of the upload (quotas, audit, announcement)
+    let mut session = Session::new(addr, stream, config.clone(), state.clone())?;
+    // only this thread adds the requests, hence the limit cannot be exceeded in between
+    if active.load(Ordering::SeqCst) >= MAX_ACTIVE_REQUESTS {
+        warn!(
+            "Refused HTTP connection from {}, {} requests being served",
+            addr, MAX_ACTIVE_REQUESTS
+        );
+        let e = ChatError::new(
+            ErrorCode::RateLimited,
+            "Too many requests being served, try again later",
+        );
+        state.metrics.http_requests.inc("503");
+        let response = Response {
+            status: "503 Service Unavailable",
+            ..Response::error(&e)
+        };
+        return write_response(&session, response.header("Retry-After", "1"));
+    }
+    let guard = Active::start(active.clone());
+    thread::Builder::new()
+        .name(format!("http-{}", addr))
+        .spawn(move || {
+            let _guard = guard;
+            set_field("peer", addr);
+            if let Err(e) = handle_request(&mut session) {
+                warn!("Failed to serve HTTP request: {}", e);
+            }
+        })?;
+    Ok(())
+}
+
+/// A request being served, counted until dropped.
+struct Active(Arc<AtomicUsize>);
+
+impl Active {
+    fn start(active: Arc<AtomicUsize>) -> Self {
+        active.fetch_add(1, Ordering::SeqCst);
+        Active(active)
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn handle_request(session: &mut Session) -> io::Result<()> {
+    let Some(request) = read_request(session)? else {
+        let e = ChatError::new(ErrorCode::InvalidArgument, "Malformed HTTP request");
+        return write_response(session, Response::error(&e));
+    };
+    let response = route(session, &request);
+    info!("{} {} -> {}", request.method, request.path, response.status);
+    let code = response.status.split(' ').next().unwrap_or_default();
+    session.state.metrics.http_requests.inc(code);
+    write_response(session, response)
+}
+
+/// Reads the request line and the headers, `None` if they are malformed (or too long).
+fn read_request(session: &mut Session) -> io::Result<Option<Request>> {
+    let mut head = (&mut session.stream).take(MAX_HEAD_SIZE as u64);
+    let mut line = String::new();
+    head.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Ok(None);
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut request = Request {
+        method: method.to_string(),
+        path: percent_decode(path, false),
+        query: query
+            .split('&')
+            .filter(|parameter| !parameter.is_empty())
+            .map(|parameter| {
+                let (name, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+                (percent_decode(name, true), percent_decode(value, true))
+            })
+            .collect(),
+        headers: Vec::new(),
+    };
+    loop {
+        line.clear();
+        if head.read_line(&mut line)? == 0 {
+            // the headers are not terminated by an empty line
+            return Ok(None);
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            return Ok(Some(request));
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            return Ok(None);
+        };
+        request
+            .headers
+            .push((name.trim().to_string(), value.trim().to_string()));
+    }
+}
+
+/// Decodes the `%XX` escapes (and `+` as a space in the query).
+fn percent_decode(value: &str, query: bool) -> String {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        // exactly two hex digits (`from_str_radix` alone would accept e.g. `+1`)
+        let escaped = tail
+            .get(..2)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (byte, escaped) {
+            (b'%', Some(decoded)) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+                continue;
+            }
+            (b'+', _) if query => bytes.push(b' '),
+            _ => bytes.push(byte),
+        }
+        rest = tail;
+    }
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+fn route(session: &mut Session, request: &Request) -> Response {
+    let segments = request
+        .path
+        .trim_matches('/')
+        .split('/')
+        .collect::<Vec<_>>();
+    let result = match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["health"]) => Ok(health(&session.state)),
+        ("GET", ["files"]) => Ok(list_files(&session.state)),
+        ("POST", ["files"]) => post_file(session, request),
+        ("GET", ["files", id]) => get_upload(session, request, id, false),
+        ("GET", ["images", id]) => get_upload(session, request, id, true),
+        (_, ["health"] | ["files"] | ["files", _] | ["images", _]) => {
+            let allowed = match segments.as_slice() {
+                ["files"] => "GET, POST",
+                _ => "GET",
+            };
+            let e = ChatError::new(
+                ErrorCode::InvalidArgument,
+                format!("Method {} not allowed", request.method),
+            );
+            return Response {
+                status: "405 Method Not Allowed",
+                ..Response::error(&e)
+            }
+            .header("Allow", allowed);
+        }
+        _ => Err(ChatError::new(
+            ErrorCode::NotFound,
+            format!("No resource {}", request.path),
+        )),
+    };
+    result.unwrap_or_else(|e| Response::error(&e))
+}
+
+fn health(state: &ServerState) -> Response {
+    let body = json!({
+        "status": "ok",
+        "uptime_seconds": state.started_at.elapsed().as_secs(),
+        "connections": state.connections.lock().unwrap().len(),
+        "uploads": state.catalog.lock().unwrap().entries().len(),
+    });
+    Response::json("200 OK", &body)
+}
+
+fn list_files(state: &ServerState) -> Response {
+    let catalog = state.catalog.lock().unwrap();
+    let files = catalog
+        .entries()
+        .iter()
+        .map(|entry| {
+            let uploaded_at = DateTime::from_timestamp(entry.uploaded_at as i64, 0)
+                .map_or(String::new(), |time| time.to_rfc3339());
+            let collection = match entry.kind.as_str() {
+                "image" => "images",
+                _ => "files",
+            };
+            json!({
+                "id": entry.id,
+                "name": entry.name(),
+                "kind": entry.kind,
+                "owner": entry.owner,
+                "size": entry.size,
+                "uploaded_at": uploaded_at,
+                "url": format!("/{}/{}", collection, entry.id),
+            })
+        })
+        .collect::<Vec<_>>();
+    Response::json("200 OK", &Value::Array(files))
+}
+
+/// Stores the body of the request as a file named by the `name` query parameter.
+fn post_file(session: &mut Session, request: &Request) -> ChatResult<Response> {
+    let length = request
+        .header("Content-Length")
+        .and_then(|length| length.parse::<usize>().ok());
+    let Some(length) = length else {
+        let e = ChatError::new(ErrorCode::InvalidArgument, "Content-Length is required");
+        return Ok(Response {
+            status: "411 Length Required",
+            ..Response::error(&e)
+        });
+    };
+    let name = request.query("name").filter(|name| !name.is_empty());
+    let Some(name) = name else {
+        discard_body(session, length)?;
+        return Err(ChatError::new(
+            ErrorCode::InvalidArgument,
+            "The file name is missing (e.g. POST /files?name=report.pdf)",
+        ));
+    };
+    // e.g. curl waits for the confirmation before sending a large body
+    if request
+        .header("Expect")
+        .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"))
+    {
+        session
+            .writer
+            .lock()
+            .unwrap()
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+    }
+    let upload = Upload {
+        filename: name,
+        wire_size: length,
+        size: length,
+        compression: None,
+    };
+    let directory = session.config().file_dir.clone();
+    let stored = store_upload(session, &upload, &directory, Storing::File)?;
+    let mut response = json!({
+        "name": name,
+        "path": stored.path,
+        "size": stored.size,
+        "type": stored.content_type,
+    });
+    let mut location = None;
+    if let Some(id) = &stored.id {
+        response["id"] = json!(id);
+        response["url"] = json!(format!("/files/{}", id));
+        location = Some(format!("/files/{}", id));
+    }
+    let response = Response::json("201 Created", &response);
+    Ok(match location {
+        Some(location) => response.header("Location", location),
+        None => response,
+    })
+}
+
+fn discard_body(session: &mut Session, length: usize) -> ChatResult<()> {
+    let copied = io::copy(
+        &mut (&mut session.stream).take(length as u64),
+        &mut io::sink(),
+    )?;
+    if copied < length as u64 {
+        return Err(ChatError::connection(
+            "Connection closed while skipping the body",
+        ));
+    }
+    Ok(())
+}
+
+/// Sends the content of the upload (an image if `image`, a file or an archive otherwise).
+fn get_upload(
+    session: &mut Session,
+    request: &Request,
+    id: &str,
+    image: bool,
+) -> ChatResult<Response> {
+    let entry = session.state.catalog.lock().unwrap().find(id).cloned();
+    let Some(entry) = entry.filter(|entry| (entry.kind == "image") == image) else {
+        let collection = if image { "image" } else { "file" };
+        return Err(ChatError::new(
+            ErrorCode::NotFound,
+            format!("No {} {} found", collection, id),
+        ));
+    };
+    let unreadable =
+        |e: io::Error| ChatError::new(ErrorCode::Io, format!("Cannot read #{}: {}", entry.id, e));
+    let (name, mut body) = match entry.kind.as_str() {
+        // packed again on every request, always the same way though (hence the ranges are consistent)
+        "archive" => {
+            let (name, content) = upload_content(&entry)?;
+            (name, Body::Bytes(content))
+        }
+        _ => {
+            let file = File::open(&entry.path).map_err(unreadable)?;
+            let length = file.metadata().map_err(unreadable)?.len();
+            (entry.name(), Body::File(file, length))
+        }
+    };
+    let content_type = content_type::detect(&body.head().map_err(unreadable)?).mime();
+    let disposition = match image {
+        true => "inline",
+        false => "attachment",
+    };
+    let filename = name
+        .chars()
+        .filter(|c| *c != '"' && !c.is_control())
+        .collect::<String>();
+    let total = body.len();
+    let range = request
+        .header("Range")
+        .and_then(|range| parse_range(range, total));
+    let response = match range {
+        None => Response::with_body("200 OK", content_type, body),
+        Some(Some((first, last))) => Response::with_body(
+            "206 Partial Content",
+            content_type,
+            body.range(first, last).map_err(unreadable)?,
+        )
+        .header(
+            "Content-Range",
+            format!("bytes {}-{}/{}", first, last, total),
+        ),
+        Some(None) => {
+            let e = ChatError::new(
+                ErrorCode::InvalidArgument,
+                format!("Range not satisfiable, {} has {} bytes", name, total),
+            );
+            return Ok(Response {
+                status: "416 Range Not Satisfiable",
+                ..Response::error(&e)
+            }
+            .header("Content-Range", format!("bytes */{}", total)));
+        }
+    };
+    session.state.audit.download(
+        session,
+        &entry.id,
+        &entry.path,
+        response.body.len() as usize,
+    );
+    let metrics = &session.state.metrics;
+    metrics.downloads.inc();
+    metrics.download_bytes.add(response.body.len());
+    Ok(response.header("Accept-Ranges", "bytes").header(
+        "Content-Disposition",
+        format!("{}; filename=\"{}\"", disposition, filename),
+    ))
+}
+
+/// Parses a single byte range (`bytes=0-99`, `bytes=100-` or `bytes=-100`) into the first and the last byte.
+///
+/// Returns `None` if the range is to be ignored (multiple ranges, another unit or an invalid range),
+/// `Some(None)` if it cannot be satisfied.
+fn parse_range(range: &str, total: u64) -> Option<Option<(u64, u64)>> {
+    let spec = range.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => match suffix.parse::<u64>().ok()? {
+            0 => return Some(None),
+            suffix => (total.saturating_sub(suffix), total.saturating_sub(1)),
+        },
+        (start, "") => (start.parse().ok()?, total.saturating_sub(1)),
+        (start, end) => {
+            let (start, end) = (start.parse().ok()?, end.parse::<u64>().ok()?);
+            if end < start {
+                return None;
+            }
+            (start, end.min(total.saturating_sub(1)))
+        }
+    };
+    Some((start <= end && end < total).then_some((start, end)))
+}
+
+fn write_response(session: &Session, response: Response) -> io::Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    );
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    let mut writer = session.writer.lock().unwrap();
+    writer.write_all(head.as_bytes())?;
+    match response.body {
+        Body::Bytes(bytes) => writer.write_all(&bytes)?,
+        Body::File(file, length) => {
+            let copied = io::copy(&mut file.take(length), &mut *writer)?;
+            if copied < length {
+                // the file was truncated meanwhile, the client must not take the response for complete
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "File shorter than announced",
+                ));
+            }
+        }
+    }
+    writer.flush()
+}
+
+/// HTTP status of the error code.
+fn status(code: ErrorCode) -> &'static str {
+    match code {
+        ErrorCode::InvalidArgument | ErrorCode::UnknownCommand => "400 Bad Request",
+        ErrorCode::Forbidden => "403 Forbidden",
+        ErrorCode::NotFound => "404 Not Found",
+        ErrorCode::Conflict => "409 Conflict",
+        ErrorCode::TooLarge => "413 Content Too Large",
+        ErrorCode::UnsupportedType => "415 Unsupported Media Type",
+        ErrorCode::DecodeFailed => "422 Unprocessable Content",
+        ErrorCode::RateLimited => "429 Too Many Requests",
+        ErrorCode::Disabled => "503 Service Unavailable",
+        ErrorCode::QuotaExceeded => "507 Insufficient Storage",
+        _ => "500 Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_range_reads_single_ranges() {
+        assert_eq!(parse_range("bytes=0-9", 100), Some(Some((0, 9))));
+        assert_eq!(parse_range("bytes=90-199", 100), Some(Some((90, 99))));
+        // open and suffix ranges
+        assert_eq!(parse_range("bytes=95-", 100), Some(Some((95, 99))));
+        assert_eq!(parse_range("bytes=-5", 100), Some(Some((95, 99))));
+        assert_eq!(parse_range("bytes=-500", 100), Some(Some((0, 99))));
+    }
+
+    #[test]
+    fn parse_range_detects_unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=100-", 100), Some(None));
+        assert_eq!(parse_range("bytes=100-200", 100), Some(None));
+        assert_eq!(parse_range("bytes=-0", 100), Some(None));
+        assert_eq!(parse_range("bytes=0-", 0), Some(None));
+        assert_eq!(parse_range("bytes=-5", 0), Some(None));
+    }
+
+    #[test]
+    fn parse_range_ignores_other_ranges() {
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), None);
+        assert_eq!(parse_range("items=0-9", 100), None);
+        assert_eq!(parse_range("bytes=9-0", 100), None);
+        assert_eq!(parse_range("bytes=a-b", 100), None);
+        assert_eq!(parse_range("bytes=5", 100), None);
+        assert_eq!(parse_range("bytes=-", 100), None);
+    }
+
+    #[test]
+    fn percent_decode_decodes_escapes() {
+        assert_eq!(percent_decode("my%20file.txt", false), "my file.txt");
+        assert_eq!(percent_decode("%c3%A9t%C3%A9", false), "été");
+        assert_eq!(percent_decode("x%0A1", true), "x\n1");
+        assert_eq!(percent_decode("a+b", true), "a b");
+        assert_eq!(percent_decode("a+b", false), "a+b");
+    }
+
+    #[test]
+    fn percent_decode_keeps_malformed_escapes() {
+        assert_eq!(percent_decode("100%", false), "100%");
+        assert_eq!(percent_decode("%4", false), "%4");
+        assert_eq!(percent_decode("%zz", false), "%zz");
+        assert_eq!(percent_decode("%+1", false), "%+1");
+        assert_eq!(percent_decode("%%41", false), "%A");
+        assert_eq!(percent_decode("%é", false), "%é");
+        assert_eq!(percent_decode("%FF", false), "\u{FFFD}");
+    }
+}
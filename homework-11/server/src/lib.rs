@@ -0,0 +1,45 @@
+//! Server for the file sharing application, usable as a library.
+//!
+//! The server is set up and started using the `Server` builder, e.g. for end-to-end tests
+//! against an ephemeral port:
+//!
+//! ```no_run
+//! use server::Server;
+//!
+//! let handle = Server::builder()
+//!     .bind("127.0.0.1:0")
+//!     .storage("target/test-storage")
+//!     .hook(|event| println!("{:?}", event))
+//!     .start()?;
+//! println!("Listening on {}", handle.local_addr());
+//! handle.shutdown();
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! Every connection is handled in a separate thread by the `stream_handler` module,
+//! the WebSocket connections are translated to the same protocol by the `websocket` module
+//! and the storage is also accessible over HTTP (see the `http` module).
+
+mod admin;
+mod audit;
+mod ban;
+mod catalog;
+mod command;
+mod config;
+mod event;
+mod file;
+mod http;
+mod metrics;
+mod quota;
+mod reload;
+mod retention;
+mod room;
+mod server;
+mod state;
+mod stream_handler;
+mod websocket;
+
+pub use config::{load_settings, Config, ConfigLoader};
+pub use event::{Event, Hook};
+pub use server::{Server, ServerHandle};
+pub use state::Session;
@@ -0,0 +1,241 @@
+//! Server metrics and their Prometheus-compatible HTTP endpoint.
+//!
+//! The metrics are collected by the processing threads into the shared `Metrics`
+//! (also used by the admin `.stats` command). When the metrics address is configured,
+//! a dedicated thread serves them in the Prometheus text format on `GET /metrics`.
+
+use crate::state::ServerState;
+use common::{error, info, warn};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Upper bounds (in seconds) of the upload duration histogram buckets.
+const UPLOAD_DURATION_BUCKETS: [f64; 8] = [0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 60.0];
+
+#[derive(Default)]
+pub(crate) struct Counter(AtomicU64);
+
+impl Counter {
+    pub(crate) fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc(&self) {
+        self.add(1);
+    }
+
+    pub(crate) fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct LabeledCounter(Mutex<BTreeMap<String, u64>>);
+
+impl LabeledCounter {
+    pub(crate) fn inc(&self, label: &str) {
+        *self.0.lock().unwrap().entry(label.to_string()).or_insert(0) += 1;
+    }
+
+    pub(crate) fn total(&self) -> u64 {
+        self.0.lock().unwrap().values().sum()
+    }
+
+    fn snapshot(&self) -> BTreeMap<String, u64> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+pub(crate) struct Histogram {
+    buckets: Vec<(f64, Counter)>,
+    count: Counter,
+    sum_micros: Counter,
+}
+
+impl Histogram {
+    fn new(bounds: &[f64]) -> Self {
+        Histogram {
+            buckets: bounds.iter().map(|b| (*b, Counter::default())).collect(),
+            count: Counter::default(),
+            sum_micros: Counter::default(),
+        }
+    }
+
+    pub(crate) fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bound, counter) in &self.buckets {
+            if secs <= *bound {
+                counter.inc();
+            }
+        }
+        self.count.inc();
+        self.sum_micros.add(duration.as_micros() as u64);
+    }
+}
+
+pub(crate) struct Metrics {
+    pub(crate) connections: Counter,
+    /// The connections accepted by the WebSocket gateway (counted in `connections` as well).
+    pub(crate) websocket_connections: Counter,
+    pub(crate) messages: Counter,
+    pub(crate) commands: LabeledCounter,
+    pub(crate) uploads: Counter,
+    pub(crate) upload_bytes: Counter,
+    pub(crate) upload_duration: Histogram,
+    pub(crate) downloads: Counter,
+    pub(crate) download_bytes: Counter,
+    pub(crate) image_conversions: Counter,
+    pub(crate) errors: LabeledCounter,
+    pub(crate) http_requests: LabeledCounter,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            connections: Counter::default(),
+            websocket_connections: Counter::default(),
+            messages: Counter::default(),
+            commands: LabeledCounter::default(),
+            uploads: Counter::default(),
+            upload_bytes: Counter::default(),
+            upload_duration: Histogram::new(&UPLOAD_DURATION_BUCKETS),
+            downloads: Counter::default(),
+            download_bytes: Counter::default(),
+            image_conversions: Counter::default(),
+            errors: LabeledCounter::default(),
+            http_requests: LabeledCounter::default(),
+        }
+    }
+}
+
+impl Metrics {
+    fn render(&self, active_connections: usize) -> String {
+        let single = |value: u64| vec![(String::new(), value)];
+        #[rustfmt::skip]
+        let sections = [
+            ("chatee_active_connections", "gauge", "Currently open client connections.", single(active_connections as u64)),
+            ("chatee_connections_total", "counter", "Accepted client connections.", single(self.connections.get())),
+            ("chatee_websocket_connections_total", "counter", "Accepted WebSocket connections.", single(self.websocket_connections.get())),
+            ("chatee_messages_total", "counter", "Plain (non-command) messages received.", single(self.messages.get())),
+            ("chatee_commands_total", "counter", "Commands received, by command.", labeled("command", &self.commands)),
+            ("chatee_uploads_total", "counter", "Successful uploads.", single(self.uploads.get())),
+            ("chatee_upload_bytes_total", "counter", "Bytes received in successful uploads.", single(self.upload_bytes.get())),
+            ("chatee_downloads_total", "counter", "Downloads sent (by .get or the HTTP API).", single(self.downloads.get())),
+            ("chatee_download_bytes_total", "counter", "Bytes of the downloaded content (before compression).", single(self.download_bytes.get())),
+            ("chatee_image_conversions_total", "counter", "Uploaded images converted to the configured format.", single(self.image_conversions.get())),
+            ("chatee_errors_total", "counter", "Errors returned to clients, by error code.", labeled("code", &self.errors)),
+            ("chatee_http_requests_total", "counter", "Requests served by the HTTP API, by status code.", labeled("status", &self.http_requests)),
+        ];
+
+        let mut out = String::new();
+        for (name, kind, help, values) in sections {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+            for (labels, value) in values {
+                let _ = writeln!(out, "{}{} {}", name, labels, value);
+            }
+        }
+
+        let name = "chatee_upload_duration_seconds";
+        let histogram = &self.upload_duration;
+        let _ = writeln!(out, "# HELP {} Duration of successful uploads.", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bound, counter) in &histogram.buckets {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, counter.get());
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{le=\"+Inf\"}} {}",
+            name,
+            histogram.count.get()
+        );
+        let _ = writeln!(
+            out,
+            "{}_sum {}",
+            name,
+            histogram.sum_micros.get() as f64 / 1e6
+        );
+        let _ = writeln!(out, "{}_count {}", name, histogram.count.get());
+        out
+    }
+}
+
+fn labeled(label: &str, counter: &LabeledCounter) -> Vec<(String, u64)> {
+    counter
+        .snapshot()
+        .into_iter()
+        .map(|(value, count)| (format!("{{{}=\"{}\"}}", label, value), count))
+        .collect()
+}
+
+/// Binds the metrics listener and starts serving the metrics in a separate thread.
+///
+/// Returns the actually bound address, the thread finishes once the server is stopped.
+pub(crate) fn serve(address: &str, state: Arc<ServerState>) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(address)?;
+    let address = listener.local_addr()?;
+    info!("Serving metrics on http://{}/metrics", address);
+    thread::Builder::new()
+        .name("metrics".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                if state.is_stopping() {
+                    break;
+                }
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = handle_request(stream, &state) {
+                            warn!("Failed to serve metrics request: {}", e);
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to accept metrics connection: {}", e);
+                    }
+                }
+            }
+        })?;
+    Ok(address)
+}
+
+fn handle_request(mut stream: TcpStream, state: &ServerState) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // skip the headers, the request has no body we would be interested in
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let active = state.connections.lock().unwrap().len();
+            (
+                "200 OK",
+                "text/plain; version=0.0.4",
+                state.metrics.render(active),
+            )
+        }
+        (Some("GET"), _) => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method not allowed\n".to_string(),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
@@ -0,0 +1,179 @@
+//! Storage quotas and the free space guard.
+//!
+//! An upload is refused before its content is received if it would exceed the quota of its owner
+//! (the IP address of the uploader, see `owner`), the quota of the whole storage, or leave less than
+//! the configured free space on the filesystem of the target directory. The announced (original) size
+//! of the upload is checked, the size of the uploads in progress is reserved until they are stored.
+
+use crate::config::Config;
+use crate::state::{ServerState, Session};
+use common::command::Args;
+use common::error::{ChatError, ChatResult, ErrorCode};
+use common::util::format_size;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Space reserved for an upload in progress, released when dropped.
+pub(crate) struct Reservation {
+    state: Arc<ServerState>,
+    owner: String,
+    size: u64,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.state
+            .catalog
+            .lock()
+            .unwrap()
+            .release(&self.owner, self.size);
+    }
+}
+
+/// The user the uploads of the session are accounted to, i.e. its IP address
+/// (unlike the nick, it stays the same when the nick changes or the client reconnects).
+pub(crate) fn owner(session: &Session) -> String {
+    session.peer.ip().to_string()
+}
+
+/// Checks the upload of the given size into the directory against the quotas and reserves the space.
+pub(crate) fn reserve(session: &Session, directory: &str, size: u64) -> ChatResult<Reservation> {
+    let config = session.config();
+    let owner = owner(session);
+    // measured before locking the catalog, walking the storage may take a while
+    let storage = storage_usage(&config);
+    let free = free_space(directory);
+    let exceeded = |message: String| Err(ChatError::new(ErrorCode::QuotaExceeded, message));
+
+    let mut catalog = session.state.catalog.lock().unwrap();
+    let used = catalog.usage(&owner).1 + catalog.reserved(Some(&owner));
+    if config.user_quota > 0 && used + size > config.user_quota {
+        return exceeded(format!(
+            "Upload of {} would exceed the quota of {} ({} of {} used)",
+            format_size(size),
+            owner,
+            format_size(used),
+            format_size(config.user_quota)
+        ));
+    }
+    let pending = catalog.reserved(None);
+    if config.storage_quota > 0 && storage + pending + size > config.storage_quota {
+        return exceeded(format!(
+            "Upload of {} would exceed the storage quota ({} of {} used)",
+            format_size(size),
+            format_size(storage + pending),
+            format_size(config.storage_quota)
+        ));
+    }
+    if let Some(free) = free.filter(|_| config.min_free_space > 0) {
+        if free.saturating_sub(pending + size) < config.min_free_space {
+            return exceeded(format!(
+                "Upload of {} would leave less than {} of free space on the server ({} free)",
+                format_size(size),
+                format_size(config.min_free_space),
+                format_size(free.saturating_sub(pending))
+            ));
+        }
+    }
+    catalog.reserve(&owner, size);
+    Ok(Reservation {
+        state: session.state.clone(),
+        owner,
+        size,
+    })
+}
+
+/// The `.quota` command, reporting the usage of the user (the session's own if not given) and of the storage.
+pub(crate) fn quota(session: &mut Session, args: &Args) -> ChatResult<String> {
+    let config = session.config();
+    // a nick of an online user stands for its address
+    let owner = match args.get("user") {
+        Some(user) => match session.state.find_by_nick(user) {
+            Some(addr) => addr.ip().to_string(),
+            None => user.to_string(),
+        },
+        None => owner(session),
+    };
+    let (uploads, used) = session.state.catalog.lock().unwrap().usage(&owner);
+    let mut free = match free_space(&config.file_dir) {
+        Some(free) => format_size(free),
+        None => "unknown".to_string(),
+    };
+    if config.min_free_space > 0 {
+        free.push_str(&format!(
+            ", at least {} is kept free",
+            format_size(config.min_free_space)
+        ));
+    }
+    Ok(format!(
+        "Usage of {}: {}, uploads: {}\nStorage: {}\nFree space: {}",
+        owner,
+        of_quota(used, config.user_quota),
+        uploads,
+        of_quota(storage_usage(&config), config.storage_quota),
+        free
+    ))
+}
+
+fn of_quota(used: u64, quota: u64) -> String {
+    match quota {
+        0 => format!("{} (no quota)", format_size(used)),
+        quota => format!(
+            "{} of {} ({}%)",
+            format_size(used),
+            format_size(quota),
+            used * 100 / quota
+        ),
+    }
+}
+
+/// Total size of the stored files and images.
+fn storage_usage(config: &Config) -> u64 {
+    let mut directories = vec![&config.file_dir, &config.image_dir];
+    directories.dedup();
+    directories
+        .into_iter()
+        .map(|directory| directory_size(Path::new(directory)))
+        .sum()
+}
+
+pub(crate) fn directory_size(path: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => directory_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+/// Space available to the server on the filesystem of the directory, `None` if unknown.
+#[cfg(unix)]
+fn free_space(directory: &str) -> Option<u64> {
+    use std::ffi::CString;
+    use std::mem::MaybeUninit;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(Path::new(directory).as_os_str().as_bytes()).ok()?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: the path is a valid C string and the structure is initialized by a successful call
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return None;
+        }
+        stat.assume_init()
+    };
+    // the field types differ across the platforms
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn free_space(_: &str) -> Option<u64> {
+    None
+}
@@ -0,0 +1,102 @@
+//! Runtime reload of the server configuration.
+//!
+//! The reload is triggered either by the admin `.reload` command or by sending `SIGHUP`
+//! to the server process (on Unix). The configuration is loaded anew by the `ConfigLoader`
+//! of the server (the standalone server re-reads all the configuration sources) and the new
+//! configuration is swapped in for the connections to pick up on their next command.
+//! The listener addresses cannot be changed without a restart, such a change is ignored.
+
+use crate::ban::BanList;
+use crate::catalog::Catalog;
+use crate::config::SharedConfig;
+use crate::state::ServerState;
+use common::error::{ChatError, ChatResult, ErrorCode};
+use common::info;
+use std::fs;
+
+pub(crate) fn reload(config: &SharedConfig, state: &ServerState) -> ChatResult<String> {
+    let loader = state.config_loader.as_ref().ok_or_else(|| {
+        ChatError::new(
+            ErrorCode::Disabled,
+            "Reloading is not enabled on this server",
+        )
+    })?;
+    let invalid = |e: String| ChatError::new(ErrorCode::InvalidConfig, e);
+    let current = config.get();
+    let mut new = loader().map_err(|e| invalid(e.to_string()))?;
+    let mut messages = vec!["Configuration reloaded".to_string()];
+
+    if new.address != current.address {
+        messages.push(format!(
+            "Listener address change ({} -> {}) requires a restart and was ignored",
+            current.address, new.address
+        ));
+        new.address = current.address.clone();
+    }
+    if new.metrics_address != current.metrics_address {
+        messages.push(format!(
+            "Metrics address change ({} -> {}) requires a restart and was ignored",
+            current.metrics_address.as_deref().unwrap_or("none"),
+            new.metrics_address.as_deref().unwrap_or("none")
+        ));
+        new.metrics_address = current.metrics_address.clone();
+    }
+    if new.ws_address != current.ws_address {
+        messages.push(format!(
+            "WebSocket address change ({} -> {}) requires a restart and was ignored",
+            current.ws_address.as_deref().unwrap_or("none"),
+            new.ws_address.as_deref().unwrap_or("none")
+        ));
+        new.ws_address = current.ws_address.clone();
+    }
+    if new.http_address != current.http_address {
+        messages.push(format!(
+            "HTTP address change ({} -> {}) requires a restart and was ignored",
+            current.http_address.as_deref().unwrap_or("none"),
+            new.http_address.as_deref().unwrap_or("none")
+        ));
+        new.http_address = current.http_address.clone();
+    }
+    for directory in [&new.file_dir, &new.image_dir] {
+        fs::create_dir_all(directory).map_err(|e| {
+            invalid(format!(
+                "Failed to create storage directory {}: {}",
+                directory, e
+            ))
+        })?;
+    }
+
+    let bans = BanList::load(&new.ban_file).map_err(|e| invalid(e.to_string()))?;
+    let catalog = Catalog::load(&new.catalog_file).map_err(|e| invalid(e.to_string()))?;
+    *state.bans.lock().unwrap() = bans;
+    state.catalog.lock().unwrap().reload(catalog);
+    config.replace(new);
+
+    let message = messages.join("\n");
+    info!("{}", message);
+    Ok(message)
+}
+
+/// Starts a thread reloading the configuration whenever the process receives `SIGHUP`.
+#[cfg(unix)]
+pub(crate) fn reload_on_sighup(
+    config: SharedConfig,
+    state: std::sync::Arc<ServerState>,
+) -> std::io::Result<()> {
+    use common::error;
+    use signal_hook::consts::SIGHUP;
+    use signal_hook::iterator::Signals;
+
+    let mut signals = Signals::new([SIGHUP])?;
+    std::thread::Builder::new()
+        .name("reload".to_string())
+        .spawn(move || {
+            for _ in signals.forever() {
+                info!("Received SIGHUP, reloading configuration");
+                if let Err(e) = reload(&config, &state) {
+                    error!("Failed to reload configuration: {}", e);
+                }
+            }
+        })?;
+    Ok(())
+}
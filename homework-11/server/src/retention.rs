@@ -0,0 +1,299 @@
+//! Retention policy of the stored uploads.
+//!
+//! A background thread applies the policy every `retention_interval`: the uploads (the stored files
+//! and the directories of the unpacked archives) older than `retention_age` are removed, then the oldest
+//! ones until the storage fits into `retention_size`. The age is taken from the modification time.
+//! The removed uploads are moved into `retention_archive_dir` if set (keeping the name of the storage
+//! directory, e.g. `archive/files/...`), deleted otherwise. In the dry-run mode, they are only logged.
+
+use crate::config::{Config, Retention, SharedConfig};
+use crate::quota::directory_size;
+use crate::state::ServerState;
+use common::util::{format_duration, format_size};
+use common::{debug, error, info};
+use std::cmp::Reverse;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Interval of checking for the shutdown (and a reloaded interval) between the runs.
+const TICK: Duration = Duration::from_secs(1);
+
+/// An upload found in the storage.
+struct Upload {
+    path: PathBuf,
+    size: u64,
+    age: Duration,
+}
+
+/// Starts the thread applying the policy, which is taken from the current configuration on every run.
+pub(crate) fn start(config: SharedConfig, state: Arc<ServerState>) -> io::Result<()> {
+    thread::Builder::new()
+        .name("retention".to_string())
+        .spawn(move || {
+            while !state.is_stopping() {
+                let started_at = Instant::now();
+                let snapshot = config.get();
+                if snapshot.retention.is_enabled() {
+                    apply(&snapshot, &state);
+                }
+                while started_at.elapsed() < config.get().retention.interval {
+                    if state.is_stopping() {
+                        return;
+                    }
+                    thread::sleep(TICK);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn apply(config: &Config, state: &ServerState) {
+    let policy = &config.retention;
+    let (removed, freed, total) = select(policy, list_uploads(config), |upload, reason| {
+        remove(upload, reason, config, state)
+    });
+    let (mode, verb) = match policy.dry_run {
+        true => (" (dry run)", "would remove"),
+        false => ("", "removed"),
+    };
+    if removed > 0 {
+        info!(
+            "Retention{}: {} {} uploads ({}), {} left in the storage",
+            mode,
+            verb,
+            removed,
+            format_size(freed),
+            format_size(total)
+        );
+    } else {
+        debug!(
+            "Retention{}: nothing to remove, {} in the storage",
+            mode,
+            format_size(total)
+        );
+    }
+}
+
+/// Hands the uploads to be removed by the policy over to `remove` (with the reason, i.e. `age` or `size`),
+/// oldest first. Returns the number and size of the removed uploads and the size left in the storage.
+fn select(
+    policy: &Retention,
+    mut uploads: Vec<Upload>,
+    mut remove: impl FnMut(&Upload, &'static str) -> bool,
+) -> (usize, u64, u64) {
+    uploads.sort_by_key(|upload| Reverse(upload.age));
+    let mut total = uploads.iter().map(|upload| upload.size).sum::<u64>();
+    let (mut removed, mut freed) = (0, 0);
+    for upload in uploads {
+        let reason = if policy.max_age.is_some_and(|age| upload.age > age) {
+            "age"
+        } else if policy.max_size > 0 && total > policy.max_size {
+            "size"
+        } else {
+            // the rest is newer and the storage fits into the size
+            break;
+        };
+        if remove(&upload, reason) {
+            total -= upload.size;
+            freed += upload.size;
+            removed += 1;
+        }
+    }
+    (removed, freed, total)
+}
+
+/// The entries of the storage directories (except the archive, if placed there).
+fn list_uploads(config: &Config) -> Vec<Upload> {
+    let archive_dir = config
+        .retention
+        .archive_dir
+        .as_ref()
+        .and_then(|directory| fs::canonicalize(directory).ok());
+    let mut directories = vec![&config.file_dir, &config.image_dir];
+    directories.dedup();
+    let now = SystemTime::now();
+    let mut uploads = Vec::new();
+    for directory in directories {
+        let entries = match fs::read_dir(directory) {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("Retention: cannot read {}: {}", directory, e);
+                continue;
+            }
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if metadata.is_dir()
+                && archive_dir.is_some()
+                && fs::canonicalize(&path).ok() == archive_dir
+            {
+                continue;
+            }
+            let modified = metadata.modified().unwrap_or(now);
+            uploads.push(Upload {
+                size: match metadata.is_dir() {
+                    true => directory_size(&path),
+                    false => metadata.len(),
+                },
+                age: now.duration_since(modified).unwrap_or_default(),
+                path,
+            });
+        }
+    }
+    uploads
+}
+
+/// Deletes or archives the upload, returning whether it was (or would be in the dry-run mode) removed.
+fn remove(upload: &Upload, reason: &str, config: &Config, state: &ServerState) -> bool {
+    let policy = &config.retention;
+    let path = upload.path.display().to_string();
+    let details = format!(
+        "{}, {} old",
+        format_size(upload.size),
+        format_duration(upload.age)
+    );
+    let target = policy
+        .archive_dir
+        .as_ref()
+        .map(|directory| archive_path(&upload.path, directory));
+    if policy.dry_run {
+        match &target {
+            Some(target) => info!(
+                "Retention (dry run): would archive {} to {} ({})",
+                path,
+                target.display(),
+                details
+            ),
+            None => info!("Retention (dry run): would delete {} ({})", path, details),
+        }
+        return true;
+    }
+
+    if let Err(e) = state.catalog.lock().unwrap().remove(&upload.path) {
+        error!("Failed to remove {} from the catalog: {}", path, e);
+    }
+    let removed = match &target {
+        Some(target) => target
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::rename(&upload.path, target)),
+        None if upload.path.is_dir() => fs::remove_dir_all(&upload.path),
+        None => fs::remove_file(&upload.path),
+    };
+    if let Err(e) = removed {
+        error!("Retention: failed to remove {}: {}", path, e);
+        return false;
+    }
+    let archived_to = target.map(|target| target.display().to_string());
+    match &archived_to {
+        Some(target) => info!("Retention: archived {} to {} ({})", path, target, details),
+        None => info!("Retention: deleted {} ({})", path, details),
+    }
+    state
+        .audit
+        .retention(config, &path, upload.size, reason, archived_to.as_deref());
+    true
+}
+
+/// Path of the upload in the archive, e.g. `archive/files/2025-03-02T10-15-42Z_report.pdf`.
+fn archive_path(path: &Path, archive_dir: &str) -> PathBuf {
+    let storage = path
+        .parent()
+        .and_then(|parent| parent.file_name())
+        .unwrap_or_default();
+    Path::new(archive_dir)
+        .join(storage)
+        .join(path.file_name().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_age: Option<u64>, max_size: u64) -> Retention {
+        Retention {
+            max_age: max_age.map(Duration::from_secs),
+            max_size,
+            interval: Duration::from_secs(3600),
+            archive_dir: None,
+            dry_run: false,
+        }
+    }
+
+    /// Uploads named by their age (in seconds), listed in no particular order.
+    fn uploads(ages_and_sizes: &[(u64, u64)]) -> Vec<Upload> {
+        ages_and_sizes
+            .iter()
+            .map(|&(age, size)| Upload {
+                path: PathBuf::from(age.to_string()),
+                size,
+                age: Duration::from_secs(age),
+            })
+            .collect()
+    }
+
+    fn run(policy: &Retention, uploads: Vec<Upload>) -> (Vec<String>, (usize, u64, u64)) {
+        let mut selected = Vec::new();
+        let outcome = select(policy, uploads, |upload, reason| {
+            selected.push(format!("{} {}", upload.path.display(), reason));
+            true
+        });
+        (selected, outcome)
+    }
+
+    #[test]
+    fn uploads_older_than_age_are_removed() {
+        let (selected, outcome) = run(
+            &policy(Some(100), 0),
+            uploads(&[(50, 1), (300, 2), (100, 4), (200, 8)]),
+        );
+        assert_eq!(selected, ["300 age", "200 age"]);
+        assert_eq!(outcome, (2, 10, 5));
+    }
+
+    #[test]
+    fn oldest_uploads_are_removed_until_storage_fits() {
+        let (selected, outcome) = run(
+            &policy(None, 10),
+            uploads(&[(10, 4), (40, 4), (30, 4), (20, 4)]),
+        );
+        assert_eq!(selected, ["40 size", "30 size"]);
+        assert_eq!(outcome, (2, 8, 8));
+
+        // the storage fits already
+        let (selected, _) = run(&policy(None, 16), uploads(&[(10, 8), (20, 8)]));
+        assert!(selected.is_empty());
+    }
+
+    #[test]
+    fn age_and_size_apply_together() {
+        let (selected, outcome) = run(
+            &policy(Some(35), 5),
+            uploads(&[(10, 2), (20, 2), (30, 2), (40, 2)]),
+        );
+        assert_eq!(selected, ["40 age", "30 size"]);
+        assert_eq!(outcome, (2, 4, 4));
+    }
+
+    #[test]
+    fn failed_removal_is_made_up_for_by_newer_uploads() {
+        let mut selected = Vec::new();
+        let outcome = select(
+            &policy(None, 4),
+            uploads(&[(10, 2), (20, 2), (30, 2)]),
+            |upload, _| {
+                selected.push(upload.path.display().to_string());
+                upload.age.as_secs() != 30
+            },
+        );
+        assert_eq!(selected, ["30", "20"]);
+        assert_eq!(outcome, (1, 2, 4));
+    }
+}
@@ -0,0 +1,116 @@
+//! Chat rooms.
+//!
+//! Every connection is in exactly one room (starting in the lobby), the plain messages are delivered
+//! to the other users in the same room only. Rooms are not declared anywhere, a room exists as long as
+//! somebody is in it. The other users of a room are notified when somebody enters or leaves it.
+
+use crate::state::{Session, LOBBY};
+use crate::stream_handler::send_event;
+use common::command::{quote, Args};
+use common::error::{ChatError, ChatResult, ErrorCode};
+use common::event::ServerEvent;
+use common::{debug, info};
+use std::collections::BTreeMap;
+
+const ROOM_NAME_MAX_LENGTH: usize = 32;
+
+pub(crate) fn join(session: &mut Session, args: &Args) -> ChatResult<String> {
+    let room = args.get("room").map_or(Ok(LOBBY.to_string()), parse_room)?;
+    let previous = {
+        let mut connections = session.state.connections.lock().unwrap();
+        let Some(connection) = connections.get_mut(&session.peer) else {
+            return Err(ChatError::new(
+                ErrorCode::Internal,
+                "Connection not registered",
+            ));
+        };
+        std::mem::replace(&mut connection.room, room.clone())
+    };
+    if previous == room {
+        return Ok(format!("Already in #{}", room));
+    }
+    let user = session.display_name();
+    announce(
+        session,
+        &previous,
+        ServerEvent::Left {
+            user: user.clone(),
+            room: previous.clone(),
+        },
+    );
+    announce(
+        session,
+        &room,
+        ServerEvent::Joined {
+            user,
+            room: room.clone(),
+        },
+    );
+    send_session(session);
+    info!("Moved from #{} to #{}", previous, room);
+    Ok(format!("Joined #{}", room))
+}
+
+/// Lists the rooms with the number of users in each of them.
+pub(crate) fn rooms(session: &mut Session, _: &Args) -> ChatResult<String> {
+    let mut rooms = BTreeMap::<String, usize>::new();
+    for connection in session.state.connections.lock().unwrap().values() {
+        *rooms.entry(connection.room.clone()).or_default() += 1;
+    }
+    let lines = rooms
+        .iter()
+        .map(|(room, count)| format!("  #{} {}", room, count))
+        .collect::<Vec<_>>();
+    Ok(format!("Rooms ({}):\n{}", lines.len(), lines.join("\n")))
+}
+
+/// Lists the online users (by nick, or address if not set) with the room each of them is in.
+pub(crate) fn users(session: &mut Session, _: &Args) -> ChatResult<String> {
+    let connections = session.state.connections.lock().unwrap();
+    let mut lines = connections
+        .iter()
+        .map(|(addr, connection)| {
+            let name = connection.nick.clone().unwrap_or_else(|| addr.to_string());
+            format!("  {} #{}", quote(&name), connection.room)
+        })
+        .collect::<Vec<_>>();
+    lines.sort();
+    Ok(format!(
+        "Online users ({}):\n{}",
+        lines.len(),
+        lines.join("\n")
+    ))
+}
+
+/// Notifies the other users in the room (e.g. about somebody joining it).
+pub(crate) fn announce(session: &Session, room: &str, event: ServerEvent) {
+    session.state.broadcast(room, session.peer, &event);
+}
+
+/// Tells the client its own room and nick, the others in the room learn about the change by `announce`.
+pub(crate) fn send_session(session: &Session) {
+    let event = ServerEvent::Session {
+        room: session.room(),
+        nick: session.nick(),
+    };
+    if let Err(e) = send_event(&session.writer, &event) {
+        debug!("Failed to send the session event: {}", e);
+    }
+}
+
+fn parse_room(name: &str) -> ChatResult<String> {
+    let room = name.strip_prefix('#').unwrap_or(name).to_lowercase();
+    let valid = room
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '-' || c == '_');
+    if room.is_empty() || room.chars().count() > ROOM_NAME_MAX_LENGTH || !valid {
+        return Err(ChatError::new(
+            ErrorCode::InvalidArgument,
+            format!(
+                "Invalid room name '{}', use up to {} letters, digits, '-' or '_'",
+                name, ROOM_NAME_MAX_LENGTH
+            ),
+        ));
+    }
+    Ok(room)
+}
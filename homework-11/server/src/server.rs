@@ -0,0 +1,304 @@
+//! Programmatic server startup and shutdown.
+//!
+//! The `Server` builder sets the server up (configuration, storage, additional commands, hooks)
+//! and starts it in background threads, returning a `ServerHandle` to find out the actually
+//! bound addresses (e.g. when binding to port 0) and to stop the server again.
+//! Besides the TCP listener, the server optionally accepts WebSocket connections (see `websocket`)
+//! and serves the HTTP API of the storage (see `http`).
+
+use crate::ban::BanList;
+use crate::catalog::Catalog;
+use crate::command;
+use crate::config::{Config, ConfigLoader, SharedConfig};
+use crate::event::{Event, Hook};
+use crate::http;
+use crate::metrics;
+use crate::retention;
+use crate::state::{ServerState, Session};
+use crate::stream_handler::handle_stream;
+use crate::websocket;
+use common::command::{Command, Registry};
+use common::{error, info, warn};
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+pub struct Server {
+    config: Config,
+    commands: Registry<Session>,
+    hooks: Vec<Hook>,
+    config_loader: Option<ConfigLoader>,
+    reload_on_sighup: bool,
+}
+
+impl Server {
+    /// Starts building a server with the default configuration.
+    pub fn builder() -> Self {
+        Server {
+            config: Config::default(),
+            commands: command::registry(),
+            hooks: Vec::new(),
+            config_loader: None,
+            reload_on_sighup: false,
+        }
+    }
+
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Sets the listener address (e.g. `127.0.0.1:0` for an ephemeral port).
+    pub fn bind(mut self, address: impl Into<String>) -> Self {
+        self.config.address = address.into();
+        self
+    }
+
+    /// Stores the files, images, the ban list and the catalog of the uploads under the given directory.
+    pub fn storage(mut self, directory: impl AsRef<Path>) -> Self {
+        let path = |name: &str| directory.as_ref().join(name).to_string_lossy().to_string();
+        self.config.file_dir = path("files");
+        self.config.image_dir = path("images");
+        self.config.ban_file = path("bans.txt");
+        self.config.catalog_file = path("catalog.txt");
+        self
+    }
+
+    /// Adds a command, replacing the built-in command of the same name (if any).
+    pub fn command(mut self, command: impl Command<Session> + 'static) -> Self {
+        self.commands.register(command);
+        self
+    }
+
+    /// Registers a hook called for every server event.
+    pub fn hook(mut self, hook: impl Fn(&Event) + Send + Sync + 'static) -> Self {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Enables the configuration reload, taking the new configuration from the loader.
+    pub fn reload_with(
+        mut self,
+        loader: impl Fn() -> Result<Config, Box<dyn Error>> + Send + Sync + 'static,
+    ) -> Self {
+        self.config_loader = Some(Box::new(loader));
+        self
+    }
+
+    /// Reloads the configuration whenever the process receives `SIGHUP` (Unix only).
+    pub fn reload_on_sighup(mut self) -> Self {
+        self.reload_on_sighup = true;
+        self
+    }
+
+    /// Binds the listeners and starts accepting the connections in a background thread.
+    pub fn start(self) -> Result<ServerHandle, Box<dyn Error>> {
+        let config = self.config;
+        if let Some(config_file) = &config.config_file {
+            info!("Using configuration file {}", config_file);
+        }
+        for directory in [&config.file_dir, &config.image_dir] {
+            fs::create_dir_all(directory)
+                .map_err(|e| format!("Failed to create storage directory {}: {}", directory, e))?;
+        }
+        let bans = BanList::load(&config.ban_file)
+            .map_err(|e| format!("Failed to load ban list from {}: {}", config.ban_file, e))?;
+        let catalog = Catalog::load(&config.catalog_file)
+            .map_err(|e| format!("Failed to load catalog from {}: {}", config.catalog_file, e))?;
+        let state = Arc::new(ServerState::new(
+            bans,
+            catalog,
+            self.commands,
+            self.hooks,
+            self.config_loader,
+        ));
+
+        let listener = TcpListener::bind(&config.address)
+            .map_err(|e| format!("Failed to bind to address {}: {}", config.address, e))?;
+        let address = listener.local_addr()?;
+        let metrics_address = match &config.metrics_address {
+            Some(metrics_address) => {
+                Some(metrics::serve(metrics_address, state.clone()).map_err(|e| {
+                    format!("Failed to serve metrics on {}: {}", metrics_address, e)
+                })?)
+            }
+            None => None,
+        };
+        let ws_listener = match &config.ws_address {
+            Some(ws_address) => Some(TcpListener::bind(ws_address).map_err(|e| {
+                format!("Failed to bind WebSocket listener to {}: {}", ws_address, e)
+            })?),
+            None => None,
+        };
+        let ws_address = ws_listener
+            .as_ref()
+            .map(TcpListener::local_addr)
+            .transpose()?;
+        let http_listener =
+            match &config.http_address {
+                Some(http_address) => Some(TcpListener::bind(http_address).map_err(|e| {
+                    format!("Failed to bind HTTP listener to {}: {}", http_address, e)
+                })?),
+                None => None,
+            };
+        let http_address = http_listener
+            .as_ref()
+            .map(TcpListener::local_addr)
+            .transpose()?;
+
+        let config = SharedConfig::new(config);
+        #[cfg(unix)]
+        if self.reload_on_sighup {
+            crate::reload::reload_on_sighup(config.clone(), state.clone())
+                .map_err(|e| format!("Failed to register SIGHUP handler: {}", e))?;
+        }
+
+        retention::start(config.clone(), state.clone())
+            .map_err(|e| format!("Failed to start the retention thread: {}", e))?;
+
+        if let Some(ws_listener) = ws_listener {
+            websocket::serve(ws_listener, config.clone(), state.clone())
+                .map_err(|e| format!("Failed to start the WebSocket listener: {}", e))?;
+        }
+        if let Some(http_listener) = http_listener {
+            http::serve(http_listener, config.clone(), state.clone())
+                .map_err(|e| format!("Failed to start the HTTP listener: {}", e))?;
+        }
+
+        info!("Starting server on {}", address);
+        let accept_state = state.clone();
+        let thread = thread::Builder::new()
+            .name("accept".to_string())
+            .spawn(move || accept(listener, config, accept_state))?;
+        Ok(ServerHandle {
+            address,
+            metrics_address,
+            ws_address,
+            http_address,
+            state,
+            thread,
+        })
+    }
+}
+
+/// Handle of a running server.
+pub struct ServerHandle {
+    address: SocketAddr,
+    metrics_address: Option<SocketAddr>,
+    ws_address: Option<SocketAddr>,
+    http_address: Option<SocketAddr>,
+    state: Arc<ServerState>,
+    thread: JoinHandle<()>,
+}
+
+impl ServerHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_address
+    }
+
+    pub fn ws_addr(&self) -> Option<SocketAddr> {
+        self.ws_address
+    }
+
+    pub fn http_addr(&self) -> Option<SocketAddr> {
+        self.http_address
+    }
+
+    /// Stops accepting new connections and closes all the open ones.
+    pub fn shutdown(self) {
+        info!("Shutting down server on {}", self.address);
+        self.state.stop();
+        // wake up the listeners blocked in accepting a connection
+        let addresses = [
+            Some(self.address),
+            self.metrics_address,
+            self.ws_address,
+            self.http_address,
+        ];
+        for address in addresses.into_iter().flatten() {
+            let _ = TcpStream::connect(connectable(address));
+        }
+        if self.thread.join().is_err() {
+            error!("Accepting thread panicked");
+        }
+        for connection in self.state.connections.lock().unwrap().values() {
+            connection.disconnect();
+        }
+    }
+
+    /// Waits for the server to finish (i.e. forever, unless the accepting fails).
+    pub fn join(self) {
+        if self.thread.join().is_err() {
+            error!("Accepting thread panicked");
+        }
+    }
+}
+
+/// The address to connect to in order to reach a listener bound to the given address.
+fn connectable(address: SocketAddr) -> SocketAddr {
+    match address {
+        SocketAddr::V4(a) if a.ip().is_unspecified() => (Ipv4Addr::LOCALHOST, a.port()).into(),
+        SocketAddr::V6(a) if a.ip().is_unspecified() => (Ipv6Addr::LOCALHOST, a.port()).into(),
+        address => address,
+    }
+}
+
+fn accept(listener: TcpListener, config: SharedConfig, state: Arc<ServerState>) {
+    for stream in listener.incoming() {
+        if state.is_stopping() {
+            break;
+        }
+        match stream {
+            Ok(stream) => {
+                if let Err(e) = spawn_session(stream, &config, &state) {
+                    error!("Failed to handle connection: {}", e);
+                }
+            }
+            Err(e) => {
+                error!("Failed to accept connection: {}", e);
+            }
+        }
+    }
+}
+
+fn spawn_session(
+    stream: TcpStream,
+    config: &SharedConfig,
+    state: &Arc<ServerState>,
+) -> io::Result<()> {
+    let addr = stream.peer_addr()?;
+    if is_banned(&addr, state) {
+        return stream.shutdown(Shutdown::Both);
+    }
+    start_session(addr, stream, config, state)
+}
+
+pub(crate) fn is_banned(addr: &SocketAddr, state: &ServerState) -> bool {
+    let banned = state.bans.lock().unwrap().is_banned(&addr.ip());
+    if banned {
+        warn!("Rejected connection from banned address {}", addr);
+    }
+    banned
+}
+
+/// Handles the connection of the peer in a thread of its own, the stream carrying the wire protocol.
+pub(crate) fn start_session(
+    addr: SocketAddr,
+    stream: TcpStream,
+    config: &SharedConfig,
+    state: &Arc<ServerState>,
+) -> io::Result<()> {
+    let mut session = Session::new(addr, stream, config.clone(), state.clone())?;
+    thread::Builder::new()
+        .name(format!("client-{}", addr))
+        .spawn(move || handle_stream(&mut session))?;
+    Ok(())
+}
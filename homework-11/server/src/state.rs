@@ -0,0 +1,237 @@
+//! Shared server state.
+//!
+//! Unlike the `Config`, the state is shared (not cloned) across all the processing threads:
+//! it keeps the registry of live connections, the ban list, the catalog of the uploads, the server metrics,
+//! the audit log, the registry of commands and the hooks. Every thread also owns a `Session` describing (and owning)
+//! its own connection.
+
+use crate::audit::AuditLog;
+use crate::ban::BanList;
+use crate::catalog::Catalog;
+use crate::config::{Config, ConfigLoader, SharedConfig};
+use crate::event::{Event, Hook};
+use crate::metrics::Metrics;
+use crate::stream_handler::{send_event, ClientStream, Writer};
+use common::command::Registry;
+use common::compression::Compression;
+use common::debug;
+use common::event::ServerEvent;
+use std::collections::HashMap;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Room every connection starts in.
+pub(crate) const LOBBY: &str = "lobby";
+
+pub(crate) struct Connection {
+    pub(crate) nick: Option<String>,
+    pub(crate) room: String,
+    pub(crate) connected_at: Instant,
+    stream: TcpStream,
+    writer: Writer,
+}
+
+impl Connection {
+    pub(crate) fn send(&self, event: &ServerEvent) {
+        if let Err(e) = send_event(&self.writer, event) {
+            debug!("Failed to send event: {}", e);
+        }
+    }
+
+    pub(crate) fn disconnect(&self) {
+        // the owning thread notices the closed socket and unregisters the connection itself
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+pub(crate) struct ServerState {
+    pub(crate) connections: Mutex<HashMap<SocketAddr, Connection>>,
+    pub(crate) bans: Mutex<BanList>,
+    pub(crate) catalog: Mutex<Catalog>,
+    pub(crate) metrics: Metrics,
+    pub(crate) audit: AuditLog,
+    pub(crate) commands: Registry<Session>,
+    pub(crate) hooks: Vec<Hook>,
+    /// Source of the configuration on reload, reloading is disabled if missing.
+    pub(crate) config_loader: Option<ConfigLoader>,
+    pub(crate) started_at: Instant,
+    stopping: AtomicBool,
+}
+
+impl ServerState {
+    pub(crate) fn new(
+        bans: BanList,
+        catalog: Catalog,
+        commands: Registry<Session>,
+        hooks: Vec<Hook>,
+        config_loader: Option<ConfigLoader>,
+    ) -> Self {
+        ServerState {
+            connections: Mutex::new(HashMap::new()),
+            bans: Mutex::new(bans),
+            catalog: Mutex::new(catalog),
+            metrics: Metrics::default(),
+            audit: AuditLog::default(),
+            commands,
+            hooks,
+            config_loader,
+            started_at: Instant::now(),
+            stopping: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn notify(&self, event: Event) {
+        for hook in &self.hooks {
+            hook(&event);
+        }
+    }
+
+    pub(crate) fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn register(
+        &self,
+        addr: SocketAddr,
+        stream: &TcpStream,
+        writer: Writer,
+    ) -> std::io::Result<()> {
+        let connection = Connection {
+            nick: None,
+            room: LOBBY.to_string(),
+            connected_at: Instant::now(),
+            stream: stream.try_clone()?,
+            writer,
+        };
+        self.connections.lock().unwrap().insert(addr, connection);
+        self.metrics.connections.inc();
+        Ok(())
+    }
+
+    pub(crate) fn unregister(&self, addr: &SocketAddr) {
+        self.connections.lock().unwrap().remove(addr);
+    }
+
+    /// Sends the event to all the connections in the room except the given one (e.g. the sender of a message).
+    pub(crate) fn broadcast(&self, room: &str, except: SocketAddr, event: &ServerEvent) {
+        // the writers are collected first, so that a slow client does not block the registry
+        let writers = self
+            .connections
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(addr, connection)| **addr != except && connection.room == room)
+            .map(|(addr, connection)| (*addr, connection.writer.clone()))
+            .collect::<Vec<_>>();
+        for (addr, writer) in writers {
+            if let Err(e) = send_event(&writer, event) {
+                debug!("Failed to send event to {}: {}", addr, e);
+            }
+        }
+    }
+
+    pub(crate) fn find_by_nick(&self, nick: &str) -> Option<SocketAddr> {
+        self.connections
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, connection)| connection.nick.as_deref() == Some(nick))
+            .map(|(addr, _)| *addr)
+    }
+}
+
+/// Per-connection state owned by the thread handling the connection.
+///
+/// The session is the context of all the commands, including the ones registered by the embedding application.
+pub struct Session {
+    pub(crate) peer: SocketAddr,
+    pub(crate) stream: ClientStream,
+    /// Writing half of the stream, shared with the other threads sending events to this connection.
+    pub(crate) writer: Writer,
+    pub(crate) admin: bool,
+    /// Compression negotiated by `.compress`, if any.
+    pub(crate) compression: Option<Compression>,
+    /// Content to be sent right after the response of the current command (e.g. a download).
+    pub(crate) attachment: Option<Vec<u8>>,
+    pub(crate) shared_config: SharedConfig,
+    pub(crate) state: Arc<ServerState>,
+    rate_window: (Instant, u32),
+}
+
+impl Session {
+    pub(crate) fn new(
+        peer: SocketAddr,
+        stream: TcpStream,
+        shared_config: SharedConfig,
+        state: Arc<ServerState>,
+    ) -> std::io::Result<Self> {
+        Ok(Session {
+            peer,
+            writer: Arc::new(Mutex::new(stream.try_clone()?)),
+            stream: ClientStream::new(stream),
+            admin: false,
+            compression: None,
+            attachment: None,
+            shared_config,
+            state,
+            rate_window: (Instant::now(), 0),
+        })
+    }
+
+    /// Current configuration snapshot (taken anew on every call to reflect reloads).
+    pub(crate) fn config(&self) -> Arc<Config> {
+        self.shared_config.get()
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.admin
+    }
+
+    pub fn nick(&self) -> Option<String> {
+        self.state
+            .connections
+            .lock()
+            .unwrap()
+            .get(&self.peer)
+            .and_then(|connection| connection.nick.clone())
+    }
+
+    /// Room the session is currently in.
+    pub fn room(&self) -> String {
+        self.state
+            .connections
+            .lock()
+            .unwrap()
+            .get(&self.peer)
+            .map_or_else(|| LOBBY.to_string(), |connection| connection.room.clone())
+    }
+
+    /// Name the session is presented with to the other users (the nick, or the address if not set).
+    pub(crate) fn display_name(&self) -> String {
+        self.nick().unwrap_or_else(|| self.peer.to_string())
+    }
+
+    /// Counts the incoming message and checks it against the configured rate limit.
+    pub(crate) fn within_rate_limit(&mut self) -> bool {
+        let limit = self.config().rate_limit;
+        let (start, count) = &mut self.rate_window;
+        if start.elapsed() >= RATE_WINDOW {
+            *start = Instant::now();
+            *count = 0;
+        }
+        *count += 1;
+        limit == 0 || *count <= limit
+    }
+}
@@ -1,36 +1,124 @@
 //! Client connection-handling module.
 //!
 //! The module handles a single client connection and its stream processing.
+//!
+//! Every response (and event) is sent as a frame of non-empty lines terminated by an empty line
+//! (see `common::frame`), a response may be followed by raw content (e.g. a download, see `common::download`).
+//! The events are written by other threads too, hence all the frames go through the shared `Writer`.
 
 use crate::command::handle_command;
-use common::util::flush;
-use common::{elog, estream, log, stream};
-use std::io::{BufRead, BufReader, Write};
+use crate::event::Event;
+use crate::room::{announce, send_session};
+use crate::state::{Session, LOBBY};
+use common::error::ChatResult;
+use common::event::ServerEvent;
+use common::logging::set_field;
+use common::{error, info, warn};
+use std::io::{self, BufRead, BufReader, Write};
 use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+
+/// Buffered client stream, kept for the whole connection lifetime.
+///
+/// Reading the command line and the content following it (e.g. uploaded file)
+/// must go through the same buffer, otherwise the already buffered content would be lost.
+pub(crate) type ClientStream = BufReader<TcpStream>;
 
-pub(crate) fn handle_stream(stream: &mut TcpStream, config: &crate::Config) {
-    log!("Accepted connection");
+/// Writing half of the client stream, locked for every frame so that the frames don't interleave.
+pub(crate) type Writer = Arc<Mutex<TcpStream>>;
+
+pub(crate) fn handle_stream(session: &mut Session) {
+    set_field("peer", session.peer);
+    info!("Accepted connection");
+    let writer = session.writer.clone();
+    if let Err(e) = session
+        .state
+        .register(session.peer, session.stream.get_ref(), writer)
+    {
+        error!("Failed to register connection: {}", e);
+        return;
+    }
+    session
+        .state
+        .notify(Event::Connected { peer: session.peer });
+    announce(
+        session,
+        LOBBY,
+        ServerEvent::Joined {
+            user: session.peer.to_string(),
+            room: LOBBY.to_string(),
+        },
+    );
+    send_session(session);
     let mut buffer = String::new();
     loop {
         buffer.clear();
-        let mut reader = BufReader::new(&mut *stream);
-        match reader.read_line(&mut buffer) {
+        match session.stream.read_line(&mut buffer) {
             Ok(0) => break, // Connection closed
             Ok(_) => {
                 let input = buffer.trim_end().to_string();
-                match handle_command(stream, &input, config) {
-                    Ok(response) => {
-                        stream!(stream, "{}\n\n", response);
-                    }
-                    Err(e) => {
-                        estream!(stream, "{}\n\n", e);
-                    }
+                let result = handle_command(&input, session);
+                if let Err(e) = &result {
+                    session.state.metrics.errors.inc(e.code.as_str());
+                }
+                let attachment = session.attachment.take().unwrap_or_default();
+                if let Err(e) = respond(&session.writer, result, &attachment) {
+                    error!("Error writing to stream: {}", e);
+                    break;
                 }
             }
             Err(e) => {
-                elog!("Error reading from stream: {}", e);
+                error!("Error reading from stream: {}", e);
                 break;
             }
         }
     }
+    let room = session.room();
+    announce(
+        session,
+        &room,
+        ServerEvent::Left {
+            user: session.display_name(),
+            room: room.clone(),
+        },
+    );
+    session.state.unregister(&session.peer);
+    session
+        .state
+        .notify(Event::Disconnected { peer: session.peer });
+    info!("Connection closed");
+}
+
+fn respond(writer: &Writer, result: ChatResult<String>, attachment: &[u8]) -> io::Result<()> {
+    let message = match result {
+        Ok(response) => {
+            if !response.trim().is_empty() {
+                info!("{}", response);
+            }
+            response
+        }
+        Err(e) => {
+            warn!("{}", e);
+            e.to_wire()
+        }
+    };
+    write_frame(writer, &message, attachment)
+}
+
+pub(crate) fn send_event(writer: &Writer, event: &ServerEvent) -> io::Result<()> {
+    write_frame(writer, &event.to_wire(), &[])
+}
+
+/// Writes the frame followed by the attachment (if any), both at once so that no event gets in between.
+fn write_frame(writer: &Writer, message: &str, attachment: &[u8]) -> io::Result<()> {
+    // the frame is terminated by an empty line, hence it must not contain any itself
+    let mut frame = String::new();
+    for line in message.lines().filter(|line| !line.trim().is_empty()) {
+        frame.push_str(line);
+        frame.push('\n');
+    }
+    frame.push('\n');
+    let mut writer = writer.lock().unwrap();
+    writer.write_all(frame.as_bytes())?;
+    writer.write_all(attachment)
 }
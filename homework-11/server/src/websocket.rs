@@ -0,0 +1,501 @@
+//! WebSocket gateway speaking the command set as JSON frames (e.g. for a browser page).
+//!
+//! Every WebSocket connection gets a regular session (see `stream_handler`), connected to the gateway
+//! by an in-process loopback socket pair, so the commands, events, quotas and rate limits work exactly
+//! the same as for the TCP clients. The gateway translates the JSON text frames of the browser:
+//!
+//! - `{"command": ".join dev"}` - a command or a plain message, as typed into the client
+//! - `{"command": ".file", "name": "a.txt", "content": "<base64>"}` - an upload (`.file`, `.image` or `.archive`)
+//!
+//! to the wire protocol and the frames of the session back to JSON:
+//!
+//! - `{"type": "response", "command": ..., "ok": true, "response": ...}` - the response to the command
+//!   (with the `name` and the base64 `content` of a download), or `"ok": false` with the `code` and the `error`
+//! - `{"type": "message", "from": ..., "text": ...}`, `{"type": "notice", "text": ...}`,
+//!   `{"type": "joined"/"left", "user": ..., "room": ...}`, `{"type": "nick", "from": ..., "to": ...}`
+//!   and `{"type": "session", "room": ..., "nick": ...}` - the events (see `common::event`)
+//!
+//! The requests are handled one at a time, the next one is read once the response is sent.
+//! An optional `tag` of the request is copied into its response. The connection thread is the only one
+//! using the WebSocket, the frames of the session are read by another thread and passed to it.
+
+use crate::config::SharedConfig;
+use crate::server::{is_banned, start_session};
+use crate::state::ServerState;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use common::command::quote;
+use common::download::Download;
+use common::error::{ChatError, ChatResult, ErrorCode};
+use common::event::ServerEvent;
+use common::frame::read_frame;
+use common::logging::set_field;
+use common::{debug, error, info, warn};
+use serde_json::{json, Map, Value};
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tungstenite::protocol::WebSocketConfig;
+use tungstenite::{Error as WsError, Message, WebSocket};
+
+/// Time limit of the WebSocket handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Interval of checking for the events to be sent while waiting for a request.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const UPLOAD_COMMANDS: [&str; 3] = [".file", ".image", ".archive"];
+
+/// Name and content of an upload or a download.
+type Attachment = (String, Vec<u8>);
+
+/// Request waiting for its response.
+#[derive(Default)]
+struct Pending {
+    command: String,
+    tag: Option<Value>,
+}
+
+/// Frame of the session, read by the session reading thread.
+enum Frame {
+    Event(ServerEvent),
+    Response(ChatResult<Reply>),
+}
+
+struct Reply {
+    text: String,
+    download: Option<Attachment>,
+}
+
+/// Starts accepting the WebSocket connections in a background thread.
+pub(crate) fn serve(
+    listener: TcpListener,
+    config: SharedConfig,
+    state: Arc<ServerState>,
+) -> io::Result<()> {
+    info!(
+        "Accepting WebSocket connections on ws://{}",
+        listener.local_addr()?
+    );
+    thread::Builder::new()
+        .name("websocket".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                if state.is_stopping() {
+                    break;
+                }
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = spawn_gateway(stream, &config, &state) {
+                            error!("Failed to handle WebSocket connection: {}", e);
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to accept WebSocket connection: {}", e);
+                    }
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn spawn_gateway(
+    stream: TcpStream,
+    config: &SharedConfig,
+    state: &Arc<ServerState>,
+) -> io::Result<()> {
+    let addr = stream.peer_addr()?;
+    if is_banned(&addr, state) {
+        return stream.shutdown(Shutdown::Both);
+    }
+    let config = config.clone();
+    let state = state.clone();
+    thread::Builder::new()
+        .name(format!("websocket-{}", addr))
+        .spawn(move || {
+            set_field("peer", addr);
+            if let Err(e) = run_gateway(addr, stream, &config, &state) {
+                warn!("WebSocket connection failed: {}", e);
+            }
+        })?;
+    Ok(())
+}
+
+fn run_gateway(
+    addr: SocketAddr,
+    stream: TcpStream,
+    config: &SharedConfig,
+    state: &Arc<ServerState>,
+) -> io::Result<()> {
+    // the base64 encoded upload is a third larger than the upload itself
+    let max_size = (config.get().max_upload_size as usize)
+        .saturating_mul(4)
+        .saturating_div(3)
+        .saturating_add(64 * 1024);
+    let ws_config = WebSocketConfig::default()
+        .max_message_size(Some(max_size))
+        .max_frame_size(Some(max_size));
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let mut socket = tungstenite::accept_with_config(stream.try_clone()?, Some(ws_config))
+        .map_err(|e| io::Error::other(format!("Handshake failed: {}", e)))?;
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    info!("Accepted WebSocket connection");
+    state.metrics.websocket_connections.inc();
+
+    let (session_end, gateway_end) = loopback_pair()?;
+    start_session(addr, session_end, config, state)?;
+    let (frames_tx, frames_rx) = mpsc::channel();
+    let reader = BufReader::new(gateway_end.try_clone()?);
+    thread::Builder::new()
+        .name(format!("websocket-session-{}", addr))
+        .spawn(move || {
+            set_field("peer", addr);
+            read_session(reader, frames_tx);
+        })?;
+
+    relay(&mut socket, gateway_end, frames_rx, state);
+    // the session is closed by either side (e.g. kicked), the other one follows
+    let _ = socket.close(None);
+    let _ = socket.flush();
+    info!("WebSocket connection closed");
+    Ok(())
+}
+
+/// Two ends of a loopback TCP connection, standing in for a socket pair.
+fn loopback_pair() -> io::Result<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let connected = TcpStream::connect(listener.local_addr()?)?;
+    loop {
+        let (accepted, peer) = listener.accept()?;
+        // anybody else could connect to the listener meanwhile
+        if peer == connected.local_addr()? {
+            return Ok((accepted, connected));
+        }
+    }
+}
+
+/// Passes the requests of the browser to the session and the frames of the session back,
+/// until either side closes.
+fn relay(
+    socket: &mut WebSocket<TcpStream>,
+    mut session: TcpStream,
+    frames: Receiver<Frame>,
+    state: &ServerState,
+) {
+    let mut pending: Option<Pending> = None;
+    loop {
+        // the events first, then the response if waiting for one (nothing is read from the browser meanwhile)
+        loop {
+            let frame = match pending {
+                Some(_) => frames.recv().map_err(|_| TryRecvError::Disconnected),
+                None => frames.try_recv(),
+            };
+            let message = match frame {
+                Ok(Frame::Event(event)) => event_message(&event),
+                Ok(Frame::Response(result)) => {
+                    response_message(&pending.take().unwrap_or_default(), result)
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return shutdown(&session),
+            };
+            if let Err(e) = send(socket, &message) {
+                debug!("Error writing to WebSocket: {}", e);
+                return shutdown(&session);
+            }
+        }
+
+        let text = match read_request(socket) {
+            Ok(Some(text)) => text,
+            Ok(None) if state.is_stopping() => return shutdown(&session),
+            Ok(None) => continue,
+            Err(WsError::ConnectionClosed | WsError::AlreadyClosed) => return shutdown(&session),
+            Err(e) => {
+                warn!("Error reading from WebSocket: {}", e);
+                return shutdown(&session);
+            }
+        };
+        let (request, upload) = parse_request(&text);
+        let upload = match upload {
+            Ok(upload) => upload,
+            Err(e) => {
+                if let Err(e) = send(socket, &response_message(&request, Err(e))) {
+                    debug!("Error writing to WebSocket: {}", e);
+                    return shutdown(&session);
+                }
+                continue;
+            }
+        };
+        let (line, content) = to_wire(&request.command, upload);
+        let written = session
+            .write_all(line.as_bytes())
+            .and_then(|_| session.write_all(&content));
+        if let Err(e) = written {
+            debug!("Failed to pass the request to the session: {}", e);
+            return shutdown(&session);
+        }
+        pending = Some(request);
+    }
+}
+
+fn shutdown(session: &TcpStream) {
+    let _ = session.shutdown(Shutdown::Both);
+}
+
+/// Reads the next text frame, `None` if there is none yet (or the frame is of another kind).
+fn read_request(socket: &mut WebSocket<TcpStream>) -> Result<Option<String>, WsError> {
+    match socket.read() {
+        Ok(Message::Text(text)) => Ok(Some(text.to_string())),
+        Ok(Message::Close(_)) => Err(WsError::ConnectionClosed),
+        // the pings are answered by the library, the other frames are ignored
+        Ok(_) => Ok(None),
+        Err(WsError::Io(e))
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Parses the request into the command (with the tag) and the upload (its name and content), if any.
+///
+/// The command and the tag are kept (as far as they could be read) for the response even if the request is invalid.
+fn parse_request(text: &str) -> (Pending, ChatResult<Option<Attachment>>) {
+    let request = match serde_json::from_str::<Map<String, Value>>(text) {
+        Ok(request) => request,
+        Err(e) => {
+            let e = invalid(format!("Invalid request: {}", e));
+            return (Pending::default(), Err(e));
+        }
+    };
+    let field = |name: &str| request.get(name).and_then(Value::as_str);
+    let pending = Pending {
+        command: field("command").unwrap_or_default().to_string(),
+        tag: request.get("tag").cloned(),
+    };
+    let upload = parse_upload(&pending.command, field("name"), field("content"));
+    (pending, upload)
+}
+
+/// The command line (and the content following it) of the wire protocol.
+fn to_wire(command: &str, upload: Option<Attachment>) -> (String, Vec<u8>) {
+    match upload {
+        Some((name, content)) => (
+            format!("{} {} {}\n", command, content.len(), quote(&name)),
+            content,
+        ),
+        None => (format!("{}\n", command), Vec::new()),
+    }
+}
+
+/// Checks the command line, returning the name and the decoded content if it is an upload.
+fn parse_upload(
+    command: &str,
+    name: Option<&str>,
+    content: Option<&str>,
+) -> ChatResult<Option<Attachment>> {
+    if command.is_empty() {
+        return Err(invalid("The request has no \"command\"".to_string()));
+    }
+    // the line must not turn into more commands
+    if command.contains(['\n', '\r']) {
+        return Err(invalid("The command must be a single line".to_string()));
+    }
+    // the size of an upload is given by the content, the upload commands take no arguments
+    let command_name = command.split(' ').next().unwrap_or_default();
+    match (UPLOAD_COMMANDS.contains(&command_name), name, content) {
+        (true, Some(name), Some(content)) if command == command_name => {
+            let content = BASE64
+                .decode(content)
+                .map_err(|e| invalid(format!("Invalid content of {}: {}", name, e)))?;
+            Ok(Some((name.to_string(), content)))
+        }
+        (true, _, _) => Err(invalid(format!(
+            "Upload {} requires \"name\" and \"content\" (base64) instead of arguments",
+            command_name
+        ))),
+        (false, _, Some(_)) => Err(invalid(format!(
+            "Command {} takes no content",
+            command_name
+        ))),
+        (false, _, None) => Ok(None),
+    }
+}
+
+fn invalid(message: String) -> ChatError {
+    ChatError::new(ErrorCode::InvalidArgument, message)
+}
+
+/// Reads the frames of the session (and the content of the downloads) and passes them on, until the session is closed.
+fn read_session(mut reader: BufReader<TcpStream>, frames: Sender<Frame>) {
+    loop {
+        let frame = match read_frame(&mut reader) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(e) => {
+                debug!("Error reading from the session: {}", e);
+                break;
+            }
+        };
+        let frame = match (ServerEvent::from_wire(&frame), ChatError::from_wire(&frame)) {
+            (Some(event), _) => Frame::Event(event),
+            (None, Some(e)) => Frame::Response(Err(e)),
+            (None, None) => match read_download(&mut reader, frame) {
+                Ok(reply) => Frame::Response(reply),
+                Err(e) => {
+                    debug!("Error reading from the session: {}", e);
+                    break;
+                }
+            },
+        };
+        if frames.send(frame).is_err() {
+            break;
+        }
+    }
+}
+
+/// Reads the content following the response if it is a download (decompressing it if needed).
+fn read_download(reader: &mut BufReader<TcpStream>, text: String) -> io::Result<ChatResult<Reply>> {
+    let Some(download) = Download::from_wire(&text) else {
+        return Ok(Ok(Reply {
+            text,
+            download: None,
+        }));
+    };
+    let mut content = vec![0; download.wire_size];
+    reader.read_exact(&mut content)?;
+    if let Some(compression) = download.compression {
+        content = match compression.decompress(&content, download.size) {
+            Ok(content) => content,
+            Err(e) => {
+                return Ok(Err(ChatError::new(
+                    ErrorCode::DecodeFailed,
+                    format!("Failed to decompress {}: {}", download.name, e),
+                )));
+            }
+        };
+    }
+    Ok(Ok(Reply {
+        text,
+        download: Some((download.name, content)),
+    }))
+}
+
+fn event_message(event: &ServerEvent) -> Value {
+    match event {
+        ServerEvent::Message { from, text } => {
+            json!({"type": "message", "from": from, "text": text})
+        }
+        ServerEvent::Notice(text) => json!({"type": "notice", "text": text}),
+        ServerEvent::Joined { user, room } => json!({"type": "joined", "user": user, "room": room}),
+        ServerEvent::Left { user, room } => json!({"type": "left", "user": user, "room": room}),
+        ServerEvent::NickChanged { from, to } => json!({"type": "nick", "from": from, "to": to}),
+        ServerEvent::Session { room, nick } => {
+            json!({"type": "session", "room": room, "nick": nick})
+        }
+        _ => json!({"type": "notice", "text": event.to_string()}),
+    }
+}
+
+fn response_message(request: &Pending, result: ChatResult<Reply>) -> Value {
+    let mut message = match result {
+        Ok(reply) => {
+            let mut message = json!({"type": "response", "command": request.command, "ok": true, "response": reply.text});
+            if let Some((name, content)) = reply.download {
+                message["name"] = json!(name);
+                message["content"] = json!(BASE64.encode(content));
+            }
+            message
+        }
+        Err(e) => json!({
+            "type": "response",
+            "command": request.command,
+            "ok": false,
+            "code": e.code.as_str(),
+            "error": e.message,
+        }),
+    };
+    if let Some(tag) = &request.tag {
+        message["tag"] = tag.clone();
+    }
+    message
+}
+
+fn send(socket: &mut WebSocket<TcpStream>, message: &Value) -> Result<(), WsError> {
+    socket.send(Message::text(message.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_code(text: &str) -> ErrorCode {
+        parse_request(text).1.unwrap_err().code
+    }
+
+    #[test]
+    fn upload_request_is_decoded() {
+        let (pending, upload) = parse_request(
+            r#"{"command": ".file", "name": "my a.txt", "content": "aGVsbG8=", "tag": 7}"#,
+        );
+        assert_eq!(pending.command, ".file");
+        assert_eq!(pending.tag, Some(json!(7)));
+        let upload = upload.unwrap();
+        assert_eq!(upload, Some(("my a.txt".to_string(), b"hello".to_vec())));
+        assert_eq!(
+            to_wire(&pending.command, upload),
+            (".file 5 \"my a.txt\"\n".to_string(), b"hello".to_vec())
+        );
+    }
+
+    #[test]
+    fn plain_command_has_no_upload() {
+        let (pending, upload) = parse_request(r#"{"command": ".join dev"}"#);
+        assert_eq!(pending.command, ".join dev");
+        assert_eq!(upload.unwrap(), None);
+    }
+
+    #[test]
+    fn invalid_requests_are_refused() {
+        let invalid = ErrorCode::InvalidArgument;
+        // not a JSON object
+        assert_eq!(error_code("hello"), invalid);
+        assert_eq!(error_code(r#"["command"]"#), invalid);
+        // no command, or not a string
+        assert_eq!(error_code(r#"{"name": "a.txt"}"#), invalid);
+        assert_eq!(error_code(r#"{"command": 1}"#), invalid);
+        // the line would turn into more commands
+        assert_eq!(error_code(r#"{"command": "hi\n.quit"}"#), invalid);
+        // the upload is given by the name and content only
+        assert_eq!(error_code(r#"{"command": ".file 5 a.txt"}"#), invalid);
+        assert_eq!(
+            error_code(r#"{"command": ".file 5 a.txt", "name": "a.txt", "content": "aGVsbG8="}"#),
+            invalid
+        );
+        assert_eq!(
+            error_code(r#"{"command": ".image", "name": "a.png"}"#),
+            invalid
+        );
+        assert_eq!(
+            error_code(r#"{"command": ".file", "name": "a.txt", "content": "not base64!"}"#),
+            invalid
+        );
+        // only the uploads take content
+        assert_eq!(
+            error_code(r#"{"command": ".rooms", "content": "aGVsbG8="}"#),
+            invalid
+        );
+    }
+
+    #[test]
+    fn tag_of_invalid_request_is_kept() {
+        let (pending, upload) = parse_request(r#"{"command": ".archive", "tag": "t1"}"#);
+        assert!(upload.is_err());
+        assert_eq!(pending.command, ".archive");
+        assert_eq!(pending.tag, Some(json!("t1")));
+    }
+}
@@ -0,0 +1,347 @@
+//! End-to-end tests of the server, talking the line protocol over a real socket.
+
+use common::cli::Settings;
+use common::compression::Compression;
+use common::download::Download;
+use common::event::ServerEvent;
+use common::frame::read_frame;
+use server::{Config, Server, ServerHandle};
+use std::fs;
+use std::io::{BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+const MAX_UPLOAD_SIZE: u64 = 64;
+
+/// Server started on an ephemeral port with a storage of its own, removed on drop.
+struct TestServer {
+    handle: Option<ServerHandle>,
+    storage: PathBuf,
+}
+
+impl TestServer {
+    fn start() -> Self {
+        Self::with_settings(Settings {
+            max_upload_size: MAX_UPLOAD_SIZE,
+            ..Settings::default()
+        })
+    }
+
+    fn with_settings(settings: Settings) -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let storage = std::env::temp_dir().join(format!(
+            "server-e2e-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let config = Config::from_settings(settings).unwrap();
+        let handle = Server::builder()
+            .config(config)
+            .bind("127.0.0.1:0")
+            .storage(&storage)
+            .start()
+            .unwrap();
+        TestServer {
+            handle: Some(handle),
+            storage,
+        }
+    }
+
+    fn connect(&self) -> Connection {
+        let stream = TcpStream::connect(self.handle.as_ref().unwrap().local_addr()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        Connection {
+            writer: stream.try_clone().unwrap(),
+            reader: BufReader::new(stream),
+            events: Vec::new(),
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            handle.shutdown();
+        }
+        let _ = fs::remove_dir_all(&self.storage);
+    }
+}
+
+struct Connection {
+    writer: TcpStream,
+    reader: BufReader<TcpStream>,
+    /// Events received so far.
+    events: Vec<ServerEvent>,
+}
+
+impl Connection {
+    /// Sends the command line followed by the content (if any) and returns the response, collecting the events.
+    fn request(&mut self, line: &str, content: &[u8]) -> String {
+        self.writer
+            .write_all(format!("{}\n", line).as_bytes())
+            .unwrap();
+        self.writer.write_all(content).unwrap();
+        loop {
+            let frame = read_frame(&mut self.reader).unwrap().unwrap();
+            match ServerEvent::from_wire(&frame) {
+                Some(event) => self.events.push(event),
+                None => return frame,
+            }
+        }
+    }
+
+    fn download(&mut self, id: &str) -> (Download, Vec<u8>) {
+        let response = self.request(&format!(".get {}", id), &[]);
+        let download = Download::from_wire(&response)
+            .unwrap_or_else(|| panic!("Not a download: {}", response));
+        let mut content = vec![0; download.wire_size];
+        self.reader.read_exact(&mut content).unwrap();
+        (download, content)
+    }
+}
+
+/// ID the upload was stored as, taken from the response (`... as #<id>`).
+fn upload_id(response: &str) -> &str {
+    let (_, id) = response
+        .rsplit_once(" as ")
+        .unwrap_or_else(|| panic!("No upload ID in: {}", response));
+    id
+}
+
+#[test]
+fn uploaded_file_is_downloaded() {
+    let server = TestServer::start();
+    let mut connection = server.connect();
+
+    let response = connection.request(".file 12 hello.txt", b"Hello world!");
+    assert!(response.starts_with("Stored 12 bytes"), "{}", response);
+
+    let (download, content) = connection.download(upload_id(&response));
+    assert_eq!(download.name, "hello.txt");
+    assert_eq!(download.compression, None);
+    assert_eq!(content, b"Hello world!");
+}
+
+#[test]
+fn compressed_upload_is_downloaded_decompressed() {
+    let server = TestServer::start();
+    let mut connection = server.connect();
+    assert_eq!(
+        connection.request(".compress deflate", &[]),
+        "Compression: deflate"
+    );
+
+    let original = vec![b'a'; MAX_UPLOAD_SIZE as usize];
+    let compression = Compression::Deflate;
+    let compressed = compression.compress_if_smaller(&original).unwrap();
+    let response = connection.request(
+        &format!(
+            ".file {} a.txt deflate {}",
+            compressed.len(),
+            original.len()
+        ),
+        &compressed,
+    );
+    assert!(response.starts_with("Stored 64 bytes"), "{}", response);
+
+    let (download, content) = connection.download(upload_id(&response));
+    assert_eq!(download.size, original.len());
+    let content = compression.decompress(&content, download.size).unwrap();
+    assert_eq!(content, original);
+}
+
+#[test]
+fn too_large_upload_is_rejected_and_skipped() {
+    let server = TestServer::start();
+    let mut connection = server.connect();
+
+    let content = vec![b'x'; MAX_UPLOAD_SIZE as usize + 1];
+    let response = connection.request(&format!(".file {} big.txt", content.len()), &content);
+    assert!(response.starts_with("ERROR: TOO_LARGE:"), "{}", response);
+
+    // the content of the rejected upload must not be taken for commands
+    let response = connection.request(".file 2 small.txt", b"ok");
+    assert!(response.starts_with("Stored 2 bytes"), "{}", response);
+}
+
+#[test]
+fn invalid_upload_command_is_discarded() {
+    let server = TestServer::start();
+    let mut connection = server.connect();
+
+    // the name is missing, yet the announced content follows
+    let response = connection.request(".file 6", b".rooms");
+    assert!(
+        response.starts_with("ERROR: INVALID_ARGUMENT:"),
+        "{}",
+        response
+    );
+
+    let response = connection.request(".file 5 next.txt", b"12345");
+    assert!(response.starts_with("Stored 5 bytes"), "{}", response);
+}
+
+#[test]
+fn compressed_upload_without_original_size_is_discarded() {
+    let server = TestServer::start();
+    let mut connection = server.connect();
+    connection.request(".compress deflate", &[]);
+
+    let response = connection.request(".file 6 a.txt deflate", b".rooms");
+    assert!(
+        response.starts_with("ERROR: INVALID_ARGUMENT:"),
+        "{}",
+        response
+    );
+
+    let response = connection.request(".file 5 next.txt", b"12345");
+    assert!(response.starts_with("Stored 5 bytes"), "{}", response);
+}
+
+#[test]
+fn unknown_upload_is_not_found() {
+    let server = TestServer::start();
+    let mut connection = server.connect();
+
+    let response = connection.request(".get #ffff", &[]);
+    assert!(response.starts_with("ERROR: NOT_FOUND:"), "{}", response);
+}
+
+#[test]
+fn room_and_nick_changes_are_events() {
+    let server = TestServer::start();
+    let mut ann = server.connect();
+    assert_eq!(ann.request(".nick ann", &[]), "Nick set to ann");
+    let mut bob = server.connect();
+    let bob_address = bob.writer.local_addr().unwrap().to_string();
+    assert_eq!(bob.request(".join dev", &[]), "Joined #dev");
+    // the events of bob are sent to ann before bob gets the response
+    ann.request(".rooms", &[]);
+
+    let session = |room: &str, nick: Option<&str>| ServerEvent::Session {
+        room: room.to_string(),
+        nick: nick.map(String::from),
+    };
+    assert_eq!(
+        ann.events,
+        [
+            session("lobby", None),
+            session("lobby", Some("ann")),
+            ServerEvent::Joined {
+                user: bob_address.clone(),
+                room: "lobby".to_string()
+            },
+            ServerEvent::Left {
+                user: bob_address,
+                room: "lobby".to_string()
+            },
+        ]
+    );
+    assert_eq!(bob.events, [session("lobby", None), session("dev", None)]);
+}
+
+#[test]
+fn http_requests_beyond_the_limit_are_refused() {
+    let server = TestServer::with_settings(Settings {
+        http_addr: Some("127.0.0.1:0".to_string()),
+        ..Settings::default()
+    });
+    let address = server.handle.as_ref().unwrap().http_addr().unwrap();
+    // connections not sending their requests yet keep their threads busy
+    let idle = (0..64)
+        .map(|_| TcpStream::connect(address).unwrap())
+        .collect::<Vec<_>>();
+
+    let mut refused = TcpStream::connect(address).unwrap();
+    refused
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let mut response = String::new();
+    refused.read_to_string(&mut response).unwrap();
+    assert!(
+        response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+        "{}",
+        response
+    );
+
+    // served again once the idle connections are given up on
+    drop(idle);
+    let health = || {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        // a refused request may be reset, as it is not read
+        let mut response = String::new();
+        let _ = stream
+            .write_all(b"GET /health HTTP/1.1\r\n\r\n")
+            .and_then(|_| stream.read_to_string(&mut response));
+        response
+    };
+    let served = (0..100).any(|_| {
+        thread::sleep(Duration::from_millis(20));
+        health().starts_with("HTTP/1.1 200 OK\r\n")
+    });
+    assert!(served);
+}
+
+#[test]
+fn quota_is_kept_when_nick_changes() {
+    let server = TestServer::with_settings(Settings {
+        user_quota: 16,
+        ..Settings::default()
+    });
+    let mut connection = server.connect();
+    let response = connection.request(".file 10 a.txt", b"0123456789");
+    assert!(response.starts_with("Stored 10 bytes"), "{}", response);
+
+    connection.request(".nick ann", &[]);
+    let response = connection.request(".file 10 b.txt", b"0123456789");
+    assert!(
+        response.starts_with("ERROR: QUOTA_EXCEEDED:"),
+        "{}",
+        response
+    );
+    let response = connection.request(".quota ann", &[]);
+    assert!(
+        response.starts_with("Usage of 127.0.0.1: 10 B"),
+        "{}",
+        response
+    );
+}
+
+#[test]
+fn unpacked_archive_is_deleted() {
+    let server = TestServer::with_settings(Settings {
+        admin_token: Some("s3cret".to_string()),
+        ..Settings::default()
+    });
+    let mut connection = server.connect();
+    let mut archive = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_size(5);
+    header.set_mode(0o644);
+    archive
+        .append_data(&mut header, "notes/a.txt", &b"hello"[..])
+        .unwrap();
+    let archive = archive.into_inner().unwrap();
+    let response = connection.request(&format!(".archive {} notes.tar", archive.len()), &archive);
+    assert!(response.starts_with("Unpacked 1 files"), "{}", response);
+    let (_, rest) = response.split_once(" into ").unwrap();
+    let (path, id) = rest.split_once(" as ").unwrap();
+
+    assert_eq!(
+        connection.request(".admin s3cret", &[]),
+        "Admin mode enabled"
+    );
+    let response = connection.request(&format!(".delete {}", path), &[]);
+    assert!(response.starts_with("Deleted"), "{}", response);
+    assert!(!PathBuf::from(path).exists());
+    let response = connection.request(&format!(".get {}", id), &[]);
+    assert!(response.starts_with("ERROR: NOT_FOUND:"), "{}", response);
+}